@@ -0,0 +1,120 @@
+// Memory mapped registers over a byte stream, the gateware side of arrange_ftdi::ftdi::registers.
+//
+// READ:  10 a0 a1 a2 a3 N         -> N values, then a status
+// WRITE: 20 a0 a1 a2 a3 N M v..   -> a status
+//
+// Addresses and values are little endian, the status is 50, or 51 when some address was not a
+// register. The register file decodes reg_address combinationally: reg_hit says whether it is
+// a register and reg_rdata holds its value. A write is a one clock reg_write pulse, with the
+// bytes to write set in reg_mask.
+/* module */
+module reg_bridge (
+    clock,
+    rx_valid,
+    rx_data,
+    tx_valid,
+    tx_data,
+    tx_ready,
+    reg_address,
+    reg_hit,
+    reg_rdata,
+    reg_write,
+    reg_wdata,
+    reg_mask
+);
+  /* I/O */
+  input clock;
+  // Byte stream from the host.
+  input rx_valid;
+  input [7:0] rx_data;
+  // Byte stream to the host, a byte is taken when tx_valid and tx_ready are both high.
+  output tx_valid;
+  output [7:0] tx_data;
+  input tx_ready;
+  // Register file.
+  output reg [31:0] reg_address = 0;
+  input reg_hit;
+  input [31:0] reg_rdata;
+  output reg_write;
+  output reg [31:0] reg_wdata = 0;
+  output reg [3:0] reg_mask = 0;
+
+  localparam READ = 8'h10, WRITE = 8'h20, STATUS_OK = 8'h50;
+
+  localparam OPCODE = 0, ADDRESS = 1, COUNT = 2, MASK = 3, WDATA = 4, STROBE = 5, LOAD = 6,
+      SEND = 7, STATUS = 8;
+
+  reg [3:0] state = OPCODE;
+  reg [7:0] opcode = 0;
+  // Registers left in the command.
+  reg [7:0] count = 0;
+  // Byte of the address or value being moved.
+  reg [1:0] index = 0;
+  reg [31:0] rdata = 0;
+  reg unmapped = 0;
+
+  assign reg_write = state == STROBE;
+  assign tx_valid = state == SEND || state == STATUS;
+  assign tx_data = state == STATUS ? STATUS_OK | unmapped : rdata[8*index+:8];
+
+  /* always */
+  always @(posedge clock) begin
+    case (state)
+      OPCODE:
+      if (rx_valid && (rx_data == READ || rx_data == WRITE)) begin
+        opcode <= rx_data;
+        unmapped <= 0;
+        index <= 0;
+        state <= ADDRESS;
+      end
+      ADDRESS:
+      if (rx_valid) begin
+        reg_address <= {rx_data, reg_address[31:8]};
+        index <= index + 1;
+        if (index == 3) state <= COUNT;
+      end
+      COUNT:
+      if (rx_valid) begin
+        count <= rx_data;
+        if (rx_data == 0) state <= STATUS;
+        else state <= opcode == WRITE ? MASK : LOAD;
+      end
+      MASK:
+      if (rx_valid) begin
+        reg_mask <= rx_data[3:0];
+        index <= 0;
+        state <= WDATA;
+      end
+      WDATA:
+      if (rx_valid) begin
+        reg_wdata <= {rx_data, reg_wdata[31:8]};
+        index <= index + 1;
+        if (index == 3) state <= STROBE;
+      end
+      // reg_write is high for this clock.
+      STROBE: begin
+        unmapped <= unmapped | !reg_hit;
+        reg_address <= reg_address + 4;
+        count <= count - 1;
+        state <= count == 1 ? STATUS : WDATA;
+      end
+      LOAD: begin
+        rdata <= reg_hit ? reg_rdata : 0;
+        unmapped <= unmapped | !reg_hit;
+        index <= 0;
+        state <= SEND;
+      end
+      SEND:
+      if (tx_ready) begin
+        index <= index + 1;
+        if (index == 3) begin
+          reg_address <= reg_address + 4;
+          count <= count - 1;
+          state <= count == 1 ? STATUS : LOAD;
+        end
+      end
+      STATUS: if (tx_ready) state <= OPCODE;
+    endcase
+  end
+
+endmodule
@@ -1,7 +1,15 @@
-// Blink an LED provided an input clock
+// Blink an LED provided an input clock, and expose the LEDs and a counter as registers over
+// the UART, see reg_bridge.v.
+//
+// 0x00 ID       "ARNG", read only
+// 0x04 LEDS     bits 3:0 drive the LEDs while bit 8 is set, otherwise they blink
+// 0x08 COUNTER  counts at 12 MHz, writes load it
+// 0x0C SCRATCH  read and write
 /* module */
 module top (
     CLK_12_MHZ,
+    RS232_RX,
+    RS232_TX,
     led1,
     led2,
     led3,
@@ -9,6 +17,8 @@ module top (
 );
   /* I/O */
   input CLK_12_MHZ;
+  input RS232_RX;
+  output RS232_TX;
   output led1;
   output led2;
   output led3;
@@ -22,15 +32,98 @@ module top (
       .divided_clocks(clk)
   );
 
+  /* UART */
+  wire rx_valid;
+  wire [7:0] rx_data;
+  wire tx_valid;
+  wire [7:0] tx_data;
+  wire tx_ready;
+  uart_rx rx (
+      .clock(CLK_12_MHZ),
+      .rx(RS232_RX),
+      .valid(rx_valid),
+      .data(rx_data)
+  );
+  uart_tx tx (
+      .clock(CLK_12_MHZ),
+      .valid(tx_valid),
+      .data(tx_data),
+      .ready(tx_ready),
+      .tx(RS232_TX)
+  );
+
+  /* Registers */
+  wire [31:0] reg_address;
+  reg reg_hit;
+  reg [31:0] reg_rdata;
+  wire reg_write;
+  wire [31:0] reg_wdata;
+  wire [3:0] reg_mask;
+  reg_bridge bridge (
+      .clock(CLK_12_MHZ),
+      .rx_valid(rx_valid),
+      .rx_data(rx_data),
+      .tx_valid(tx_valid),
+      .tx_data(tx_data),
+      .tx_ready(tx_ready),
+      .reg_address(reg_address),
+      .reg_hit(reg_hit),
+      .reg_rdata(reg_rdata),
+      .reg_write(reg_write),
+      .reg_wdata(reg_wdata),
+      .reg_mask(reg_mask)
+  );
+
+  reg [31:0] leds = 0;
+  reg [31:0] counter = 0;
+  reg [31:0] scratch = 0;
+
+  // The bytes of value selected by mask, over the bytes of old.
+  function [31:0] masked;
+    input [31:0] old;
+    input [31:0] value;
+    input [3:0] mask;
+    integer i;
+    begin
+      for (i = 0; i < 4; i = i + 1) masked[8*i+:8] = mask[i] ? value[8*i+:8] : old[8*i+:8];
+    end
+  endfunction
+
+  always @(*) begin
+    reg_hit = 1;
+    case (reg_address)
+      32'h00: reg_rdata = 32'h474E5241;
+      32'h04: reg_rdata = leds;
+      32'h08: reg_rdata = counter;
+      32'h0C: reg_rdata = scratch;
+      default: begin
+        reg_hit = 0;
+        reg_rdata = 0;
+      end
+    endcase
+  end
+
+  always @(posedge CLK_12_MHZ) begin
+    counter <= counter + 1;
+    if (reg_write) begin
+      case (reg_address)
+        32'h04: leds <= masked(leds, reg_wdata, reg_mask) & 32'h10F;
+        32'h08: counter <= masked(counter, reg_wdata, reg_mask);
+        32'h0C: scratch <= masked(scratch, reg_wdata, reg_mask);
+        default: ;
+      endcase
+    end
+  end
+
   /* LED drivers */
   reg [3:0] led1_value = 0;
   reg [3:0] led2_value = 0;
   reg [3:0] led3_value = 0;
   reg [3:0] led4_value = 0;
-  assign led1 = led1_value[3];
-  assign led2 = led2_value[2];
-  assign led3 = led3_value[2];
-  assign led4 = led4_value[2];
+  assign led1 = leds[8] ? leds[0] : led1_value[3];
+  assign led2 = leds[8] ? leds[1] : led2_value[2];
+  assign led3 = leds[8] ? leds[2] : led3_value[2];
+  assign led4 = leds[8] ? leds[3] : led4_value[2];
 
   /* always */
   always @(posedge clk[20]) begin
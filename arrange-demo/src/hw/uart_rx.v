@@ -0,0 +1,64 @@
+// Receive bytes from the FTDI chip's UART, 8N1.
+/* module */
+module uart_rx (
+    clock,
+    rx,
+    valid,
+    data
+);
+  // 115200 baud from 12 MHz.
+  parameter CLKS_PER_BIT = 104;
+
+  /* I/O */
+  input clock;
+  input rx;
+  // High for one clock when a byte is in data.
+  output reg valid = 0;
+  output reg [7:0] data = 0;
+
+  localparam IDLE = 0, START = 1, DATA = 2, STOP = 3;
+
+  /* Synchronise the input to our clock */
+  reg [1:0] rx_sync = 2'b11;
+  wire rx_bit = rx_sync[1];
+
+  reg [1:0] state = IDLE;
+  reg [15:0] count = 0;
+  reg [2:0] index = 0;
+
+  /* always */
+  always @(posedge clock) begin
+    rx_sync <= {rx_sync[0], rx};
+    valid <= 0;
+
+    case (state)
+      IDLE:
+      if (!rx_bit) begin
+        state <= START;
+        count <= 0;
+      end
+      // Sample in the middle of the start bit, and of every bit after it.
+      START:
+      if (count == CLKS_PER_BIT / 2 - 1) begin
+        // A glitch, not a start bit.
+        state <= rx_bit ? IDLE : DATA;
+        count <= 0;
+        index <= 0;
+      end else count <= count + 1;
+      DATA:
+      if (count == CLKS_PER_BIT - 1) begin
+        data[index] <= rx_bit;
+        if (index == 7) state <= STOP;
+        index <= index + 1;
+        count <= 0;
+      end else count <= count + 1;
+      STOP:
+      if (count == CLKS_PER_BIT - 1) begin
+        // Drop the byte on a framing error.
+        valid <= rx_bit;
+        state <= IDLE;
+      end else count <= count + 1;
+    endcase
+  end
+
+endmodule
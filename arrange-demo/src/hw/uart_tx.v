@@ -0,0 +1,44 @@
+// Send bytes to the FTDI chip's UART, 8N1.
+/* module */
+module uart_tx (
+    clock,
+    valid,
+    data,
+    ready,
+    tx
+);
+  // 115200 baud from 12 MHz.
+  parameter CLKS_PER_BIT = 104;
+
+  /* I/O */
+  input clock;
+  // data is taken on a clock where both valid and ready are high.
+  input valid;
+  input [7:0] data;
+  output ready;
+  output tx;
+
+  // Stop bit, data and start bit, shifted out from the bottom.
+  reg [9:0] shift = 10'h3FF;
+  reg [3:0] bits = 0;
+  reg [15:0] count = 0;
+  assign ready = bits == 0;
+  assign tx = shift[0];
+
+  /* always */
+  always @(posedge clock) begin
+    if (bits == 0) begin
+      if (valid) begin
+        shift <= {1'b1, data, 1'b0};
+        bits <= 10;
+        count <= 0;
+      end
+    end else if (count == CLKS_PER_BIT - 1) begin
+      // Idle high once everything is out.
+      shift <= {1'b1, shift[9:1]};
+      bits <= bits - 1;
+      count <= 0;
+    end else count <= count + 1;
+  end
+
+endmodule
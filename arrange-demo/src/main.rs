@@ -1,6 +1,9 @@
 use std::time::Instant;
 
-use arrange::prelude::*;
+use arrange::{
+    prelude::*,
+    FTDI::{comm::CommMode, config::ArrangeFTDIConfig, uart::UartConfig},
+};
 use log::info;
 
 fn main() {
@@ -8,8 +11,10 @@ fn main() {
     let bitstream = include_bytes!("hw/build/bitstream.bin");
     info!("Bitstream Byte Count: {}", bitstream.len());
 
-    // Create and initalize Arrange.
-    let mut arrange = arrange::Arrange::new();
+    // Create and initalize Arrange, with the UART that the bitstream's register bridge listens on.
+    let mut config = ArrangeFTDIConfig::default();
+    config.comm.mode = CommMode::Uart(UartConfig::default());
+    let mut arrange = arrange::Arrange::with_config(config);
     match arrange.init() {
         Ok(_) => {
             // If we have a valid Arrange device.
@@ -23,6 +28,15 @@ fn main() {
             // could be sped up by doing incremental flashing?
             // so only rewrite when you find a difference.
             println!("Burning Time: {:?}", elapsed);
+
+            // The bitstream exposes its LEDs and a counter as registers, see hw/top.v.
+            let mut registers = arrange.registers().unwrap();
+            let id = registers.read32(0x00).unwrap();
+            let counter = registers.read32(0x08).unwrap();
+            println!("Gateware ID: {id:#010X}, Counter: {counter}");
+
+            // Light every other LED.
+            registers.write32(0x04, 0x105).unwrap();
         }
 
         Err(_) => {
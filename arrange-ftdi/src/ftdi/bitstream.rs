@@ -0,0 +1,150 @@
+//! The framing of iCE40 bitstreams, as written by `icepack`.
+//!
+//! After an optional comment header, a bitstream starts with the preamble `7E AA 99 7E`. A
+//! sequence of commands follows: the high nibble of a command byte is the command, the low nibble
+//! the number of payload bytes after it. Bank data for the configuration and block RAMs follows
+//! its command, sized by the bank width and height set before it, and the bitstream ends with
+//! the wakeup command.
+
+use arrange_misc::error::ArrangeError;
+
+/// Marks the start of the bitstream proper.
+pub const PREAMBLE: [u8; 4] = [0x7E, 0xAA, 0x99, 0x7E];
+
+/// How far into the image the preamble may be, past any comment header.
+pub const MAX_HEADER: usize = 4096;
+
+fn error(message: String) -> ArrangeError {
+    ArrangeError::UnexpectedResponse {
+        operation: "bitstream",
+        message,
+    }
+}
+
+/// Finds the end of a bitstream, a piece at a time.
+///
+/// ```
+/// # use arrange_ftdi::ftdi::bitstream::Framing;
+/// let image = [0x7E, 0xAA, 0x99, 0x7E, 0x01, 0x05, 0x01, 0x06, 0x00, 0xFF, 0xFF];
+/// let mut framing = Framing::new();
+/// assert_eq!(framing.advance(&image[..6]).unwrap(), None);
+/// assert_eq!(framing.advance(&image).unwrap(), Some(9));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Framing {
+    /// Where the next command starts, once the preamble has been found.
+    offset: Option<usize>,
+    /// Width in bits of the current bank.
+    width: usize,
+    /// Height in rows of the current bank.
+    height: usize,
+    /// Just past the wakeup command, once it has been seen.
+    wakeup: Option<usize>,
+}
+
+impl Framing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses as much of `bytes`, the start of the image, as it can.
+    ///
+    /// Every call must pass everything passed before and possibly more. Returns the length of the
+    /// bitstream once its end is in `bytes`, including the padding byte `icepack` writes after
+    /// the wakeup command, or `None` if more of the image is needed.
+    pub fn advance(&mut self, bytes: &[u8]) -> Result<Option<usize>, ArrangeError> {
+        let mut offset = match self.offset {
+            Some(offset) => offset,
+            None => {
+                let header = &bytes[..bytes.len().min(MAX_HEADER)];
+                match header.windows(PREAMBLE.len()).position(|window| window == PREAMBLE) {
+                    Some(start) => start + PREAMBLE.len(),
+                    None if bytes.len() >= MAX_HEADER => {
+                        return Err(error(format!(
+                            "no preamble in the first {MAX_HEADER} bytes"
+                        )))
+                    }
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        let result = loop {
+            if let Some(end) = self.wakeup {
+                break match bytes.get(end) {
+                    None => Ok(None),
+                    Some(0x00) => Ok(Some(end + 1)),
+                    Some(_) => Ok(Some(end)),
+                };
+            }
+
+            let Some(&command) = bytes.get(offset) else {
+                break Ok(None);
+            };
+            let start = offset + 1;
+            let Some(payload) = bytes.get(start..start + (command & 0x0F) as usize) else {
+                break Ok(None);
+            };
+            let payload = payload
+                .iter()
+                .fold(0usize, |value, byte| value << 8 | *byte as usize);
+            let mut next = start + (command & 0x0F) as usize;
+            let too_large = || error(format!("bank at {offset:#08X} is too large"));
+
+            match (command >> 4, payload) {
+                // Configuration or block RAM data, followed by two zero bytes.
+                (0x0, 0x01 | 0x03) => {
+                    if self.width == 0 || self.height == 0 {
+                        break Err(error(format!("bank data at {offset:#08X} without a size")));
+                    }
+
+                    let Some(end) = self
+                        .width
+                        .checked_mul(self.height)
+                        .and_then(|bits| next.checked_add(bits.div_ceil(8)))
+                        .and_then(|end| end.checked_add(2))
+                    else {
+                        break Err(too_large());
+                    };
+                    let Some(trailer) = bytes.get(end - 2..end) else {
+                        break Ok(None);
+                    };
+                    if trailer != [0, 0] {
+                        break Err(error(format!(
+                            "bank data at {offset:#08X} is not followed by zeros"
+                        )));
+                    }
+                    next = end;
+                }
+                // Reset the CRC.
+                (0x0, 0x05) => {}
+                (0x0, 0x06) => self.wakeup = Some(next),
+                (0x0, 0x08) => {
+                    let message = "multiple image (warmboot) bitstreams are not supported";
+                    break Err(error(message.to_string()));
+                }
+                (0x0, other) => {
+                    break Err(error(format!("unknown command {other:#04X} at {offset:#08X}")))
+                }
+                (0x6 | 0x7, _) if command & 0x0F > 2 => {
+                    break Err(error(format!(
+                        "bank size at {offset:#08X} has {} bytes, at most 2 fit",
+                        command & 0x0F
+                    )))
+                }
+                (0x6, width) => match width.checked_add(1) {
+                    Some(width) => self.width = width,
+                    None => break Err(too_large()),
+                },
+                (0x7, height) => self.height = height,
+                // Bank number, CRC check, frequency range, bank offset and flags.
+                _ => {}
+            }
+
+            offset = next;
+        };
+
+        self.offset = Some(offset);
+        result
+    }
+}
@@ -9,6 +9,6 @@ pub enum BlockErase {
 
 impl fmt::Display for BlockErase {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", (*self as usize).to_string())
+        write!(f, "{}", *self as usize)
     }
 }
@@ -0,0 +1,119 @@
+use core::fmt;
+
+#[cfg(feature = "libftdi")]
+use libftdi1_sys::ftdi_interface;
+
+use super::pins::PinMap;
+
+/// An interface (channel) of a multi-channel FTDI chip.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interface {
+    A,
+    B,
+    C,
+    D,
+}
+
+#[cfg(feature = "libftdi")]
+impl From<Interface> for ftdi_interface {
+    fn from(interface: Interface) -> Self {
+        match interface {
+            Interface::A => ftdi_interface::INTERFACE_A,
+            Interface::B => ftdi_interface::INTERFACE_B,
+            Interface::C => ftdi_interface::INTERFACE_C,
+            Interface::D => ftdi_interface::INTERFACE_D,
+        }
+    }
+}
+
+impl fmt::Display for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// Describes how an FPGA board is wired to its FTDI chip.
+///
+/// Use one of the named constructors for known boards, or fill in the fields for a custom
+/// board.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BoardProfile {
+    pub name: String,
+    /// USB vendor and product IDs to look for, tried in order.
+    pub ids: Vec<(u16, u16)>,
+    /// Interface wired to the configuration flash.
+    pub flash_interface: Interface,
+    /// Interface wired to the FPGA for communication, if there is one.
+    pub comm_interface: Option<Interface>,
+    pub pins: PinMap,
+}
+
+impl BoardProfile {
+    const FTDI_VENDOR: u16 = 0x0403;
+    const FT2232H: u16 = 0x6010;
+    const FT232H: u16 = 0x6014;
+
+    /// Lattice iCEstick evaluation kit (FT2232H).
+    pub fn icestick() -> Self {
+        Self {
+            name: "icestick".to_string(),
+            ids: vec![(Self::FTDI_VENDOR, Self::FT2232H)],
+            flash_interface: Interface::A,
+            comm_interface: Some(Interface::B),
+            pins: PinMap::default(),
+        }
+    }
+
+    /// Lattice iCE40-HX8K breakout board (FT2232H).
+    pub fn hx8k_breakout() -> Self {
+        Self {
+            name: "hx8k".to_string(),
+            ..Self::icestick()
+        }
+    }
+
+    /// 1BitSquared iCEBreaker (FT2232H).
+    pub fn icebreaker() -> Self {
+        Self {
+            name: "icebreaker".to_string(),
+            ..Self::icestick()
+        }
+    }
+
+    /// A board built around a single-interface FT232H, with the flash on its only interface
+    /// and nothing to talk to the gateware over.
+    pub fn ft232h() -> Self {
+        Self {
+            name: "ft232h".to_string(),
+            ids: vec![(Self::FTDI_VENDOR, Self::FT232H)],
+            flash_interface: Interface::A,
+            comm_interface: None,
+            pins: PinMap::default(),
+        }
+    }
+
+    /// Looks up a known board by the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "icestick" => Some(Self::icestick()),
+            "hx8k" => Some(Self::hx8k_breakout()),
+            "icebreaker" => Some(Self::icebreaker()),
+            "ft232h" => Some(Self::ft232h()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for BoardProfile {
+    /// Any FT2232H with the standard iCE40 wiring. FT232H boards have no comm interface, see
+    /// [`BoardProfile::ft232h`].
+    fn default() -> Self {
+        Self {
+            name: "default".to_string(),
+            ids: vec![(Self::FTDI_VENDOR, Self::FT2232H)],
+            flash_interface: Interface::A,
+            comm_interface: Some(Interface::B),
+            pins: PinMap::default(),
+        }
+    }
+}
@@ -0,0 +1,484 @@
+//! Turns a [`Capture`] back into MPSSE commands and SPI flash transactions.
+//!
+//! ```
+//! use arrange_ftdi::ftdi::{
+//!     board::Interface,
+//!     capture::{decode, Capture},
+//!     pins::PinMap,
+//! };
+//!
+//! let capture: Capture = "0.000100 A open
+//! 0.000200 A W 80 00 93 31 01 00 05 05 80 80 83 87
+//! 0.000300 A R ff 03"
+//!     .parse()
+//!     .unwrap();
+//!
+//! let commands = decode::mpsse_commands(&capture, Interface::A);
+//! let transactions = decode::flash_transactions(&commands, &PinMap::default());
+//! assert_eq!(transactions[0].to_string(), "RSR1 -> 0x03");
+//! ```
+
+use std::fmt;
+
+use super::{Capture, Event};
+use crate::ftdi::{
+    board::Interface,
+    flash::FlashCommand,
+    mpsse::{MPSSECommand, MPSSE},
+    pins::{PinByte, PinMap},
+    transport::BitMode,
+};
+
+/// An MPSSE command, as decoded from what was written to the chip and what it answered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodedCommand {
+    SetGpio {
+        byte: PinByte,
+        value: u8,
+        direction: u8,
+    },
+    /// Reading the GPIO, `value` is `None` if the answer was not captured.
+    ReadGpio { byte: PinByte, value: Option<u8> },
+    ClockDivisor(u16),
+    DivideBy5(bool),
+    Loopback(bool),
+    Flush,
+    /// Waiting for GPIOL1 to go high (`true`) or low.
+    WaitGpiol1(bool),
+    /// Shifting `length` bytes (or bits) of data. `mosi` holds what was clocked out, `miso` what
+    /// was clocked in, as far as it was captured.
+    Data {
+        opcode: u8,
+        bits: bool,
+        length: usize,
+        mosi: Option<Vec<u8>>,
+        miso: Option<Vec<u8>>,
+    },
+    /// A read cycle on the MCU host bus, `value` is `None` if the answer was not captured. Short
+    /// (not `extended`) addresses only carry A7 to A0.
+    CpuRead {
+        address: u16,
+        extended: bool,
+        value: Option<u8>,
+    },
+    /// A write cycle on the MCU host bus.
+    CpuWrite {
+        address: u16,
+        extended: bool,
+        value: u8,
+    },
+    /// A command taking no arguments that is not decoded any further.
+    Other(u8),
+    /// A command the MPSSE does not know, which it answers with `0xFA`.
+    Invalid(u8),
+    /// The start of a command that never got to the chip in full, e.g. after a short write.
+    Truncated(Vec<u8>),
+}
+
+/// Everything clocked over SPI while the flash was selected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlashTransaction {
+    pub mosi: Vec<u8>,
+    /// What the flash answered, `0xFF` where data was only clocked out.
+    pub miso: Vec<u8>,
+}
+
+/// How many bytes of a payload are shown before it is cut short.
+const PREVIEW_LEN: usize = 16;
+
+fn write_preview(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for byte in bytes.iter().take(PREVIEW_LEN) {
+        write!(f, " {byte:02x}")?;
+    }
+    if bytes.len() > PREVIEW_LEN {
+        write!(f, " ...")?;
+    }
+
+    Ok(())
+}
+
+impl fmt::Display for DecodedCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let gpio = |byte: &PinByte| match byte {
+            PinByte::Low => "BLOW",
+            PinByte::High => "BHIGH",
+        };
+
+        match self {
+            DecodedCommand::SetGpio {
+                byte,
+                value,
+                direction,
+            } => write!(f, "SET{} {value:#04x} direction {direction:#04x}", gpio(byte)),
+            DecodedCommand::ReadGpio {
+                byte,
+                value: Some(value),
+            } => write!(f, "READ{} -> {value:#04x}", gpio(byte)),
+            DecodedCommand::ReadGpio { byte, value: None } => write!(f, "READ{} -> ?", gpio(byte)),
+            DecodedCommand::ClockDivisor(divisor) => write!(f, "SETCLKDIV {divisor}"),
+            DecodedCommand::DivideBy5(true) => write!(f, "TCKD5"),
+            DecodedCommand::DivideBy5(false) => write!(f, "TCKX5"),
+            DecodedCommand::Loopback(true) => write!(f, "LOOPBACKEN"),
+            DecodedCommand::Loopback(false) => write!(f, "LOOPBACKDIS"),
+            DecodedCommand::Flush => write!(f, "FLUSH"),
+            DecodedCommand::WaitGpiol1(true) => write!(f, "WAITH"),
+            DecodedCommand::WaitGpiol1(false) => write!(f, "WAITL"),
+            DecodedCommand::Data {
+                bits,
+                length,
+                mosi,
+                miso,
+                ..
+            } => {
+                let direction = match (mosi.is_some(), miso.is_some()) {
+                    (true, true) => "IN/OUT",
+                    (true, false) => "OUT",
+                    _ => "IN",
+                };
+                let unit = if *bits { "bits" } else { "bytes" };
+                write!(f, "DATA {direction} {length} {unit}")?;
+
+                if let Some(mosi) = mosi {
+                    write!(f, ":")?;
+                    write_preview(f, mosi)?;
+                }
+                if let Some(miso) = miso {
+                    write!(f, " ->")?;
+                    write_preview(f, miso)?;
+                }
+
+                Ok(())
+            }
+            DecodedCommand::CpuRead {
+                address,
+                extended,
+                value,
+            } => {
+                match extended {
+                    true => write!(f, "CPURE {address:#06x} ->")?,
+                    false => write!(f, "CPURS {address:#04x} ->")?,
+                }
+                match value {
+                    Some(value) => write!(f, " {value:#04x}"),
+                    None => write!(f, " ?"),
+                }
+            }
+            DecodedCommand::CpuWrite {
+                address,
+                extended: true,
+                value,
+            } => write!(f, "CPUWE {address:#06x} <- {value:#04x}"),
+            DecodedCommand::CpuWrite {
+                address,
+                extended: false,
+                value,
+            } => write!(f, "CPUWS {address:#04x} <- {value:#04x}"),
+            DecodedCommand::Other(opcode) => write!(f, "{opcode:#04x}"),
+            DecodedCommand::Invalid(opcode) => write!(f, "invalid command {opcode:#04x}"),
+            DecodedCommand::Truncated(bytes) => {
+                write!(f, "truncated command:")?;
+                write_preview(f, bytes)
+            }
+        }
+    }
+}
+
+impl FlashTransaction {
+    /// The 24 bit address following the opcode.
+    fn address(&self) -> Option<usize> {
+        match self.mosi.get(1..4) {
+            Some(&[high, middle, low]) => {
+                Some((high as usize) << 16 | (middle as usize) << 8 | low as usize)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FlashTransaction {
+    /// Describes the transaction like `PP 0x001200 256 bytes` or `RSR1 -> 0x03`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(&opcode) = self.mosi.first() else {
+            return write!(f, "(empty)");
+        };
+        let Some(command) = FlashCommand::from_opcode(opcode) else {
+            write!(f, "{opcode:#04x} ({} bytes)", self.mosi.len())?;
+            return Ok(());
+        };
+
+        let payload = |start: usize| self.mosi.len().saturating_sub(start);
+        match (command, self.address()) {
+            (FlashCommand::PP, Some(address)) => {
+                write!(f, "PP {address:#08x} {} bytes", payload(4))
+            }
+            (FlashCommand::RD, Some(address)) => {
+                write!(f, "RD {address:#08x} {} bytes", payload(4))
+            }
+            // Fast read has a dummy byte after the address.
+            (FlashCommand::FR, Some(address)) => {
+                write!(f, "FR {address:#08x} {} bytes", payload(5))
+            }
+            (
+                FlashCommand::SE | FlashCommand::BE32 | FlashCommand::BE64,
+                Some(address),
+            ) => write!(f, "{command:?} {address:#08x}"),
+            (FlashCommand::RSR1 | FlashCommand::RSR2 | FlashCommand::RSR3, _)
+                if self.miso.len() > 1 =>
+            {
+                write!(f, "{command:?} -> {:#04x}", self.miso[self.miso.len() - 1])
+            }
+            (FlashCommand::WSR1 | FlashCommand::WSR2 | FlashCommand::WSR3, _)
+                if self.mosi.len() > 1 =>
+            {
+                write!(f, "{command:?} <- {:#04x}", self.mosi[1])
+            }
+            (FlashCommand::JEDECID | FlashCommand::MFGID | FlashCommand::UID, _) => {
+                write!(f, "{command:?} ->")?;
+                write_preview(f, &self.miso[1..])
+            }
+            (command, _) if self.mosi.len() == 1 => write!(f, "{command:?}"),
+            (command, _) => write!(f, "{command:?} +{} bytes", payload(1)),
+        }
+    }
+}
+
+/// Parses `written` into commands, taking their answers from `read`. In MCU host bus emulation
+/// (`cpu`) only the bus cycles and `FLUSH` are commands.
+fn parse(
+    written: &[u8],
+    read: &mut impl Iterator<Item = u8>,
+    commands: &mut Vec<DecodedCommand>,
+    cpu: bool,
+) {
+    let mut position = 0;
+    while position < written.len() {
+        let bytes = &written[position..];
+        let opcode = bytes[0];
+        let command = MPSSECommand::from_opcode(opcode);
+        let gpio_byte = match command {
+            Some(MPSSECommand::SETBHIGH | MPSSECommand::READBHIGH) => PinByte::High,
+            _ => PinByte::Low,
+        };
+
+        let invalid = |read: &mut dyn Iterator<Item = u8>| {
+            // Skip the chip's 0xFA and the echoed command.
+            read.take(2).for_each(drop);
+            (DecodedCommand::Invalid(opcode), 1)
+        };
+
+        let (decoded, length) = match command {
+            Some(
+                MPSSECommand::CPURS
+                | MPSSECommand::CPURE
+                | MPSSECommand::CPUWS
+                | MPSSECommand::CPUWE,
+            ) if cpu => {
+                let extended = matches!(command, Some(MPSSECommand::CPURE | MPSSECommand::CPUWE));
+                let write = matches!(command, Some(MPSSECommand::CPUWS | MPSSECommand::CPUWE));
+                let header = if extended { 3 } else { 2 };
+                let length = header + write as usize;
+                let address = if extended {
+                    bytes.get(1..3).map(|address| u16::from_be_bytes([address[0], address[1]]))
+                } else {
+                    bytes.get(1).map(|&address| address as u16)
+                };
+
+                match (address, bytes.get(header)) {
+                    (Some(address), Some(&value)) if write => (
+                        DecodedCommand::CpuWrite {
+                            address,
+                            extended,
+                            value,
+                        },
+                        length,
+                    ),
+                    (Some(address), _) if !write => (
+                        DecodedCommand::CpuRead {
+                            address,
+                            extended,
+                            value: read.next(),
+                        },
+                        length,
+                    ),
+                    _ => (DecodedCommand::Truncated(bytes.to_vec()), bytes.len()),
+                }
+            }
+            Some(MPSSECommand::FLUSH) => (DecodedCommand::Flush, 1),
+            _ if cpu => invalid(read),
+            Some(MPSSECommand::SETBLOW | MPSSECommand::SETBHIGH) if bytes.len() >= 3 => (
+                DecodedCommand::SetGpio {
+                    byte: gpio_byte,
+                    value: bytes[1],
+                    direction: bytes[2],
+                },
+                3,
+            ),
+            Some(MPSSECommand::READBLOW | MPSSECommand::READBHIGH) => (
+                DecodedCommand::ReadGpio {
+                    byte: gpio_byte,
+                    value: read.next(),
+                },
+                1,
+            ),
+            Some(MPSSECommand::SETCLKDIV) if bytes.len() >= 3 => (
+                DecodedCommand::ClockDivisor(u16::from_le_bytes([bytes[1], bytes[2]])),
+                3,
+            ),
+            Some(MPSSECommand::TCKX5 | MPSSECommand::TCKD5) => {
+                (DecodedCommand::DivideBy5(command == Some(MPSSECommand::TCKD5)), 1)
+            }
+            Some(MPSSECommand::LOOPBACKEN | MPSSECommand::LOOPBACKDIS) => {
+                (DecodedCommand::Loopback(command == Some(MPSSECommand::LOOPBACKEN)), 1)
+            }
+            Some(MPSSECommand::WAITH | MPSSECommand::WAITL) => {
+                (DecodedCommand::WaitGpiol1(command == Some(MPSSECommand::WAITH)), 1)
+            }
+            Some(
+                MPSSECommand::EN3PHCLK
+                | MPSSECommand::DIS3PHCLK
+                | MPSSECommand::ENADPTCLK
+                | MPSSECommand::DISADPTCLK,
+            ) => (DecodedCommand::Other(opcode), 1),
+            Some(MPSSECommand::SETBLOW | MPSSECommand::SETBHIGH | MPSSECommand::SETCLKDIV) => {
+                (DecodedCommand::Truncated(bytes.to_vec()), bytes.len())
+            }
+            None if opcode & 0x80 == 0 && opcode & MPSSE::DATA_TMS == 0 => {
+                let bits = opcode & MPSSE::DATA_BITS != 0;
+                let data_out = opcode & MPSSE::DATA_OUT != 0;
+                let data_in = opcode & MPSSE::DATA_IN != 0;
+                let (length, header, payload) = if bits {
+                    (bytes.get(1).map(|&n| n as usize + 1), 2, 1)
+                } else {
+                    let length = bytes
+                        .get(1..3)
+                        .map(|length| u16::from_le_bytes([length[0], length[1]]) as usize + 1);
+                    (length, 3, length.unwrap_or(0))
+                };
+                let payload = if data_out { payload } else { 0 };
+
+                match length {
+                    Some(length) if bytes.len() >= header + payload => {
+                        let response_len = if bits { 1 } else { length };
+                        (
+                            DecodedCommand::Data {
+                                opcode,
+                                bits,
+                                length,
+                                mosi: data_out.then(|| bytes[header..header + payload].to_vec()),
+                                miso: data_in.then(|| read.by_ref().take(response_len).collect()),
+                            },
+                            header + payload,
+                        )
+                    }
+                    _ => (DecodedCommand::Truncated(bytes.to_vec()), bytes.len()),
+                }
+            }
+            _ => invalid(read),
+        };
+
+        commands.push(decoded);
+        position += length;
+    }
+}
+
+/// The MPSSE commands sent to `interface`, with their answers.
+///
+/// Reopening, resetting, purging or changing the bit mode of the interface discards anything
+/// unfinished, so each of those starts afresh. Traffic while the interface is in UART mode is
+/// skipped.
+pub fn mpsse_commands(capture: &Capture, interface: Interface) -> Vec<DecodedCommand> {
+    let mut commands = vec![];
+    let mut written = vec![];
+    let mut read = vec![];
+    let mut mode = BitMode::Mpsse;
+
+    for event in capture.events(interface) {
+        match event {
+            Event::Write(data) if mode != BitMode::Reset => written.extend_from_slice(data),
+            Event::Read(data) if mode != BitMode::Reset => read.extend_from_slice(data),
+            Event::Write(_)
+            | Event::Read(_)
+            | Event::LatencyTimer(_)
+            | Event::SetLatencyTimer(_)
+            | Event::SetBaudRate { .. }
+            | Event::SetLineProperties { .. }
+            | Event::SetFlowControl(_)
+            | Event::Failed { .. } => {}
+            Event::Open | Event::Close | Event::Reset | Event::Purge | Event::SetBitmode { .. } => {
+                parse(&written, &mut read.drain(..), &mut commands, mode == BitMode::Mcu);
+                written.clear();
+                if let Event::SetBitmode { mode: new, .. } = event {
+                    mode = *new;
+                }
+            }
+        }
+    }
+    parse(&written, &mut read.drain(..), &mut commands, mode == BitMode::Mcu);
+
+    commands
+}
+
+/// Groups `commands` into the SPI transactions the flash saw, using `pins` to follow its chip
+/// select.
+pub fn flash_transactions(commands: &[DecodedCommand], pins: &PinMap) -> Vec<FlashTransaction> {
+    let mut transactions = vec![];
+    let mut current: Option<FlashTransaction> = None;
+    let mut gpio = [(0u8, 0u8); 2];
+    let cs = pins.cs;
+    let cs_byte = match cs.byte {
+        PinByte::Low => 0,
+        PinByte::High => 1,
+    };
+
+    for command in commands {
+        match command {
+            DecodedCommand::SetGpio {
+                byte,
+                value,
+                direction,
+            } => {
+                let index = match byte {
+                    PinByte::Low => 0,
+                    PinByte::High => 1,
+                };
+                gpio[index] = (*value, *direction);
+
+                let (value, direction) = gpio[cs_byte];
+                let selected = direction & cs.mask() != 0 && value & cs.mask() == 0;
+                match (selected, current.take()) {
+                    (true, None) => {
+                        current = Some(FlashTransaction {
+                            mosi: vec![],
+                            miso: vec![],
+                        })
+                    }
+                    (true, Some(transaction)) => current = Some(transaction),
+                    (false, Some(transaction)) if !transaction.mosi.is_empty() => {
+                        transactions.push(transaction)
+                    }
+                    (false, _) => {}
+                }
+            }
+            DecodedCommand::Data {
+                bits: false,
+                length,
+                mosi,
+                miso,
+                ..
+            } => {
+                if let Some(transaction) = current.as_mut() {
+                    let mut mosi = mosi.clone().unwrap_or_default();
+                    mosi.resize(*length, 0);
+                    let mut miso = miso.clone().unwrap_or_default();
+                    miso.resize(*length, 0xFF);
+                    transaction.mosi.extend(mosi);
+                    transaction.miso.extend(miso);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    transactions.extend(current.filter(|transaction| !transaction.mosi.is_empty()));
+    transactions
+}
@@ -0,0 +1,516 @@
+//! Capturing, decoding and replaying the traffic between [`MPSSE`](super::mpsse::MPSSE) and the
+//! chip.
+//!
+//! Wrapping a transport in a [`Recorder`] logs every call made on it, with the bytes written and
+//! read and when, to a [`CaptureLog`]. The log can be written to a file as it goes:
+//!
+//! ```text
+//! # arrange capture
+//! 0.000021 A open
+//! 0.001043 A bitmode 02 ff
+//! 0.001187 A W 8b 86 02 00
+//! 0.001350 A W 80 00 93 31 01 00 05 05 80 00 83 87
+//! 0.002016 A R ff 03
+//! ```
+//!
+//! Calls that fail are captured too, with the error they failed with.
+//!
+//! [`decode`] turns a [`Capture`] back into MPSSE commands and SPI flash transactions, and
+//! [`Replay`] plays one back to [`Flash`](super::flash::Flash) instead of a device.
+
+pub mod decode;
+mod replay;
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use arrange_misc::error::ArrangeError;
+use log::warn;
+
+pub use self::replay::Replay;
+use super::{
+    board::Interface,
+    device_string::DeviceString,
+    transport::{BitMode, Transport},
+    uart::{DataBits, FlowControl, Parity, StopBits},
+};
+
+/// A call made on a [`Transport`], as captured by a [`Recorder`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    Open,
+    Close,
+    Reset,
+    Purge,
+    /// The latency timer was read and had this value.
+    LatencyTimer(u8),
+    SetLatencyTimer(u8),
+    SetBitmode { mode: BitMode, mask: u8 },
+    /// The baud rate asked for, and the rate the chip settled on.
+    SetBaudRate { baud: u32, actual: u32 },
+    SetLineProperties {
+        data_bits: DataBits,
+        stop_bits: StopBits,
+        parity: Parity,
+    },
+    SetFlowControl(FlowControl),
+    /// Bytes the chip accepted.
+    Write(Vec<u8>),
+    /// Bytes the chip returned. Reads that returned nothing are not captured.
+    Read(Vec<u8>),
+    /// A call that failed, named like the event it would have captured (`W`, `bitmode`, ...),
+    /// and the error it failed with.
+    Failed { call: String, error: String },
+}
+
+impl Event {
+    fn failed(call: &str, error: &ArrangeError) -> Self {
+        Event::Failed {
+            call: call.to_string(),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// An [`Event`] on one interface, `time` after the capture started.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub time: Duration,
+    pub interface: Interface,
+    pub event: Event,
+}
+
+/// A captured session, every interface interleaved in the order things happened.
+///
+/// Converts to and from the text format written by [`CaptureLog::create`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capture {
+    pub records: Vec<Record>,
+}
+
+impl Capture {
+    const HEADER: &'static str = "# arrange capture";
+
+    /// The events on `interface`, in order.
+    pub fn events(&self, interface: Interface) -> impl Iterator<Item = &Event> + '_ {
+        self.records
+            .iter()
+            .filter(move |record| record.interface == interface)
+            .map(|record| &record.event)
+    }
+}
+
+fn write_hex(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for byte in bytes {
+        write!(f, " {byte:02x}")?;
+    }
+
+    Ok(())
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Open => write!(f, "open"),
+            Event::Close => write!(f, "close"),
+            Event::Reset => write!(f, "reset"),
+            Event::Purge => write!(f, "purge"),
+            Event::LatencyTimer(latency) => write!(f, "latency {latency:02x}"),
+            Event::SetLatencyTimer(latency) => write!(f, "set-latency {latency:02x}"),
+            Event::SetBitmode { mode, mask } => {
+                write!(f, "bitmode {:02x} {mask:02x}", *mode as u8)
+            }
+            Event::SetBaudRate { baud, actual } => write!(f, "baud {baud} {actual}"),
+            Event::SetLineProperties {
+                data_bits,
+                stop_bits,
+                parity,
+            } => write!(f, "line {data_bits} {parity} {stop_bits}"),
+            Event::SetFlowControl(flow_control) => write!(f, "flow {flow_control}"),
+            Event::Write(data) => {
+                write!(f, "W")?;
+                write_hex(f, data)
+            }
+            Event::Read(data) => {
+                write!(f, "R")?;
+                write_hex(f, data)
+            }
+            Event::Failed { call, error } => write!(f, "failed {call} {error}"),
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:06} {} {}",
+            self.time.as_secs(),
+            self.time.subsec_micros(),
+            self.interface,
+            self.event
+        )
+    }
+}
+
+impl fmt::Display for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", Capture::HEADER)?;
+        for record in &self.records {
+            writeln!(f, "{record}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a UART setting, in the format it is displayed in.
+fn setting<S: FromStr<Err = ArrangeError>>(value: &str) -> Result<S, String> {
+    value.parse().map_err(|error: ArrangeError| error.to_string())
+}
+
+impl FromStr for Record {
+    type Err = String;
+
+    /// Parses a line such as `0.001350 A W 80 00 93`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let mut field = |name: &str| fields.next().ok_or(format!("missing {name}"));
+
+        let time = field("time")?;
+        let time = time
+            .split_once('.')
+            .filter(|(_, micros)| micros.len() == 6)
+            .and_then(|(secs, micros)| Some((secs.parse().ok()?, micros.parse::<u32>().ok()?)))
+            .map(|(secs, micros)| Duration::new(secs, micros * 1000))
+            .ok_or(format!("bad time '{time}'"))?;
+        let interface = match field("interface")? {
+            "A" => Interface::A,
+            "B" => Interface::B,
+            "C" => Interface::C,
+            "D" => Interface::D,
+            interface => return Err(format!("bad interface '{interface}'")),
+        };
+        let name = field("event")?;
+        let arguments: Vec<&str> = fields.collect();
+        let argument = |n: usize| {
+            arguments
+                .get(n)
+                .copied()
+                .ok_or(format!("{name} is missing an argument"))
+        };
+        let byte = |n: usize| {
+            let value = argument(n)?;
+            u8::from_str_radix(value, 16).map_err(|_| format!("bad byte '{value}'"))
+        };
+        let number = |n: usize| {
+            let value = argument(n)?;
+            value
+                .parse::<u32>()
+                .map_err(|_| format!("bad number '{value}'"))
+        };
+        let bytes = || {
+            arguments
+                .iter()
+                .map(|byte| {
+                    u8::from_str_radix(byte, 16).map_err(|_| format!("bad byte '{byte}'"))
+                })
+                .collect::<Result<Vec<u8>, _>>()
+        };
+
+        let event = match name {
+            "open" => Event::Open,
+            "close" => Event::Close,
+            "reset" => Event::Reset,
+            "purge" => Event::Purge,
+            "latency" => Event::LatencyTimer(byte(0)?),
+            "set-latency" => Event::SetLatencyTimer(byte(0)?),
+            "bitmode" => Event::SetBitmode {
+                mode: match byte(0)? {
+                    0x00 => BitMode::Reset,
+                    0x02 => BitMode::Mpsse,
+                    0x08 => BitMode::Mcu,
+                    mode => return Err(format!("unknown bitmode {mode:#04x}")),
+                },
+                mask: byte(1)?,
+            },
+            "baud" => Event::SetBaudRate {
+                baud: number(0)?,
+                actual: number(1)?,
+            },
+            "line" => Event::SetLineProperties {
+                data_bits: setting(argument(0)?)?,
+                parity: setting(argument(1)?)?,
+                stop_bits: setting(argument(2)?)?,
+            },
+            "flow" => Event::SetFlowControl(setting(argument(0)?)?),
+            "W" => Event::Write(bytes()?),
+            "R" => Event::Read(bytes()?),
+            "failed" => Event::Failed {
+                call: argument(0)?.to_string(),
+                error: arguments[1..].join(" "),
+            },
+            name => return Err(format!("unknown event '{name}'")),
+        };
+
+        Ok(Record {
+            time,
+            interface,
+            event,
+        })
+    }
+}
+
+impl FromStr for Capture {
+    type Err = ArrangeError;
+
+    /// Parses a capture in the format written by [`CaptureLog::create`], ignoring blank lines
+    /// and `#` comments.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let records = s
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+            .map(|(number, line)| {
+                line.parse()
+                    .map_err(|message| ArrangeError::InvalidConfiguration {
+                        message: format!("capture line {}: {message}", number + 1),
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Capture { records })
+    }
+}
+
+struct LogState {
+    start: Instant,
+    capture: Capture,
+    file: Option<BufWriter<File>>,
+}
+
+/// Where [`Recorder`]s put what they capture. Clones share the same log, so every interface of
+/// a device ends up in one capture.
+#[derive(Clone)]
+pub struct CaptureLog {
+    state: Arc<Mutex<LogState>>,
+}
+
+impl Default for CaptureLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CaptureLog {
+    /// A log kept in memory, see [`CaptureLog::capture`].
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(LogState {
+                start: Instant::now(),
+                capture: Capture::default(),
+                file: None,
+            })),
+        }
+    }
+
+    /// A log also written to `path` as it is captured.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "{}", Capture::HEADER)?;
+
+        let log = Self::new();
+        log.state().file = Some(file);
+        Ok(log)
+    }
+
+    fn state(&self) -> MutexGuard<'_, LogState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Everything captured so far. A log written to a file keeps nothing in memory, read the
+    /// file back instead.
+    pub fn capture(&self) -> Capture {
+        self.state().capture.clone()
+    }
+
+    /// Wraps `transport` so that everything done with it is captured here.
+    pub fn recorder<T: Transport>(&self, transport: T) -> Recorder<T> {
+        Recorder {
+            transport,
+            log: self.clone(),
+            interface: None,
+        }
+    }
+
+    fn record(&self, interface: Interface, event: Event) {
+        let mut state = self.state();
+        let elapsed = state.start.elapsed();
+        let record = Record {
+            // The capture is only precise to the microsecond.
+            time: Duration::new(elapsed.as_secs(), elapsed.subsec_micros() * 1000),
+            interface,
+            event,
+        };
+
+        if let Some(file) = state.file.as_mut() {
+            // Losing the capture must not break the programming it is capturing.
+            match writeln!(file, "{record}").and_then(|_| file.flush()) {
+                Ok(()) => return,
+                Err(error) => {
+                    warn!("Failed to write capture, keeping the rest in memory: {error}");
+                    state.file = None;
+                }
+            }
+        }
+        state.capture.records.push(record);
+    }
+}
+
+/// A [`Transport`] that captures everything done through the transport it wraps, see
+/// [`CaptureLog::recorder`].
+pub struct Recorder<T: Transport> {
+    transport: T,
+    log: CaptureLog,
+    /// The interface opened, records are tagged with it.
+    interface: Option<Interface>,
+}
+
+impl<T: Transport + Default> Default for Recorder<T> {
+    /// A recorder over the default transport, capturing to a log of its own.
+    fn default() -> Self {
+        CaptureLog::new().recorder(T::default())
+    }
+}
+
+impl<T: Transport> Recorder<T> {
+    pub fn log(&self) -> &CaptureLog {
+        &self.log
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Captures the outcome of `call`: the event made of what it returned, or its error.
+    fn record<R>(
+        &self,
+        call: &str,
+        result: Result<R, ArrangeError>,
+        event: impl FnOnce(&R) -> Event,
+    ) -> Result<R, ArrangeError> {
+        if let Some(interface) = self.interface {
+            let event = match &result {
+                Ok(value) => event(value),
+                Err(error) => Event::failed(call, error),
+            };
+            self.log.record(interface, event);
+        }
+
+        result
+    }
+}
+
+impl<T: Transport> Transport for Recorder<T> {
+    fn open(
+        &mut self,
+        interface: Interface,
+        ids: &[(u16, u16)],
+        device_string: Option<&DeviceString>,
+    ) -> Result<(), ArrangeError> {
+        self.close();
+        let result = self.transport.open(interface, ids, device_string);
+        match &result {
+            Ok(()) => {
+                self.interface = Some(interface);
+                self.log.record(interface, Event::Open);
+            }
+            Err(error) => self.log.record(interface, Event::failed("open", error)),
+        }
+
+        result
+    }
+
+    fn close(&mut self) {
+        self.transport.close();
+        if let Some(interface) = self.interface.take() {
+            self.log.record(interface, Event::Close);
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), ArrangeError> {
+        let result = self.transport.reset();
+        self.record("reset", result, |_| Event::Reset)
+    }
+
+    fn purge(&mut self) -> Result<(), ArrangeError> {
+        let result = self.transport.purge();
+        self.record("purge", result, |_| Event::Purge)
+    }
+
+    fn latency_timer(&mut self) -> Result<u8, ArrangeError> {
+        let result = self.transport.latency_timer();
+        self.record("latency", result, |latency| Event::LatencyTimer(*latency))
+    }
+
+    fn set_latency_timer(&mut self, latency: u8) -> Result<(), ArrangeError> {
+        let result = self.transport.set_latency_timer(latency);
+        self.record("set-latency", result, |_| Event::SetLatencyTimer(latency))
+    }
+
+    fn set_bitmode(&mut self, mask: u8, mode: BitMode) -> Result<(), ArrangeError> {
+        let result = self.transport.set_bitmode(mask, mode);
+        self.record("bitmode", result, |_| Event::SetBitmode { mode, mask })
+    }
+
+    fn set_baud_rate(&mut self, baud: u32) -> Result<u32, ArrangeError> {
+        let result = self.transport.set_baud_rate(baud);
+        self.record("baud", result, |actual| Event::SetBaudRate {
+            baud,
+            actual: *actual,
+        })
+    }
+
+    fn set_line_properties(
+        &mut self,
+        data_bits: DataBits,
+        stop_bits: StopBits,
+        parity: Parity,
+    ) -> Result<(), ArrangeError> {
+        let result = self.transport.set_line_properties(data_bits, stop_bits, parity);
+        self.record("line", result, |_| Event::SetLineProperties {
+            data_bits,
+            stop_bits,
+            parity,
+        })
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> Result<(), ArrangeError> {
+        let result = self.transport.set_flow_control(flow_control);
+        self.record("flow", result, |_| Event::SetFlowControl(flow_control))
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.transport.set_timeout(timeout);
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, ArrangeError> {
+        let result = self.transport.write(data);
+        self.record("W", result, |written| Event::Write(data[..*written].to_vec()))
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ArrangeError> {
+        let result = self.transport.read(buffer);
+        match result {
+            Ok(0) => result,
+            result => self.record("R", result, |read| Event::Read(buffer[..*read].to_vec())),
+        }
+    }
+}
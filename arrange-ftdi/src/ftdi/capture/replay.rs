@@ -0,0 +1,273 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use arrange_misc::error::ArrangeError;
+
+use super::{Capture, Event};
+use crate::ftdi::{
+    board::Interface,
+    device_string::DeviceString,
+    transport::{BitMode, Transport},
+    uart::{DataBits, FlowControl, Parity, StopBits},
+};
+
+struct ReplayState {
+    capture: Capture,
+    /// For each interface, the index of the first record not replayed yet.
+    cursors: [usize; 4],
+    /// How much of the read being replayed on each interface has been returned already.
+    read_offsets: [usize; 4],
+}
+
+impl ReplayState {
+    /// The next record of `interface` that has not been replayed.
+    fn peek(&mut self, interface: Interface) -> Option<(usize, &Event)> {
+        let start = self.cursors[interface as usize];
+        self.capture.records[start..]
+            .iter()
+            .enumerate()
+            .find(|(_, record)| record.interface == interface)
+            .map(|(offset, record)| (start + offset, &record.event))
+    }
+}
+
+/// A [`Transport`] that plays a [`Capture`] back instead of talking to a device.
+///
+/// Every call must be the one that was captured next on its interface, with the same bytes
+/// written, otherwise it fails with [`ArrangeError::UnexpectedResponse`]. Reads return what was
+/// captured, and calls captured failing fail again with an [`ArrangeError::Ftdi`] carrying the
+/// captured error. Clones share the capture, so one can be handed to each interface.
+#[derive(Clone)]
+pub struct Replay {
+    state: Arc<Mutex<ReplayState>>,
+    interface: Option<Interface>,
+}
+
+impl Default for Replay {
+    /// Replays an empty capture, there is no device to open.
+    fn default() -> Self {
+        Self::new(Capture::default())
+    }
+}
+
+impl Replay {
+    pub fn new(capture: Capture) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ReplayState {
+                capture,
+                cursors: [0; 4],
+                read_offsets: [0; 4],
+            })),
+            interface: None,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, ReplayState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Whether everything captured has been replayed.
+    pub fn is_finished(&self) -> bool {
+        let mut state = self.state();
+        [Interface::A, Interface::B, Interface::C, Interface::D]
+            .into_iter()
+            .all(|interface| state.peek(interface).is_none())
+    }
+
+    /// The error a call captured failing is replayed with.
+    fn failure(error: &str) -> ArrangeError {
+        ArrangeError::Ftdi {
+            operation: "replay",
+            code: -1,
+            message: error.to_string(),
+        }
+    }
+
+    fn interface(&self) -> Result<Interface, ArrangeError> {
+        self.interface.ok_or(ArrangeError::NotOpen {
+            description: "replayed device".to_string(),
+        })
+    }
+
+    /// Replays the next event of our interface, which `expected` must accept unless it is the
+    /// failure of the same call. `wanted` starts with the name of the call.
+    fn expect<R>(
+        &mut self,
+        wanted: &str,
+        expected: impl FnOnce(&Event) -> Option<R>,
+    ) -> Result<R, ArrangeError> {
+        let interface = self.interface()?;
+        let mut state = self.state();
+        let (index, event) = state.peek(interface).ok_or(ArrangeError::UnexpectedResponse {
+            operation: "replay",
+            message: format!("{wanted} on interface {interface}, after the end of the capture"),
+        })?;
+
+        let call = wanted.split(' ').next().unwrap_or(wanted);
+        if let Event::Failed { call: failed, error } = event {
+            if failed == call {
+                let error = Self::failure(error);
+                state.cursors[interface as usize] = index + 1;
+                return Err(error);
+            }
+        }
+
+        match expected(event) {
+            Some(value) => {
+                state.cursors[interface as usize] = index + 1;
+                Ok(value)
+            }
+            None => Err(ArrangeError::UnexpectedResponse {
+                operation: "replay",
+                message: format!("{wanted} on interface {interface}, the capture has '{event}'"),
+            }),
+        }
+    }
+}
+
+impl Transport for Replay {
+    fn open(
+        &mut self,
+        interface: Interface,
+        _ids: &[(u16, u16)],
+        _device_string: Option<&DeviceString>,
+    ) -> Result<(), ArrangeError> {
+        self.close();
+
+        // Skip ahead to the next attempt to open this interface.
+        let mut state = self.state();
+        let start = state.cursors[interface as usize];
+        let opened = state.capture.records[start..]
+            .iter()
+            .enumerate()
+            .find(|(_, record)| {
+                record.interface == interface
+                    && match &record.event {
+                        Event::Open => true,
+                        Event::Failed { call, .. } => call == "open",
+                        _ => false,
+                    }
+            })
+            .map(|(offset, record)| (start + offset, record.event.clone()));
+        match opened {
+            Some((index, event)) => {
+                state.cursors[interface as usize] = index + 1;
+                if let Event::Failed { error, .. } = event {
+                    return Err(Self::failure(&error));
+                }
+            }
+            None => {
+                return Err(ArrangeError::DeviceNotFound {
+                    description: format!("no more sessions on interface {interface} to replay"),
+                })
+            }
+        }
+        drop(state);
+
+        self.interface = Some(interface);
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        if let Some(interface) = self.interface.take() {
+            let mut state = self.state();
+            if let Some((index, Event::Close)) = state.peek(interface) {
+                state.cursors[interface as usize] = index + 1;
+            }
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), ArrangeError> {
+        self.expect("reset", |event| (*event == Event::Reset).then_some(()))
+    }
+
+    fn purge(&mut self) -> Result<(), ArrangeError> {
+        self.expect("purge", |event| (*event == Event::Purge).then_some(()))
+    }
+
+    fn latency_timer(&mut self) -> Result<u8, ArrangeError> {
+        self.expect("latency", |event| match event {
+            Event::LatencyTimer(latency) => Some(*latency),
+            _ => None,
+        })
+    }
+
+    fn set_latency_timer(&mut self, latency: u8) -> Result<(), ArrangeError> {
+        let wanted = Event::SetLatencyTimer(latency);
+        self.expect(&wanted.to_string(), |event| (*event == wanted).then_some(()))
+    }
+
+    fn set_bitmode(&mut self, mask: u8, mode: BitMode) -> Result<(), ArrangeError> {
+        let wanted = Event::SetBitmode { mode, mask };
+        self.expect(&wanted.to_string(), |event| (*event == wanted).then_some(()))
+    }
+
+    fn set_baud_rate(&mut self, baud: u32) -> Result<u32, ArrangeError> {
+        self.expect(&format!("baud {baud}"), |event| match event {
+            Event::SetBaudRate {
+                baud: captured,
+                actual,
+            } if *captured == baud => Some(*actual),
+            _ => None,
+        })
+    }
+
+    fn set_line_properties(
+        &mut self,
+        data_bits: DataBits,
+        stop_bits: StopBits,
+        parity: Parity,
+    ) -> Result<(), ArrangeError> {
+        let wanted = Event::SetLineProperties {
+            data_bits,
+            stop_bits,
+            parity,
+        };
+        self.expect(&wanted.to_string(), |event| (*event == wanted).then_some(()))
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> Result<(), ArrangeError> {
+        let wanted = Event::SetFlowControl(flow_control);
+        self.expect(&wanted.to_string(), |event| (*event == wanted).then_some(()))
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, ArrangeError> {
+        // The capture holds what the chip accepted, which may be less than was written.
+        let wanted = Event::Write(data.to_vec());
+        self.expect(&wanted.to_string(), |event| match event {
+            Event::Write(captured) if data.starts_with(captured) && !captured.is_empty() => {
+                Some(captured.len())
+            }
+            _ => None,
+        })
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ArrangeError> {
+        let interface = self.interface()?;
+        let slot = interface as usize;
+        let mut state = self.state();
+        let offset = state.read_offsets[slot];
+        let (index, data) = match state.peek(interface) {
+            Some((index, Event::Read(data))) => (index, data),
+            Some((index, Event::Failed { call, error })) if call == "R" => {
+                let error = Self::failure(error);
+                state.cursors[slot] = index + 1;
+                return Err(error);
+            }
+            // Nothing was read at this point, the caller keeps polling until it times out.
+            _ => return Ok(0),
+        };
+
+        let count = buffer.len().min(data.len() - offset);
+        buffer[..count].copy_from_slice(&data[offset..offset + count]);
+        let finished = offset + count == data.len();
+
+        if finished {
+            state.cursors[slot] = index + 1;
+            state.read_offsets[slot] = 0;
+        } else {
+            state.read_offsets[slot] = offset + count;
+        }
+
+        Ok(count)
+    }
+}
@@ -0,0 +1,181 @@
+//! The comm interface as a byte stream, for code written against [`std::io`] or, with the
+//! `futures-io` feature, [`futures_io`].
+
+use std::io;
+
+use arrange_misc::error::ArrangeError;
+
+use super::{
+    comm::Comm,
+    transport::{DefaultTransport, Transport},
+};
+
+/// A byte stream to and from the gateware, over [`Comm`].
+///
+/// Reads return as soon as anything arrives, writes as soon as the gateware takes anything, as
+/// [`io::Read`] and [`io::Write`] expect. Waiting longer than the configured comm timeout fails
+/// with [`io::ErrorKind::TimedOut`], the [`ArrangeError`](arrange_misc::error::ArrangeError)
+/// behind any error is reachable through [`io::Error::get_ref`].
+///
+/// The async implementations poll the gateware once per call, a short exchange with the chip that
+/// holds up the executor while it lasts. If the gateware is not ready they have their task woken
+/// after the comm poll interval to try again. They have no timeout of their own.
+pub struct Channel<'a, T: Transport = DefaultTransport> {
+    comm: Comm<'a, T>,
+}
+
+impl<'a, T: Transport> Channel<'a, T> {
+    pub fn new(comm: Comm<'a, T>) -> Self {
+        Self { comm }
+    }
+
+    /// The comm interface underneath, e.g. to check its status.
+    pub fn comm(&mut self) -> &mut Comm<'a, T> {
+        &mut self.comm
+    }
+}
+
+impl<T: Transport> io::Read for Channel<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(self.comm.recv_into(buf)?)
+    }
+}
+
+impl<T: Transport> io::Write for Channel<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(self.comm.send_some(buf)?)
+    }
+
+    /// Nothing to do, writes hand their bytes to the chip before they return.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Recovers the [`ArrangeError`] behind an error from the stream, if there is one.
+pub(crate) fn from_io(error: io::Error) -> ArrangeError {
+    let message = error.to_string();
+    match error.into_inner().map(|inner| inner.downcast::<ArrangeError>()) {
+        Some(Ok(error)) => *error,
+        _ => ArrangeError::UnexpectedResponse {
+            operation: "comm stream",
+            message,
+        },
+    }
+}
+
+#[cfg(feature = "futures-io")]
+mod asynchronous {
+    use std::{
+        io,
+        pin::Pin,
+        sync::{
+            mpsc::{self, RecvTimeoutError, Sender},
+            OnceLock,
+        },
+        task::{Context, Poll, Waker},
+        thread,
+        time::Instant,
+    };
+
+    use futures_io::{AsyncRead, AsyncWrite};
+
+    use super::Channel;
+    use crate::ftdi::transport::Transport;
+
+    /// Wakes `waker` once `deadline` has passed, from a thread shared by every channel.
+    fn wake_at(deadline: Instant, waker: Waker) {
+        static TIMER: OnceLock<Sender<(Instant, Waker)>> = OnceLock::new();
+
+        let timer = TIMER.get_or_init(|| {
+            let (sender, receiver) = mpsc::channel::<(Instant, Waker)>();
+            thread::spawn(move || {
+                let mut pending = Vec::new();
+                loop {
+                    let now = Instant::now();
+                    pending.retain(|(deadline, waker): &(Instant, Waker)| {
+                        let due = *deadline <= now;
+                        if due {
+                            waker.wake_by_ref();
+                        }
+                        !due
+                    });
+
+                    let next = pending.iter().map(|(deadline, _)| *deadline).min();
+                    let received = match next {
+                        Some(next) => receiver.recv_timeout(next - now),
+                        None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+                    match received {
+                        Ok(entry) => pending.push(entry),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+            });
+            sender
+        });
+
+        // The timer thread outlives every sender, sending cannot fail.
+        let _ = timer.send((deadline, waker));
+    }
+
+    impl<T: Transport> Channel<'_, T> {
+        /// Ready with `count` bytes, or pending with the task woken after the poll interval to
+        /// try again.
+        fn ready(&self, count: usize, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+            if count == 0 {
+                let deadline = Instant::now() + self.comm.config().poll_interval;
+                wake_at(deadline, cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            Poll::Ready(Ok(count))
+        }
+    }
+
+    impl<T: Transport> AsyncRead for Channel<'_, T> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+
+            let channel = self.get_mut();
+            match channel.comm.try_recv_into(buf) {
+                Ok(count) => channel.ready(count, cx),
+                Err(error) => Poll::Ready(Err(error.into())),
+            }
+        }
+    }
+
+    impl<T: Transport> AsyncWrite for Channel<'_, T> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+
+            let channel = self.get_mut();
+            match channel.comm.try_send(buf) {
+                Ok(count) => channel.ready(count, cx),
+                Err(error) => Poll::Ready(Err(error.into())),
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        /// The interface stays open, it belongs to the [`ArrangeFTDI`](crate::ArrangeFTDI).
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
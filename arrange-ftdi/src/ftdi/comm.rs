@@ -0,0 +1,323 @@
+//! Streaming bytes to and from the gateware over the comm interface, in one of two
+//! [`CommMode`]s.
+//!
+//! In UART mode the interface is a plain serial port and the bytes go over it as they are, the
+//! gateware needs nothing but a UART with the same settings.
+//!
+//! In SPI mode the host is the SPI master, in mode 0 with the most significant bit first. Every
+//! exchange is a transaction of its own, with CS held low for its whole length, and starts with a
+//! command byte:
+//!
+//! | Command  | MOSI              | MISO                                     |
+//! |----------|-------------------|------------------------------------------|
+//! | `STATUS` | `00 xx xx`        | `xx R W`                                 |
+//! | `WRITE`  | `01 N d0 .. dN-1` | ignored                                  |
+//! | `READ`   | `02 N xx ..`      | `xx xx d0 .. dN-1`                       |
+//!
+//! `R` is how many bytes the gateware has waiting for the host and `W` how many it can take,
+//! both saturating at 255. The host never writes more than `W` bytes nor reads more than `R`
+//! bytes, so the gateware needs no flow control of its own beyond reporting them. Unknown
+//! commands are ignored until CS goes high again.
+
+use std::{
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use arrange_misc::error::ArrangeError;
+use log::debug;
+
+use super::{
+    command_buffer::CommandBuffer,
+    mpsse::MPSSE,
+    pins::Pin,
+    spi::{BitOrder, SpiConfig, SpiMaster, SpiMode},
+    transport::{BitMode, DefaultTransport, Transport},
+    uart::UartConfig,
+};
+
+/// The command bytes of the comm protocol, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CommCommand {
+    Status = 0x00,
+    Write = 0x01,
+    Read = 0x02,
+}
+
+/// The physical link to the gateware, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CommMode {
+    /// The comm protocol, over SPI.
+    #[default]
+    Spi,
+    /// A plain byte stream over the UART, which the iCEstick and HX8K breakout wire to the FPGA
+    /// on interface B.
+    Uart(UartConfig),
+}
+
+/// How the comm interface talks to the gateware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommConfig {
+    pub mode: CommMode,
+    /// SPI clock in SPI mode, in Hz.
+    pub frequency: u32,
+    /// The gateware's chip select (active low) in SPI mode. SCK, MOSI and MISO are on the
+    /// MPSSE's fixed pins.
+    pub cs: Pin,
+    /// How long [`Comm::send`] and [`Comm::recv`] wait for the gateware to make room or data.
+    pub timeout: Duration,
+    /// How long to pause between status polls while waiting.
+    pub poll_interval: Duration,
+}
+
+impl Default for CommConfig {
+    fn default() -> Self {
+        Self {
+            mode: CommMode::default(),
+            frequency: MPSSE::MAX_FREQUENCY,
+            cs: Pin::low(3),
+            timeout: Duration::from_secs(1),
+            poll_interval: Duration::from_millis(1),
+        }
+    }
+}
+
+impl CommConfig {
+    /// The SPI bus of SPI mode, with the gateware as its only device.
+    pub fn spi(&self) -> SpiConfig {
+        SpiConfig {
+            mode: SpiMode::Mode0,
+            bit_order: BitOrder::MsbFirst,
+            frequency: self.frequency,
+            cs: vec![self.cs],
+        }
+    }
+
+    /// Checks that in SPI mode, the clock and CS make a valid [`SpiConfig`].
+    pub fn validate(&self) -> Result<(), ArrangeError> {
+        match self.mode {
+            CommMode::Spi => self.spi().validate(),
+            CommMode::Uart(_) => Ok(()),
+        }
+    }
+}
+
+/// The gateware at the other end of the comm interface.
+pub struct Comm<'a, T: Transport = DefaultTransport> {
+    mpsse: &'a mut MPSSE<T>,
+    config: CommConfig,
+}
+
+impl<'a, T: Transport> Comm<'a, T> {
+    /// The most bytes a single `WRITE` or `READ` carries.
+    pub const MAX_TRANSFER_LEN: usize = 0xFF;
+
+    pub fn new(mpsse: &'a mut MPSSE<T>, config: CommConfig) -> Self {
+        Self { mpsse, config }
+    }
+
+    pub fn config(&self) -> &CommConfig {
+        &self.config
+    }
+
+    /// The SPI bus to the gateware, clocked by [`MPSSE::init`] with the configured frequency.
+    fn spi(&mut self) -> SpiMaster<'_, T> {
+        SpiMaster::attach(self.mpsse, self.config.spi())
+    }
+
+    /// Gets the freshly opened interface ready for the configured mode: leaves the SPI bus idle,
+    /// or switches the interface to UART and applies the serial settings.
+    pub fn configure(&mut self) -> Result<(), ArrangeError> {
+        let CommMode::Uart(uart) = self.config.mode else {
+            return self.idle();
+        };
+
+        let transport = self.mpsse.transport_mut();
+        transport.set_bitmode(0, BitMode::Reset)?;
+        let actual = transport.set_baud_rate(uart.baud)?;
+        uart.check_baud(actual)?;
+        transport.set_line_properties(uart.data_bits, uart.stop_bits, uart.parity)?;
+        transport.set_flow_control(uart.flow_control)?;
+        transport.purge()?;
+
+        debug!(
+            "comm UART at {actual} baud, {} data bits, {} parity, {} stop bits",
+            uart.data_bits, uart.parity, uart.stop_bits
+        );
+        Ok(())
+    }
+
+    /// Deselects the gateware and leaves the bus idle.
+    pub fn idle(&mut self) -> Result<(), ArrangeError> {
+        self.spi().idle()
+    }
+
+    /// A transaction with the gateware, see [`SpiMaster::transaction`].
+    fn transaction(
+        &mut self,
+        queue: impl FnOnce(&mut CommandBuffer) -> &mut CommandBuffer,
+    ) -> Result<Vec<u8>, ArrangeError> {
+        self.spi().transaction(0, queue)
+    }
+
+    /// How many bytes the gateware has for us, and how many it can take.
+    pub fn status(&mut self) -> Result<(usize, usize), ArrangeError> {
+        let cmd = [CommCommand::Status as u8, 0, 0];
+        let response = self.transaction(|commands| commands.spi_transfer(&cmd))?;
+        Ok((response[1] as usize, response[2] as usize))
+    }
+
+    fn send_timeout(&self, expected: usize, actual: usize) -> ArrangeError {
+        ArrangeError::Timeout {
+            operation: "comm send",
+            expected,
+            actual,
+            timeout: self.config.timeout,
+        }
+    }
+
+    fn recv_timeout(&self, expected: usize) -> ArrangeError {
+        ArrangeError::Timeout {
+            operation: "comm recv",
+            expected,
+            actual: 0,
+            timeout: self.config.timeout,
+        }
+    }
+
+    /// Sends as much of `bytes` as the gateware takes right now, without waiting. Returns how
+    /// many bytes were sent, possibly none.
+    pub fn try_send(&mut self, bytes: &[u8]) -> Result<usize, ArrangeError> {
+        if bytes.is_empty() {
+            return Ok(0);
+        }
+
+        match self.config.mode {
+            CommMode::Spi => self.try_send_spi(bytes),
+            CommMode::Uart(_) => self.mpsse.transport_mut().write(bytes),
+        }
+    }
+
+    /// Receives what the gateware has sent so far into `buffer`, without waiting. Returns how
+    /// many bytes were received, possibly none.
+    pub fn try_recv_into(&mut self, buffer: &mut [u8]) -> Result<usize, ArrangeError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        match self.config.mode {
+            CommMode::Spi => self.try_recv_spi(buffer),
+            CommMode::Uart(_) => self.mpsse.transport_mut().read(buffer),
+        }
+    }
+
+    /// Sends the start of `bytes`, waiting until the gateware takes at least one byte. Returns
+    /// how many bytes were sent.
+    ///
+    /// Fails with [`ArrangeError::Timeout`] if the gateware took nothing within the configured
+    /// timeout.
+    pub fn send_some(&mut self, bytes: &[u8]) -> Result<usize, ArrangeError> {
+        let deadline = Instant::now() + self.config.timeout;
+        loop {
+            let count = self.try_send(bytes)?;
+            if count > 0 || bytes.is_empty() {
+                return Ok(count);
+            }
+            if Instant::now() >= deadline {
+                return Err(self.send_timeout(bytes.len(), 0));
+            }
+
+            // Full, or held off by flow control.
+            sleep(self.config.poll_interval);
+        }
+    }
+
+    /// Sends all of `bytes`, as fast as the gateware takes them.
+    ///
+    /// Fails with [`ArrangeError::Timeout`] if the gateware stops taking bytes for longer than
+    /// the configured timeout, the error tells how many were sent.
+    pub fn send(&mut self, bytes: &[u8]) -> Result<(), ArrangeError> {
+        let mut sent = 0;
+        while sent < bytes.len() {
+            sent += self.send_some(&bytes[sent..]).map_err(|error| match error {
+                ArrangeError::Timeout { .. } => self.send_timeout(bytes.len(), sent),
+                error => error,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Receives into `buffer`, waiting until the gateware has sent at least one byte. Returns
+    /// how many bytes were received, which may be fewer than fit.
+    ///
+    /// Fails with [`ArrangeError::Timeout`] if nothing arrived within the configured timeout.
+    pub fn recv_into(&mut self, buffer: &mut [u8]) -> Result<usize, ArrangeError> {
+        let deadline = Instant::now() + self.config.timeout;
+        loop {
+            let count = self.try_recv_into(buffer)?;
+            if count > 0 || buffer.is_empty() {
+                return Ok(count);
+            }
+            if Instant::now() >= deadline {
+                return Err(self.recv_timeout(buffer.len()));
+            }
+
+            sleep(self.config.poll_interval);
+        }
+    }
+
+    /// Receives up to `length` bytes.
+    ///
+    /// Returns as soon as the gateware has sent anything, with whatever it sent up to `length`
+    /// bytes, so fewer than `length` bytes may come back. Fails with [`ArrangeError::Timeout`] if
+    /// nothing arrived within the configured timeout.
+    pub fn recv(&mut self, length: usize) -> Result<Vec<u8>, ArrangeError> {
+        let mut received = vec![0; length];
+        let count = self.recv_into(&mut received)?;
+        received.truncate(count);
+        Ok(received)
+    }
+
+    fn try_send_spi(&mut self, bytes: &[u8]) -> Result<usize, ArrangeError> {
+        let (_, room) = self.status()?;
+        let count = room.min(bytes.len()).min(Self::MAX_TRANSFER_LEN);
+        if count == 0 {
+            return Ok(0);
+        }
+
+        debug!("comm send {count} bytes");
+        let chunk = &bytes[..count];
+        self.transaction(|commands| {
+            commands
+                .spi_write(&[CommCommand::Write as u8, count as u8])
+                .spi_write(chunk)
+        })?;
+        Ok(count)
+    }
+
+    fn try_recv_spi(&mut self, buffer: &mut [u8]) -> Result<usize, ArrangeError> {
+        let mut received = 0;
+        let mut available = self.status()?.0;
+        while available > 0 {
+            let count = available.min(buffer.len() - received).min(Self::MAX_TRANSFER_LEN);
+            debug!("comm recv {count} bytes");
+            let response = self.transaction(|commands| {
+                commands
+                    .spi_write(&[CommCommand::Read as u8, count as u8])
+                    .spi_read(count)
+            })?;
+            buffer[received..received + count].copy_from_slice(&response);
+            received += count;
+
+            // The status saturates, there may be more than it said.
+            if received == buffer.len() || available < Self::MAX_TRANSFER_LEN {
+                break;
+            }
+            available = self.status()?.0;
+        }
+
+        Ok(received)
+    }
+}
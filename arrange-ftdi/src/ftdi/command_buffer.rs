@@ -0,0 +1,198 @@
+use super::{
+    mpsse::{MPSSECommand, MPSSE},
+    spi::SpiFormat,
+};
+
+/// Accumulates MPSSE commands so they can be sent to the device in a single USB transfer.
+///
+/// Every queued command that clocks data in adds to [`CommandBuffer::read_len`];
+/// [`MPSSE::execute`] sends the whole buffer followed by `FLUSH` and then reads all of the
+/// responses back at once.
+///
+/// SPI data of any length can be queued, it is split into as many MPSSE commands as needed. It
+/// is clocked in mode 0 with the most significant bit first unless [`CommandBuffer::spi_format`]
+/// says otherwise.
+#[derive(Clone, Debug, Default)]
+pub struct CommandBuffer {
+    bytes: Vec<u8>,
+    read_len: usize,
+    segments: Vec<Segment>,
+    format: SpiFormat,
+}
+
+/// The end of a queued command, and the number of bytes the buffer reads up to that point.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Segment {
+    pub(crate) end: usize,
+    pub(crate) read_len: usize,
+}
+
+impl CommandBuffer {
+    /// The most data a single MPSSE data command can clock (its length field is 16 bits).
+    pub const MAX_COMMAND_LEN: usize = 0x10000;
+    /// Full duplex transfers are split into commands of this size, small enough for the response
+    /// to fit in the chip's buffer while it still receives the data to send.
+    pub const MAX_TRANSFER_LEN: usize = 2048;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The raw command bytes queued so far.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The number of bytes the queued commands will return.
+    pub fn read_len(&self) -> usize {
+        self.read_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Where each queued command ends, used to split execution without cutting a command apart.
+    pub(crate) fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Marks the end of a command.
+    fn end_command(&mut self) -> &mut Self {
+        self.segments.push(Segment {
+            end: self.bytes.len(),
+            read_len: self.read_len,
+        });
+        self
+    }
+
+    /// Sets the value and direction (1 = output) of the low GPIO byte.
+    pub fn set_gpio(&mut self, gpio: u8, direction: u8) -> &mut Self {
+        self.bytes
+            .extend_from_slice(&[MPSSECommand::SETBLOW as u8, gpio, direction]);
+        self.end_command()
+    }
+
+    /// Sets the value and direction (1 = output) of the high GPIO byte.
+    pub fn set_gpio_high(&mut self, gpio: u8, direction: u8) -> &mut Self {
+        self.bytes
+            .extend_from_slice(&[MPSSECommand::SETBHIGH as u8, gpio, direction]);
+        self.end_command()
+    }
+
+    /// Reads the low GPIO byte, returns 1 byte.
+    pub fn read_low_byte(&mut self) -> &mut Self {
+        self.bytes.push(MPSSECommand::READBLOW as u8);
+        self.read_len += 1;
+        self.end_command()
+    }
+
+    /// Reads the high GPIO byte, returns 1 byte.
+    pub fn read_high_byte(&mut self) -> &mut Self {
+        self.bytes.push(MPSSECommand::READBHIGH as u8);
+        self.read_len += 1;
+        self.end_command()
+    }
+
+    /// Clocks the SPI data queued from now on in `format`.
+    pub fn spi_format(&mut self, format: SpiFormat) -> &mut Self {
+        self.format = format;
+        self
+    }
+
+    fn spi_header(&mut self, opcode: u8, len: usize) {
+        let opcode = opcode | self.format.data_flags();
+        self.bytes
+            .extend_from_slice(&[opcode, (len - 1) as u8, ((len - 1) >> 8) as u8]);
+    }
+
+    /// Clocks `data` out on MOSI, ignoring MISO.
+    pub fn spi_write(&mut self, data: &[u8]) -> &mut Self {
+        for chunk in data.chunks(CommandBuffer::MAX_COMMAND_LEN) {
+            self.spi_header(MPSSE::DATA_OUT, chunk.len());
+            self.bytes.extend_from_slice(chunk);
+            self.end_command();
+        }
+        self
+    }
+
+    /// Clocks `len` bytes in on MISO without driving MOSI, returns `len` bytes.
+    pub fn spi_read(&mut self, len: usize) -> &mut Self {
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk_len = remaining.min(CommandBuffer::MAX_COMMAND_LEN);
+            self.spi_header(MPSSE::DATA_IN, chunk_len);
+            self.read_len += chunk_len;
+            self.end_command();
+            remaining -= chunk_len;
+        }
+        self
+    }
+
+    /// Clocks `data` out on MOSI while clocking in MISO, returns `data.len()` bytes.
+    pub fn spi_transfer(&mut self, data: &[u8]) -> &mut Self {
+        for chunk in data.chunks(CommandBuffer::MAX_TRANSFER_LEN) {
+            self.spi_header(MPSSE::DATA_IN | MPSSE::DATA_OUT, chunk.len());
+            self.bytes.extend_from_slice(chunk);
+            self.read_len += chunk.len();
+            self.end_command();
+        }
+        self
+    }
+
+    /// Clocks the first `n` bits of `data` out while clocking in MISO, returns 1 byte. The first
+    /// bits are the top ones, or the bottom ones when the least significant bit goes first.
+    pub fn spi_transfer_bits(&mut self, data: u8, n: u8) -> &mut Self {
+        self.bytes.extend_from_slice(&[
+            MPSSE::DATA_IN | MPSSE::DATA_OUT | MPSSE::DATA_BITS | self.format.data_flags(),
+            n - 1,
+            data,
+        ]);
+        self.read_len += 1;
+        self.end_command()
+    }
+
+    /// Stalls the MPSSE until GPIOL1 is high (or low), the commands after this one only run
+    /// once it is.
+    pub fn wait_gpiol1(&mut self, high: bool) -> &mut Self {
+        let command = if high { MPSSECommand::WAITH } else { MPSSECommand::WAITL };
+        self.bytes.push(command as u8);
+        self.end_command()
+    }
+
+    /// Runs a read cycle on the host bus at an 8 bit address, returns 1 byte. A15 to A8 keep
+    /// the value of the last extended address.
+    pub fn cpu_read_short(&mut self, address: u8) -> &mut Self {
+        self.bytes.extend_from_slice(&[MPSSECommand::CPURS as u8, address]);
+        self.read_len += 1;
+        self.end_command()
+    }
+
+    /// Runs a read cycle on the host bus at a 16 bit address, returns 1 byte.
+    pub fn cpu_read_extended(&mut self, address: u16) -> &mut Self {
+        let [high, low] = address.to_be_bytes();
+        self.bytes.extend_from_slice(&[MPSSECommand::CPURE as u8, high, low]);
+        self.read_len += 1;
+        self.end_command()
+    }
+
+    /// Runs a write cycle on the host bus at an 8 bit address. A15 to A8 keep the value of the
+    /// last extended address.
+    pub fn cpu_write_short(&mut self, address: u8, data: u8) -> &mut Self {
+        self.bytes.extend_from_slice(&[MPSSECommand::CPUWS as u8, address, data]);
+        self.end_command()
+    }
+
+    /// Runs a write cycle on the host bus at a 16 bit address.
+    pub fn cpu_write_extended(&mut self, address: u16, data: u8) -> &mut Self {
+        let [high, low] = address.to_be_bytes();
+        self.bytes.extend_from_slice(&[MPSSECommand::CPUWE as u8, high, low, data]);
+        self.end_command()
+    }
+
+    /// Appends a raw MPSSE command.
+    pub fn command(&mut self, command: MPSSECommand) -> &mut Self {
+        self.bytes.push(command as u8);
+        self.end_command()
+    }
+}
@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+use super::{
+    board::BoardProfile, comm::CommConfig, device_string::DeviceString, mpsse::MPSSE,
+    retry::RetryPolicy,
+};
+
+/// Options used by [`crate::ArrangeFTDI`] when opening the device.
+#[derive(Clone, Debug)]
+pub struct ArrangeFTDIConfig {
+    /// How the board is wired to the FTDI chip.
+    pub board: BoardProfile,
+    /// Which device to open, the first device matching the board's IDs is used when `None`.
+    pub device_string: Option<DeviceString>,
+    /// SPI clock used to talk to the flash, in Hz.
+    pub frequency: u32,
+    /// How long to wait for the device to answer before giving up.
+    pub timeout: Duration,
+    /// How failed flash operations are retried.
+    pub retry: RetryPolicy,
+    /// How [`Arrange::send`](arrange_misc::traits::Arrange::send) and
+    /// [`Arrange::recv`](arrange_misc::traits::Arrange::recv) talk to the gateware.
+    pub comm: CommConfig,
+}
+
+impl Default for ArrangeFTDIConfig {
+    fn default() -> Self {
+        Self {
+            board: BoardProfile::default(),
+            device_string: None,
+            frequency: MPSSE::MAX_FREQUENCY,
+            timeout: MPSSE::DEFAULT_TIMEOUT,
+            retry: RetryPolicy::default(),
+            comm: CommConfig::default(),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use core::fmt;
+use std::{error::Error, str::FromStr};
+
+/// Selects a single FTDI device when several are connected.
+///
+/// Uses the same formats as libftdi's `ftdi_usb_open_string` (and therefore iceprog's `-d`):
+///
+/// * `d:<bus>/<device>` - the USB bus number and device address, e.g. `d:002/005`.
+/// * `i:<vendor>:<product>[:<index>]` - the n-th device with the given IDs, e.g. `i:0x0403:0x6010:1`.
+/// * `s:<vendor>:<product>:<serial>` - the device with the given serial number.
+///
+/// Vendor, product and index accept `0x` prefixed hexadecimal, `0` prefixed octal or decimal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceString {
+    BusDevice { bus: u8, device: u8 },
+    VendorProduct { vendor: u16, product: u16, index: u32 },
+    Serial { vendor: u16, product: u16, serial: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseDeviceStringError {
+    input: String,
+    reason: &'static str,
+}
+
+impl fmt::Display for ParseDeviceStringError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid device string '{}': {}", self.input, self.reason)
+    }
+}
+
+impl Error for ParseDeviceStringError {}
+
+/// Parses a number the way `strtoul(.., 0)` does.
+fn parse_c_number<T: TryFrom<u32>>(value: &str) -> Option<T> {
+    let parsed = if let Some(hex) = value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16)
+    } else if value.len() > 1 && value.starts_with('0') {
+        u32::from_str_radix(&value[1..], 8)
+    } else {
+        value.parse::<u32>()
+    };
+
+    parsed.ok().and_then(|value| T::try_from(value).ok())
+}
+
+impl FromStr for DeviceString {
+    type Err = ParseDeviceStringError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |reason| ParseDeviceStringError {
+            input: s.to_string(),
+            reason,
+        };
+
+        let (kind, rest) = s
+            .split_once(':')
+            .ok_or_else(|| error("expected d:, i: or s: prefix"))?;
+
+        match kind {
+            "d" => {
+                let (bus, device) = rest
+                    .split_once('/')
+                    .ok_or_else(|| error("expected d:<bus>/<device>"))?;
+
+                Ok(DeviceString::BusDevice {
+                    bus: bus.parse().map_err(|_| error("invalid bus number"))?,
+                    device: device.parse().map_err(|_| error("invalid device address"))?,
+                })
+            }
+            "i" => {
+                let mut fields = rest.split(':');
+                let vendor = fields.next().and_then(parse_c_number);
+                let product = fields.next().and_then(parse_c_number);
+                let index = match fields.next() {
+                    Some(index) => parse_c_number(index),
+                    None => Some(0),
+                };
+
+                match (vendor, product, index, fields.next()) {
+                    (Some(vendor), Some(product), Some(index), None) => {
+                        Ok(DeviceString::VendorProduct {
+                            vendor,
+                            product,
+                            index,
+                        })
+                    }
+                    _ => Err(error("expected i:<vendor>:<product>[:<index>]")),
+                }
+            }
+            "s" => {
+                // The serial is everything after the product, and may itself contain ':'.
+                let mut fields = rest.splitn(3, ':');
+                let vendor = fields.next().and_then(parse_c_number);
+                let product = fields.next().and_then(parse_c_number);
+
+                match (vendor, product, fields.next()) {
+                    (Some(vendor), Some(product), Some(serial)) => Ok(DeviceString::Serial {
+                        vendor,
+                        product,
+                        serial: serial.to_string(),
+                    }),
+                    _ => Err(error("expected s:<vendor>:<product>:<serial>")),
+                }
+            }
+            _ => Err(error("expected d:, i: or s: prefix")),
+        }
+    }
+}
+
+impl fmt::Display for DeviceString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceString::BusDevice { bus, device } => write!(f, "d:{bus:03}/{device:03}"),
+            DeviceString::VendorProduct {
+                vendor,
+                product,
+                index,
+            } => write!(f, "i:{vendor:#06x}:{product:#06x}:{index}"),
+            DeviceString::Serial {
+                vendor,
+                product,
+                serial,
+            } => write!(f, "s:{vendor:#06x}:{product:#06x}:{serial}"),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use core::fmt;
+
+use arrange_misc::error::ArrangeError;
+
+use super::{board::BoardProfile, device_string::DeviceString};
+
+/// Describes a connected FTDI device that Arrange can talk to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    pub bus: u8,
+    pub address: u8,
+    pub interfaces: u8,
+}
+
+impl DeviceInfo {
+    /// A device string that opens exactly this device.
+    pub fn device_string(&self) -> DeviceString {
+        DeviceString::BusDevice {
+            bus: self.bus,
+            device: self.address,
+        }
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} {} interface{} {} {} serial: {}",
+            self.device_string(),
+            self.vendor_id,
+            self.product_id,
+            self.interfaces,
+            if self.interfaces == 1 { "" } else { "s" },
+            self.manufacturer.as_deref().unwrap_or("?"),
+            self.product.as_deref().unwrap_or("?"),
+            self.serial.as_deref().unwrap_or("?"),
+        )
+    }
+}
+
+/// Lists every connected device matching one of the given vendor and product IDs.
+pub fn list_devices_with_ids(ids: &[(u16, u16)]) -> Result<Vec<DeviceInfo>, ArrangeError> {
+    #[cfg(feature = "libftdi")]
+    return super::libftdi::list_devices_with_ids(ids);
+    #[cfg(all(not(feature = "libftdi"), feature = "nusb"))]
+    return super::nusb::list_devices_with_ids(ids);
+    // The build already failed in `transport`, this only keeps it to that one error.
+    #[cfg(not(any(feature = "libftdi", feature = "nusb")))]
+    unreachable!("no USB backend to list {ids:?} with")
+}
+
+/// Lists every connected device matching the IDs of the given board.
+pub fn list_devices_for(board: &BoardProfile) -> Result<Vec<DeviceInfo>, ArrangeError> {
+    list_devices_with_ids(&board.ids)
+}
+
+/// Lists every connected device with one of the IDs Arrange looks for by default.
+pub fn list_devices() -> Result<Vec<DeviceInfo>, ArrangeError> {
+    list_devices_for(&BoardProfile::default())
+}
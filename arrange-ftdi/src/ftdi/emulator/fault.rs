@@ -0,0 +1,24 @@
+/// A failure the [`Emulator`](super::Emulator) can be told to produce, see
+/// [`Emulator::inject`](super::Emulator::inject).
+///
+/// Every fault happens once, except [`Fault::StuckBusy`] which lasts until the flash is reset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The next write of more than `accepted` bytes only gets its first `accepted` bytes to the
+    /// chip, the rest are dropped.
+    ShortWrite { accepted: usize },
+    /// The byte `offset` bytes into what is read next comes back XORed with `mask`.
+    CorruptRead { offset: usize, mask: u8 },
+    /// The flash returns the byte at `address` XORed with `mask` the next time it is read, after
+    /// `skip` reads that come back clean.
+    BitFlip {
+        address: usize,
+        mask: u8,
+        skip: usize,
+    },
+    /// The flash reports a program or erase in progress until it is reset.
+    StuckBusy,
+    /// The device disappears from the bus as soon as the flash has received `opcode`, until
+    /// [`Emulator::reconnect`](super::Emulator::reconnect).
+    Disconnect { opcode: u8 },
+}
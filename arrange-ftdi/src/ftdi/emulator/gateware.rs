@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+
+use crate::ftdi::comm::CommCommand;
+
+const STATUS: u8 = CommCommand::Status as u8;
+const WRITE: u8 = CommCommand::Write as u8;
+const READ: u8 = CommCommand::Read as u8;
+
+/// Where the gateware is in the transaction it has been selected for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Deselected,
+    Command,
+    /// Answering `STATUS`, with how many status bytes have been sent.
+    Status(usize),
+    WriteLength,
+    /// Taking `WRITE` data, with how many bytes are left.
+    Write(usize),
+    ReadLength,
+    /// Sending `READ` data, with how many bytes are left.
+    Read(usize),
+    /// Waiting for CS to go high after an unknown command.
+    Ignore,
+}
+
+/// Emulated gateware at the other end of the comm interface, with a FIFO each way. It speaks the
+/// [comm protocol](crate::ftdi::comm) over SPI, or takes and sends plain bytes over the UART. In
+/// MCU host bus emulation it is 64 KiB of memory on the bus.
+#[derive(Clone, Debug)]
+pub struct Gateware {
+    /// Bytes the host sent, until the test takes them.
+    from_host: VecDeque<u8>,
+    /// Bytes queued by the test for the host to read.
+    to_host: VecDeque<u8>,
+    /// How many bytes `from_host` holds before the gateware reports it full.
+    capacity: usize,
+    state: State,
+    /// What the host bus reads and writes, one byte per address.
+    bus: Vec<u8>,
+    /// The level the gateware drives on GPIOL1.
+    gpiol1: bool,
+}
+
+impl Gateware {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            from_host: VecDeque::new(),
+            to_host: VecDeque::new(),
+            capacity,
+            state: State::Deselected,
+            bus: vec![0; 1 << 16],
+            gpiol1: false,
+        }
+    }
+
+    /// Takes everything the host has sent so far.
+    pub fn take_from_host(&mut self) -> Vec<u8> {
+        self.from_host.drain(..).collect()
+    }
+
+    /// Queues `data` for the host to read.
+    pub fn queue_for_host(&mut self, data: &[u8]) {
+        self.to_host.extend(data);
+    }
+
+    /// How many queued bytes the host has not read yet.
+    pub fn pending_for_host(&self) -> usize {
+        self.to_host.len()
+    }
+
+    /// The memory behind the host bus.
+    pub fn bus(&self) -> &[u8] {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut [u8] {
+        &mut self.bus
+    }
+
+    /// Drives GPIOL1 of the comm interface, which starts out low.
+    pub fn set_gpiol1(&mut self, high: bool) {
+        self.gpiol1 = high;
+    }
+
+    pub fn gpiol1(&self) -> bool {
+        self.gpiol1
+    }
+
+    /// Takes bytes that came in over the UART, returning how many fit.
+    pub(crate) fn receive(&mut self, data: &[u8]) -> usize {
+        let accepted = data.len().min(self.capacity.saturating_sub(self.from_host.len()));
+        self.from_host.extend(&data[..accepted]);
+        accepted
+    }
+
+    /// Sends everything queued for the host over the UART.
+    pub(crate) fn take_for_host(&mut self) -> Vec<u8> {
+        self.to_host.drain(..).collect()
+    }
+
+    pub(crate) fn select(&mut self) {
+        self.state = State::Command;
+    }
+
+    pub(crate) fn deselect(&mut self) {
+        self.state = State::Deselected;
+    }
+
+    /// Clocks a byte in, returning the byte clocked out.
+    pub(crate) fn transfer(&mut self, mosi: u8) -> u8 {
+        let (miso, next) = match self.state {
+            // Nothing drives MISO, it is pulled up.
+            State::Deselected => (0xFF, State::Deselected),
+            State::Command => match mosi {
+                STATUS => (0x00, State::Status(0)),
+                WRITE => (0x00, State::WriteLength),
+                READ => (0x00, State::ReadLength),
+                _ => (0x00, State::Ignore),
+            },
+            State::Status(0) => (self.to_host.len().min(0xFF) as u8, State::Status(1)),
+            State::Status(1) => {
+                let room = self.capacity.saturating_sub(self.from_host.len());
+                (room.min(0xFF) as u8, State::Ignore)
+            }
+            State::WriteLength => (0x00, State::Write(mosi as usize)),
+            State::Write(0) | State::Read(0) | State::Status(_) | State::Ignore => {
+                (0x00, State::Ignore)
+            }
+            State::Write(left) => {
+                // A host that ignores the status overflows the FIFO, the byte is lost.
+                if self.from_host.len() < self.capacity {
+                    self.from_host.push_back(mosi);
+                }
+                (0x00, State::Write(left - 1))
+            }
+            State::ReadLength => (0x00, State::Read(mosi as usize)),
+            State::Read(left) => (self.to_host.pop_front().unwrap_or(0x00), State::Read(left - 1)),
+        };
+
+        self.state = next;
+        miso
+    }
+}
@@ -0,0 +1,877 @@
+//! An emulated FT2232H wired to an iCE40 and its configuration flash, for testing without a board.
+//!
+//! The emulator implements [`Transport`], so it plugs in under [`MPSSE`](super::mpsse::MPSSE),
+//! [`Flash`](super::flash::Flash) and [`ArrangeFTDI`](crate::ArrangeFTDI) in place of real
+//! hardware:
+//!
+//! ```
+//! use arrange_ftdi::{
+//!     ftdi::{config::ArrangeFTDIConfig, emulator::Emulator},
+//!     ArrangeFTDI,
+//! };
+//! use arrange_misc::traits::Arrange;
+//!
+//! let emulator = Emulator::new();
+//! let mut arrange = ArrangeFTDI::with_transports(
+//!     ArrangeFTDIConfig::default(),
+//!     emulator.clone(),
+//!     emulator.clone(),
+//! );
+//! arrange.init().unwrap();
+//! arrange.burn(&[0x7E, 0xAA, 0x99, 0x7E]).unwrap();
+//! assert_eq!(&emulator.flash_memory()[..4], &[0x7E, 0xAA, 0x99, 0x7E]);
+//! ```
+//!
+//! The MPSSE engine understands the GPIO, clock, data shifting and wait commands, and in MCU
+//! host bus emulation the bus cycles. Commands it does not know are answered with `0xFA` followed
+//! by the command, like the real chip.
+//!
+//! The other interface is wired to [`Gateware`] speaking the [comm protocol](super::comm) in
+//! MPSSE mode and a plain byte stream in UART mode, so
+//! [`Arrange::send`](arrange_misc::traits::Arrange::send) and
+//! [`Arrange::recv`](arrange_misc::traits::Arrange::recv) can be tested too. Its host bus
+//! is [`Gateware::bus`], and it drives GPIOL1 with [`Gateware::set_gpiol1`].
+//!
+//! Failures of the USB link and of the flash can be injected with [`Emulator::inject`] to test
+//! how they are recovered from.
+//!
+//! Only built with the `emulator` feature, enable it from `[dev-dependencies]`.
+
+mod fault;
+mod gateware;
+mod spi_flash;
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use arrange_misc::error::ArrangeError;
+
+pub use self::{fault::Fault, gateware::Gateware, spi_flash::SpiFlash};
+use super::{
+    board::Interface,
+    device_string::DeviceString,
+    mpsse::{MPSSECommand, MPSSE},
+    pins::{Pin, PinByte, PinMap},
+    transport::{BitMode, Transport},
+    uart::{self, DataBits, FlowControl, Parity, StopBits, UartConfig},
+};
+
+/// Describes the emulated board.
+#[derive(Clone, Debug)]
+pub struct EmulatorConfig {
+    /// The vendor and product ID the emulated chip enumerates with.
+    pub id: (u16, u16),
+    /// How many interfaces the chip has: 2 for an FT2232H, 1 for an FT232H.
+    pub interfaces: usize,
+    pub serial: String,
+    /// The bus number and address used by `d:` device strings.
+    pub bus: u8,
+    pub address: u8,
+    /// The interface the flash and the iCE40 configuration pins are wired to.
+    pub flash_interface: Interface,
+    pub pins: PinMap,
+    pub flash_size: usize,
+    /// The interface the gateware is wired to, and its chip select.
+    pub comm_interface: Interface,
+    pub comm_cs: Pin,
+    /// How many bytes from the host the gateware buffers.
+    pub comm_capacity: usize,
+    /// The serial settings of the gateware's UART. Bytes sent with other settings are lost to
+    /// framing errors.
+    pub comm_uart: UartConfig,
+}
+
+impl Default for EmulatorConfig {
+    /// An iCEstick: an FT2232H with a 4 MiB flash on interface A and the gateware on
+    /// interface B.
+    fn default() -> Self {
+        Self {
+            id: (0x0403, 0x6010),
+            interfaces: 2,
+            serial: "ARRANGE-EMULATOR".to_string(),
+            bus: 1,
+            address: 1,
+            flash_interface: Interface::A,
+            pins: PinMap::default(),
+            flash_size: 4 << 20,
+            comm_interface: Interface::B,
+            comm_cs: Pin::low(3),
+            comm_capacity: 512,
+            comm_uart: UartConfig::default(),
+        }
+    }
+}
+
+/// The state of one interface (channel) of the chip.
+#[derive(Debug)]
+struct Channel {
+    open: bool,
+    bitmode: BitMode,
+    latency: u8,
+    /// Output values and directions (1 = output) of the low and high GPIO bytes.
+    gpio: [u8; 2],
+    direction: [u8; 2],
+    divisor: u16,
+    divide_by_5: bool,
+    loopback: bool,
+    /// The serial settings used outside of MPSSE mode, `baud` is the rate achieved.
+    uart: UartConfig,
+    /// A15 to A8 of the host bus, as left by the last extended address.
+    address_high: u8,
+    /// Bytes written that do not yet form a whole command.
+    input: Vec<u8>,
+    /// Bytes waiting to be read.
+    output: VecDeque<u8>,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            open: false,
+            bitmode: BitMode::Reset,
+            latency: 16,
+            gpio: [0; 2],
+            direction: [0; 2],
+            divisor: 0,
+            // The /5 prescaler is enabled at power on.
+            divide_by_5: true,
+            loopback: false,
+            uart: UartConfig {
+                baud: 9600,
+                ..Default::default()
+            },
+            address_high: 0,
+            input: vec![],
+            output: VecDeque::new(),
+        }
+    }
+}
+
+/// Everything behind the USB connection, shared by every [`Emulator`] handle.
+#[derive(Debug)]
+struct Board {
+    config: EmulatorConfig,
+    channels: [Channel; 2],
+    flash: SpiFlash,
+    gateware: Gateware,
+    /// Whether the iCE40 has loaded a configuration.
+    cdone: bool,
+    /// The last levels seen on CS, CRESET_B and the gateware's CS, to detect edges.
+    cs_b: bool,
+    creset_b: bool,
+    comm_cs_b: bool,
+    /// Faults waiting to happen.
+    faults: Vec<Fault>,
+    /// Whether the device has dropped off the bus.
+    disconnected: bool,
+    /// Bumped whenever the device disappears, so stale handles can tell.
+    generation: u32,
+}
+
+/// An emulated FT2232H, see the [module documentation](self).
+///
+/// Clones share the same chip, so one clone can be handed to each [`MPSSE`] while the test keeps
+/// another to look at the flash and the iCE40.
+#[derive(Clone, Debug)]
+pub struct Emulator {
+    board: Arc<Mutex<Board>>,
+    /// The channel this handle has open, and the generation of the device it was opened on.
+    channel: Option<(usize, u32)>,
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Emulator {
+    /// The reply to a command the MPSSE does not understand, followed by the command.
+    pub const BAD_COMMAND: u8 = 0xFA;
+    /// The iCE40 only leaves configuration once it sees this in the bitstream.
+    const ICE40_PREAMBLE: [u8; 4] = [0x7E, 0xAA, 0x99, 0x7E];
+    /// How far into the flash the iCE40 looks for the preamble.
+    const ICE40_PREAMBLE_WINDOW: usize = 4096;
+
+    /// An emulated iCEstick with a blank flash.
+    pub fn new() -> Self {
+        Self::with_config(EmulatorConfig::default())
+    }
+
+    pub fn with_config(config: EmulatorConfig) -> Self {
+        let flash = SpiFlash::new(config.flash_size);
+        let gateware = Gateware::new(config.comm_capacity);
+        Self {
+            board: Arc::new(Mutex::new(Board {
+                config,
+                channels: Default::default(),
+                flash,
+                gateware,
+                cdone: false,
+                cs_b: true,
+                creset_b: true,
+                comm_cs_b: true,
+                faults: vec![],
+                disconnected: false,
+                generation: 0,
+            })),
+            channel: None,
+        }
+    }
+
+    fn board(&self) -> MutexGuard<'_, Board> {
+        // A test that panicked while holding the lock has already failed.
+        self.board.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Runs `f` with the emulated flash.
+    pub fn with_flash<R>(&self, f: impl FnOnce(&mut SpiFlash) -> R) -> R {
+        f(&mut self.board().flash)
+    }
+
+    /// Runs `f` with the emulated gateware, to queue bytes for the host or take the ones it
+    /// sent.
+    pub fn with_gateware<R>(&self, f: impl FnOnce(&mut Gateware) -> R) -> R {
+        f(&mut self.board().gateware)
+    }
+
+    /// A copy of the whole flash.
+    pub fn flash_memory(&self) -> Vec<u8> {
+        self.board().flash.memory().to_vec()
+    }
+
+    /// Writes `data` straight into the flash at `address`, bypassing SPI.
+    pub fn load_flash(&self, address: usize, data: &[u8]) {
+        self.board().flash.memory_mut()[address..address + data.len()].copy_from_slice(data);
+    }
+
+    /// The opcode of every flash command completed so far.
+    pub fn flash_history(&self) -> Vec<u8> {
+        self.board().flash.history().to_vec()
+    }
+
+    /// Whether the iCE40 is configured.
+    pub fn cdone(&self) -> bool {
+        self.board().cdone
+    }
+
+    /// Whether the iCE40 is being held in reset.
+    pub fn in_reset(&self) -> bool {
+        !self.board().creset_b
+    }
+
+    /// Whether `interface` is open by any handle.
+    pub fn is_open(&self, interface: Interface) -> bool {
+        self.board()
+            .channels
+            .get(interface as usize)
+            .is_some_and(|channel| channel.open)
+    }
+
+    /// The SPI clock `interface` is set to, in Hz.
+    pub fn frequency(&self, interface: Interface) -> u32 {
+        let board = self.board();
+        let channel = &board.channels[interface as usize];
+        let base = if channel.divide_by_5 {
+            MPSSE::MAX_FREQUENCY / 5
+        } else {
+            MPSSE::MAX_FREQUENCY
+        };
+
+        base / (channel.divisor as u32 + 1)
+    }
+
+    /// The serial settings of `interface`, with the baud rate it achieved.
+    pub fn uart_config(&self, interface: Interface) -> UartConfig {
+        self.board().channels[interface as usize].uart
+    }
+
+    /// The latency timer of `interface`, in milliseconds.
+    pub fn latency_timer(&self, interface: Interface) -> u8 {
+        self.board().channels[interface as usize].latency
+    }
+
+    /// Arranges for `fault` to happen, see [`Fault`].
+    pub fn inject(&self, fault: Fault) {
+        let mut board = self.board();
+        match fault {
+            Fault::StuckBusy => board.flash.set_stuck_busy(true),
+            Fault::BitFlip {
+                address,
+                mask,
+                skip,
+            } => board.flash.add_bit_flip(address, mask, skip),
+            fault => board.faults.push(fault),
+        }
+    }
+
+    /// Cancels every fault, including a stuck flash. Does not reconnect the device.
+    pub fn clear_faults(&self) {
+        let mut board = self.board();
+        board.faults.clear();
+        board.flash.set_stuck_busy(false);
+        board.flash.clear_bit_flips();
+    }
+
+    /// Plugs a device that disappeared back in. Handles opened before it disappeared stay
+    /// unusable until they are opened again.
+    pub fn reconnect(&self) {
+        let mut board = self.board();
+        if board.disconnected {
+            board.disconnected = false;
+            board.channels = Default::default();
+            board.comm_cs_b = true;
+            board.gateware.deselect();
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        !self.board().disconnected
+    }
+
+    /// The board and the channel this handle has open, or an error like libftdi's when the
+    /// handle is not open or the device is gone.
+    fn open_board(&self) -> Result<(MutexGuard<'_, Board>, usize), ArrangeError> {
+        let board = self.board();
+        match self.channel {
+            Some((index, generation)) if generation == board.generation && !board.disconnected => {
+                Ok((board, index))
+            }
+            Some(_) => Err(ArrangeError::Ftdi {
+                operation: "emulator",
+                code: -4,
+                message: "USB device disconnected".to_string(),
+            }),
+            None => Err(ArrangeError::Ftdi {
+                operation: "emulator",
+                code: -3,
+                message: "USB device unavailable".to_string(),
+            }),
+        }
+    }
+
+    fn matches(config: &EmulatorConfig, ids: &[(u16, u16)], device: Option<&DeviceString>) -> bool {
+        let (vendor_id, product_id) = config.id;
+        match device {
+            Some(DeviceString::BusDevice { bus, device }) => {
+                *bus == config.bus && *device == config.address
+            }
+            Some(DeviceString::VendorProduct {
+                vendor,
+                product,
+                index,
+            }) => (*vendor, *product, *index) == (vendor_id, product_id, 0),
+            Some(DeviceString::Serial {
+                vendor,
+                product,
+                serial,
+            }) => (*vendor, *product) == config.id && *serial == config.serial,
+            None => ids.contains(&config.id),
+        }
+    }
+}
+
+impl Transport for Emulator {
+    fn open(
+        &mut self,
+        interface: Interface,
+        ids: &[(u16, u16)],
+        device_string: Option<&DeviceString>,
+    ) -> Result<(), ArrangeError> {
+        self.close();
+        let mut board = self.board();
+        if board.disconnected || !Emulator::matches(&board.config, ids, device_string) {
+            return Err(ArrangeError::DeviceNotFound {
+                description: match device_string {
+                    Some(device_string) => format!("{device_string}: device not found"),
+                    None => "no emulated device with the requested IDs".to_string(),
+                },
+            });
+        }
+
+        let index = interface as usize;
+        let interfaces = board.config.interfaces;
+        let Some(channel) = board.channels.get_mut(index).filter(|_| index < interfaces) else {
+            return Err(ArrangeError::Ftdi {
+                operation: "emulator",
+                code: -11,
+                message: format!("the emulated chip has no interface {interface}"),
+            });
+        };
+        if channel.open {
+            return Err(ArrangeError::Ftdi {
+                operation: "emulator",
+                code: -5,
+                message: format!("interface {interface} is already claimed"),
+            });
+        }
+
+        *channel = Channel {
+            open: true,
+            ..Default::default()
+        };
+        let generation = board.generation;
+        drop(board);
+        self.channel = Some((index, generation));
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        if let Some((index, generation)) = self.channel.take() {
+            let mut board = self.board();
+            if generation == board.generation && !board.disconnected {
+                board.channels[index] = Channel::default();
+                // Released pins float up.
+                board.update_pins(index);
+            }
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), ArrangeError> {
+        let (mut board, index) = self.open_board()?;
+        let channel = &mut board.channels[index];
+        channel.bitmode = BitMode::Reset;
+        channel.input.clear();
+        channel.output.clear();
+        Ok(())
+    }
+
+    fn purge(&mut self) -> Result<(), ArrangeError> {
+        let (mut board, index) = self.open_board()?;
+        let channel = &mut board.channels[index];
+        channel.input.clear();
+        channel.output.clear();
+        Ok(())
+    }
+
+    fn latency_timer(&mut self) -> Result<u8, ArrangeError> {
+        let (board, index) = self.open_board()?;
+        Ok(board.channels[index].latency)
+    }
+
+    fn set_latency_timer(&mut self, latency: u8) -> Result<(), ArrangeError> {
+        let (mut board, index) = self.open_board()?;
+        board.channels[index].latency = latency;
+        Ok(())
+    }
+
+    fn set_bitmode(&mut self, _mask: u8, mode: BitMode) -> Result<(), ArrangeError> {
+        let (mut board, index) = self.open_board()?;
+        let channel = &mut board.channels[index];
+        channel.bitmode = mode;
+        channel.input.clear();
+        if mode != BitMode::Mpsse {
+            channel.direction = [0; 2];
+            board.update_pins(index);
+        }
+        Ok(())
+    }
+
+    fn set_baud_rate(&mut self, baud: u32) -> Result<u32, ArrangeError> {
+        let (_, actual) = uart::baud_divisor(baud)?;
+        let (mut board, index) = self.open_board()?;
+        board.channels[index].uart.baud = actual;
+        Ok(actual)
+    }
+
+    fn set_line_properties(
+        &mut self,
+        data_bits: DataBits,
+        stop_bits: StopBits,
+        parity: Parity,
+    ) -> Result<(), ArrangeError> {
+        let (mut board, index) = self.open_board()?;
+        let uart = &mut board.channels[index].uart;
+        uart.data_bits = data_bits;
+        uart.stop_bits = stop_bits;
+        uart.parity = parity;
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> Result<(), ArrangeError> {
+        let (mut board, index) = self.open_board()?;
+        board.channels[index].uart.flow_control = flow_control;
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, ArrangeError> {
+        let (mut board, index) = self.open_board()?;
+        let short = |fault: &Fault| {
+            matches!(fault, Fault::ShortWrite { accepted } if *accepted < data.len())
+        };
+        let accepted = match board.take_fault(short) {
+            Some(Fault::ShortWrite { accepted }) => accepted,
+            _ => data.len(),
+        };
+
+        if board.channels[index].bitmode != BitMode::Reset {
+            board.channels[index]
+                .input
+                .extend_from_slice(&data[..accepted]);
+            board.run_mpsse(index);
+            Ok(accepted)
+        } else {
+            Ok(board.uart_write(index, &data[..accepted]))
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ArrangeError> {
+        let (mut board, index) = self.open_board()?;
+        if board.channels[index].bitmode != BitMode::Reset {
+            // Commands held up by a wait may go on now.
+            board.run_mpsse(index);
+        }
+        board.uart_read(index);
+        let output = &mut board.channels[index].output;
+        let count = buffer.len().min(output.len());
+        for (slot, byte) in buffer.iter_mut().zip(output.drain(..count)) {
+            *slot = byte;
+        }
+
+        board.corrupt(&mut buffer[..count]);
+        Ok(count)
+    }
+}
+
+impl Board {
+    /// Removes and returns the first pending fault `matches` accepts.
+    fn take_fault(&mut self, matches: impl Fn(&Fault) -> bool) -> Option<Fault> {
+        let position = self.faults.iter().position(matches)?;
+        Some(self.faults.remove(position))
+    }
+
+    /// Applies a pending [`Fault::CorruptRead`] to `data`, which is about to be read.
+    fn corrupt(&mut self, data: &mut [u8]) {
+        self.faults.retain_mut(|fault| match fault {
+            Fault::CorruptRead { offset, mask } => match data.get_mut(*offset) {
+                Some(byte) => {
+                    *byte ^= *mask;
+                    false
+                }
+                None => {
+                    *offset -= data.len();
+                    true
+                }
+            },
+            _ => true,
+        });
+    }
+
+    /// Executes every complete command waiting on `index`.
+    fn run_mpsse(&mut self, index: usize) {
+        let input = std::mem::take(&mut self.channels[index].input);
+        let mut position = 0;
+        while position < input.len() && !self.disconnected {
+            match self.mpsse_command(index, &input[position..]) {
+                Some(length) => position += length,
+                None => break,
+            }
+        }
+
+        self.channels[index].input = input[position..].to_vec();
+    }
+
+    /// Executes the command at the start of `bytes`, returning its length, or `None` if it has
+    /// not been received completely yet.
+    fn mpsse_command(&mut self, index: usize, bytes: &[u8]) -> Option<usize> {
+        let opcode = bytes[0];
+        if self.channels[index].bitmode == BitMode::Mcu {
+            return self.cpu_command(index, bytes);
+        }
+        if opcode & 0x80 == 0 {
+            return self.mpsse_data(index, bytes);
+        }
+
+        let argument = |n: usize| bytes.get(n).copied();
+        let channel = &mut self.channels[index];
+        let command = MPSSECommand::from_opcode(opcode);
+        match command {
+            Some(MPSSECommand::SETBLOW | MPSSECommand::SETBHIGH) => {
+                let (value, direction) = (argument(1)?, argument(2)?);
+                let byte = (command == Some(MPSSECommand::SETBHIGH)) as usize;
+                channel.gpio[byte] = value;
+                channel.direction[byte] = direction;
+                self.update_pins(index);
+                Some(3)
+            }
+            Some(MPSSECommand::READBLOW) => {
+                let value = self.read_pins(index, PinByte::Low);
+                self.channels[index].output.push_back(value);
+                Some(1)
+            }
+            Some(MPSSECommand::READBHIGH) => {
+                let value = self.read_pins(index, PinByte::High);
+                self.channels[index].output.push_back(value);
+                Some(1)
+            }
+            Some(MPSSECommand::SETCLKDIV) => {
+                channel.divisor = u16::from_le_bytes([argument(1)?, argument(2)?]);
+                Some(3)
+            }
+            Some(MPSSECommand::WAITH | MPSSECommand::WAITL) => {
+                // Everything after the wait stays queued until GPIOL1 is at the level.
+                let level = self.pin_level(index, MPSSE::GPIOL1);
+                (level == (command == Some(MPSSECommand::WAITH))).then_some(1)
+            }
+            Some(MPSSECommand::TCKX5 | MPSSECommand::TCKD5) => {
+                channel.divide_by_5 = command == Some(MPSSECommand::TCKD5);
+                Some(1)
+            }
+            Some(MPSSECommand::LOOPBACKEN | MPSSECommand::LOOPBACKDIS) => {
+                channel.loopback = command == Some(MPSSECommand::LOOPBACKEN);
+                Some(1)
+            }
+            Some(
+                MPSSECommand::FLUSH
+                | MPSSECommand::EN3PHCLK
+                | MPSSECommand::DIS3PHCLK
+                | MPSSECommand::ENADPTCLK
+                | MPSSECommand::DISADPTCLK,
+            ) => Some(1),
+            _ => {
+                channel.output.extend([Emulator::BAD_COMMAND, opcode]);
+                Some(1)
+            }
+        }
+    }
+
+    /// Executes a command in MCU host bus emulation, where only the bus cycles and `FLUSH` work.
+    /// The bus of the comm interface is the gateware's, nothing answers on the other one.
+    fn cpu_command(&mut self, index: usize, bytes: &[u8]) -> Option<usize> {
+        let opcode = bytes[0];
+        let argument = |n: usize| bytes.get(n).copied();
+        let command = MPSSECommand::from_opcode(opcode);
+        let (address, length) = match command {
+            Some(MPSSECommand::CPURS | MPSSECommand::CPUWS) => {
+                let high = self.channels[index].address_high;
+                (u16::from_be_bytes([high, argument(1)?]), 2)
+            }
+            Some(MPSSECommand::CPURE | MPSSECommand::CPUWE) => {
+                (u16::from_be_bytes([argument(1)?, argument(2)?]), 3)
+            }
+            Some(MPSSECommand::FLUSH) => return Some(1),
+            _ => {
+                self.channels[index]
+                    .output
+                    .extend([Emulator::BAD_COMMAND, opcode]);
+                return Some(1);
+            }
+        };
+        let write = matches!(command, Some(MPSSECommand::CPUWS | MPSSECommand::CPUWE));
+        let data = if write { Some(argument(length)?) } else { None };
+
+        let [high, _] = address.to_be_bytes();
+        self.channels[index].address_high = high;
+        let bus = (index == self.config.comm_interface as usize).then_some(&mut self.gateware);
+        match (data, bus) {
+            (Some(data), Some(gateware)) => gateware.bus_mut()[address as usize] = data,
+            (Some(_), None) => {}
+            (None, bus) => {
+                // Nothing drives the data lines, they are pulled up.
+                let value = bus.map_or(0xFF, |gateware| gateware.bus()[address as usize]);
+                self.channels[index].output.push_back(value);
+            }
+        }
+
+        Some(length + write as usize)
+    }
+
+    /// Executes a data shifting command.
+    fn mpsse_data(&mut self, index: usize, bytes: &[u8]) -> Option<usize> {
+        let opcode = bytes[0];
+        if opcode & MPSSE::DATA_TMS != 0 {
+            self.channels[index]
+                .output
+                .extend([Emulator::BAD_COMMAND, opcode]);
+            return Some(1);
+        }
+
+        let data_in = opcode & MPSSE::DATA_IN != 0;
+        let data_out = opcode & MPSSE::DATA_OUT != 0;
+        let lsb_first = opcode & MPSSE::DATA_LSB != 0;
+
+        if opcode & MPSSE::DATA_BITS != 0 {
+            // Bit transfers only clock a partial byte, the flash sees nothing but the clocks.
+            let bits = *bytes.get(1)? as u32 + 1;
+            let length = if data_out { 3 } else { 2 };
+            if bytes.len() < length {
+                return None;
+            }
+
+            if data_in {
+                let value = if self.channels[index].loopback && data_out {
+                    bytes[2]
+                } else {
+                    (0xFFu16 >> (8 - bits.min(8))) as u8
+                };
+                self.channels[index].output.push_back(value);
+            }
+            return Some(length);
+        }
+
+        let count = u16::from_le_bytes([*bytes.get(1)?, *bytes.get(2)?]) as usize + 1;
+        let length = 3 + if data_out { count } else { 0 };
+        if bytes.len() < length {
+            return None;
+        }
+
+        for i in 0..count {
+            let mosi = if data_out { bytes[3 + i] } else { 0 };
+            let mosi = if lsb_first { mosi.reverse_bits() } else { mosi };
+            let miso = if self.channels[index].loopback {
+                mosi
+            } else {
+                self.spi_transfer(index, mosi)
+            };
+
+            if data_in {
+                let miso = if lsb_first { miso.reverse_bits() } else { miso };
+                self.channels[index].output.push_back(miso);
+            }
+        }
+
+        Some(length)
+    }
+
+    /// Whether the UART of `index` is wired to the gateware and set up like the gateware's.
+    fn uart_matches(&self, index: usize) -> bool {
+        let (host, gateware) = (self.channels[index].uart, self.config.comm_uart);
+        index == self.config.comm_interface as usize
+            && gateware.check_baud(host.baud).is_ok()
+            && (host.data_bits, host.stop_bits, host.parity)
+                == (gateware.data_bits, gateware.stop_bits, gateware.parity)
+    }
+
+    /// Sends `data` out of the UART of `index`, returning how much the chip took. With hardware
+    /// flow control the gateware holds the chip off once it is full, otherwise whatever does not
+    /// fit is lost.
+    fn uart_write(&mut self, index: usize, data: &[u8]) -> usize {
+        if !self.uart_matches(index) {
+            return data.len();
+        }
+
+        let flow_control = self.channels[index].uart.flow_control;
+        let accepted = self.gateware.receive(data);
+        match flow_control {
+            FlowControl::RtsCts | FlowControl::DtrDsr => accepted,
+            FlowControl::None | FlowControl::XonXoff => data.len(),
+        }
+    }
+
+    /// Moves whatever the gateware sent over the UART of `index` into its read buffer.
+    fn uart_read(&mut self, index: usize) {
+        let channel = &self.channels[index];
+        if channel.bitmode != BitMode::Reset || index != self.config.comm_interface as usize {
+            return;
+        }
+
+        let sent = self.gateware.take_for_host();
+        if self.uart_matches(index) {
+            self.channels[index].output.extend(sent);
+        }
+    }
+
+    /// Clocks a byte over the SPI bus of `index`, returning what came back on MISO.
+    fn spi_transfer(&mut self, index: usize, mosi: u8) -> u8 {
+        if index == self.config.flash_interface as usize {
+            self.flash.transfer(mosi)
+        } else if index == self.config.comm_interface as usize {
+            self.gateware.transfer(mosi)
+        } else {
+            // Nothing is listening, MISO is pulled up.
+            0xFF
+        }
+    }
+
+    /// The level on `pin` of `index`. Pins that are not driven are pulled up, except GPIOL1 of
+    /// the comm interface, which the gateware drives.
+    fn pin_level(&self, index: usize, pin: Pin) -> bool {
+        let byte = match pin.byte {
+            PinByte::Low => 0,
+            PinByte::High => 1,
+        };
+        let channel = &self.channels[index];
+        if channel.direction[byte] & pin.mask() != 0 {
+            channel.gpio[byte] & pin.mask() != 0
+        } else if index == self.config.comm_interface as usize && pin == MPSSE::GPIOL1 {
+            self.gateware.gpiol1()
+        } else {
+            true
+        }
+    }
+
+    /// The value `READBLOW` / `READBHIGH` return for `index`.
+    fn read_pins(&self, index: usize, byte: PinByte) -> u8 {
+        let pins = self.config.pins;
+        (0..8)
+            .map(|bit| Pin { byte, bit })
+            .filter(|&pin| {
+                if index == self.config.flash_interface as usize && pin == pins.cdone {
+                    self.cdone
+                } else {
+                    self.pin_level(index, pin)
+                }
+            })
+            .fold(0, |value, pin| value | pin.mask())
+    }
+
+    /// Reacts to the GPIO of `index` changing: selects or deselects the flash or the gateware
+    /// and resets or releases the iCE40.
+    fn update_pins(&mut self, index: usize) {
+        if index == self.config.comm_interface as usize {
+            let comm_cs_b = self.pin_level(index, self.config.comm_cs);
+            if comm_cs_b != self.comm_cs_b {
+                if comm_cs_b {
+                    self.gateware.deselect();
+                } else {
+                    self.gateware.select();
+                }
+                self.comm_cs_b = comm_cs_b;
+            }
+        }
+
+        if index != self.config.flash_interface as usize {
+            return;
+        }
+
+        let pins = self.config.pins;
+        let cs_b = self.pin_level(index, pins.cs);
+        let creset_b = self.pin_level(index, pins.creset);
+
+        if cs_b != self.cs_b {
+            if cs_b {
+                if let Some(opcode) = self.flash.deselect() {
+                    self.disconnect_on(opcode);
+                }
+            } else {
+                self.flash.select();
+            }
+            self.cs_b = cs_b;
+        }
+
+        if creset_b != self.creset_b {
+            // Holding CRESET_B low clears the configuration, releasing it makes the iCE40 load
+            // its configuration from the flash.
+            self.cdone = creset_b && self.flash_has_bitstream();
+            self.creset_b = creset_b;
+        }
+    }
+
+    /// Drops the device off the bus if a [`Fault::Disconnect`] was waiting for `opcode`.
+    fn disconnect_on(&mut self, opcode: u8) {
+        let matches = |fault: &Fault| *fault == Fault::Disconnect { opcode };
+        if self.take_fault(matches).is_some() {
+            self.disconnected = true;
+            self.generation += 1;
+        }
+    }
+
+    fn flash_has_bitstream(&self) -> bool {
+        let memory = self.flash.memory();
+        memory[..memory.len().min(Emulator::ICE40_PREAMBLE_WINDOW)]
+            .windows(Emulator::ICE40_PREAMBLE.len())
+            .any(|window| window == Emulator::ICE40_PREAMBLE)
+    }
+}
@@ -0,0 +1,287 @@
+use crate::ftdi::flash::FlashCommand;
+
+const WE: u8 = FlashCommand::WE as u8;
+const WD: u8 = FlashCommand::WD as u8;
+const RPD: u8 = FlashCommand::RPD as u8;
+const JEDECID: u8 = FlashCommand::JEDECID as u8;
+const RD: u8 = FlashCommand::RD as u8;
+const FR: u8 = FlashCommand::FR as u8;
+const PP: u8 = FlashCommand::PP as u8;
+const SE: u8 = FlashCommand::SE as u8;
+const BE32: u8 = FlashCommand::BE32 as u8;
+const BE64: u8 = FlashCommand::BE64 as u8;
+const CE: u8 = FlashCommand::CE as u8;
+/// Chip erase has two opcodes.
+const CE_ALT: u8 = 0x60;
+const RSR1: u8 = FlashCommand::RSR1 as u8;
+const WSR1: u8 = FlashCommand::WSR1 as u8;
+const RSR2: u8 = FlashCommand::RSR2 as u8;
+const RSR3: u8 = FlashCommand::RSR3 as u8;
+const PD: u8 = FlashCommand::PD as u8;
+const ERESET: u8 = FlashCommand::ERESET as u8;
+const RESET: u8 = FlashCommand::RESET as u8;
+
+/// An emulated SPI NOR flash, modelled on the Micron N25Q032 found on the iCEstick.
+///
+/// Programs and erases complete instantly, but report busy for a configurable number of status
+/// register reads afterwards so that polling loops are exercised.
+#[derive(Clone, Debug)]
+pub struct SpiFlash {
+    memory: Vec<u8>,
+    /// What follows the JEDEC ID command: manufacturer, two device IDs, the length of the
+    /// extended device string and the string itself.
+    jedec_id: Vec<u8>,
+    /// Status register 1. Bit 0 is WIP (derived from `busy`), bit 1 is WEL.
+    status: u8,
+    busy_polls: u32,
+    busy: u32,
+    /// Reports busy whatever `busy` says, until reset.
+    stuck_busy: bool,
+    /// Addresses that read back wrong: the address, the bits flipped and how many clean reads
+    /// come first.
+    bit_flips: Vec<(usize, u8, usize)>,
+    powered_down: bool,
+    reset_enabled: bool,
+    selected: bool,
+    /// Bytes clocked in since the flash was selected, as many as a page program needs.
+    received: Vec<u8>,
+    /// How many bytes were clocked since the flash was selected.
+    clocked: usize,
+    history: Vec<u8>,
+}
+
+impl SpiFlash {
+    const STATUS_WIP: u8 = 0x01;
+    const STATUS_WEL: u8 = 0x02;
+    const PAGE_SIZE: usize = 256;
+    /// Opcode plus 24 bit address.
+    const HEADER_LEN: usize = 4;
+
+    /// A blank (all `0xFF`) flash of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        let mut jedec_id = vec![0x20, 0xBA, 0x16, 0x10];
+        jedec_id.extend(0..0x10);
+
+        Self {
+            memory: vec![0xFF; size],
+            jedec_id,
+            status: 0,
+            busy_polls: 2,
+            busy: 0,
+            stuck_busy: false,
+            bit_flips: vec![],
+            powered_down: false,
+            reset_enabled: false,
+            selected: false,
+            received: vec![],
+            clocked: 0,
+            history: vec![],
+        }
+    }
+
+    /// How many status reads report busy after a program or erase.
+    pub fn set_busy_polls(&mut self, polls: u32) {
+        self.busy_polls = polls;
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    /// The opcode of every command the flash has completed, oldest first.
+    pub fn history(&self) -> &[u8] {
+        &self.history
+    }
+
+    pub fn is_powered_down(&self) -> bool {
+        self.powered_down
+    }
+
+    pub fn status(&self) -> u8 {
+        self.status | if self.is_busy() { SpiFlash::STATUS_WIP } else { 0 }
+    }
+
+    fn is_busy(&self) -> bool {
+        self.busy > 0 || self.stuck_busy
+    }
+
+    pub(crate) fn set_stuck_busy(&mut self, stuck: bool) {
+        self.stuck_busy = stuck;
+    }
+
+    pub(crate) fn add_bit_flip(&mut self, address: usize, mask: u8, skip: usize) {
+        self.bit_flips.push((address, mask, skip));
+    }
+
+    pub(crate) fn clear_bit_flips(&mut self) {
+        self.bit_flips.clear();
+    }
+
+    /// CS went low.
+    pub(crate) fn select(&mut self) {
+        self.selected = true;
+        self.received.clear();
+        self.clocked = 0;
+    }
+
+    /// CS went high, finishing the command that was clocked in. Returns its opcode if the flash
+    /// accepted it.
+    pub(crate) fn deselect(&mut self) -> Option<u8> {
+        if !self.selected {
+            return None;
+        }
+        self.selected = false;
+
+        let opcode = *self.received.first()?;
+
+        if self.powered_down {
+            if opcode == RPD {
+                self.powered_down = false;
+                self.history.push(opcode);
+                return Some(opcode);
+            }
+            return None;
+        }
+
+        // While busy only the status register can be read, and the flash reset.
+        if self.is_busy() && !matches!(opcode, RSR1 | ERESET | RESET) {
+            return None;
+        }
+
+        self.history.push(opcode);
+        let write_enabled = self.status & SpiFlash::STATUS_WEL != 0;
+        let address = self.address();
+
+        match opcode {
+            WE => self.status |= SpiFlash::STATUS_WEL,
+            WD => self.status &= !SpiFlash::STATUS_WEL,
+            PD => self.powered_down = true,
+            ERESET => self.reset_enabled = true,
+            RESET if self.reset_enabled => {
+                self.status = 0;
+                self.busy = 0;
+                self.stuck_busy = false;
+            }
+            WSR1 if write_enabled => {
+                if let Some(&value) = self.received.get(1) {
+                    // WIP and WEL are read only.
+                    self.status = value & !(SpiFlash::STATUS_WIP | SpiFlash::STATUS_WEL);
+                    self.finish_write();
+                }
+            }
+            PP if write_enabled => {
+                if let Some(address) = address {
+                    let page = address & !(SpiFlash::PAGE_SIZE - 1);
+                    let data = &self.received[SpiFlash::HEADER_LEN..];
+                    for (i, byte) in data.iter().enumerate() {
+                        // Programming wraps around within the page, and can only clear bits.
+                        let target = page + (address + i) % SpiFlash::PAGE_SIZE;
+                        if let Some(cell) = self.memory.get_mut(target) {
+                            *cell &= byte;
+                        }
+                    }
+                    self.finish_write();
+                }
+            }
+            SE | BE32 | BE64 if write_enabled => {
+                if let Some(address) = address {
+                    let size = match opcode {
+                        SE => 4 << 10,
+                        BE32 => 32 << 10,
+                        _ => 64 << 10,
+                    };
+                    let start = (address & !(size - 1)).min(self.memory.len());
+                    let end = (start + size).min(self.memory.len());
+                    self.memory[start..end].fill(0xFF);
+                    self.finish_write();
+                }
+            }
+            CE | CE_ALT if write_enabled => {
+                self.memory.fill(0xFF);
+                self.finish_write();
+            }
+            _ => {}
+        }
+
+        if opcode != ERESET {
+            self.reset_enabled = false;
+        }
+
+        Some(opcode)
+    }
+
+    /// Clocks one byte in on MOSI, returning the byte clocked out on MISO.
+    pub(crate) fn transfer(&mut self, mosi: u8) -> u8 {
+        if !self.selected {
+            return 0xFF;
+        }
+
+        let position = self.clocked;
+        self.clocked += 1;
+        if position < SpiFlash::HEADER_LEN + SpiFlash::PAGE_SIZE {
+            self.received.push(mosi);
+        }
+
+        if position == 0 || self.powered_down {
+            return 0xFF;
+        }
+
+        match self.received[0] {
+            RSR1 => {
+                let status = self.status();
+                self.busy = self.busy.saturating_sub(1);
+                status
+            }
+            RSR2 | RSR3 => 0,
+            JEDECID => self.jedec_id.get(position - 1).copied().unwrap_or(0),
+            RD => self.read_at(position, SpiFlash::HEADER_LEN),
+            // Fast read has a dummy byte after the address.
+            FR => self.read_at(position, SpiFlash::HEADER_LEN + 1),
+            _ => 0xFF,
+        }
+    }
+
+    /// The memory at the received address plus `position - start`, wrapping at the end.
+    fn read_at(&mut self, position: usize, start: usize) -> u8 {
+        let target = match self.address() {
+            Some(address) if position >= start && !self.memory.is_empty() => {
+                (address + position - start) % self.memory.len()
+            }
+            _ => return 0xFF,
+        };
+
+        let mut value = self.memory[target];
+        self.bit_flips.retain_mut(|(address, mask, skip)| {
+            if *address != target {
+                true
+            } else if *skip > 0 {
+                *skip -= 1;
+                true
+            } else {
+                value ^= *mask;
+                false
+            }
+        });
+
+        value
+    }
+
+    /// The 24 bit address following the opcode, once it has been received.
+    fn address(&self) -> Option<usize> {
+        match self.received.get(1..SpiFlash::HEADER_LEN) {
+            Some(&[high, middle, low]) => {
+                Some((high as usize) << 16 | (middle as usize) << 8 | low as usize)
+            }
+            _ => None,
+        }
+    }
+
+    fn finish_write(&mut self) {
+        self.status &= !SpiFlash::STATUS_WEL;
+        self.busy = self.busy_polls;
+    }
+}
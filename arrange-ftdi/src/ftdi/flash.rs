@@ -1,8 +1,19 @@
-use arrange_misc::error::ArrangeError;
-use log::{debug, error, info};
-
-use super::{block_erase::BlockErase, mpsse::MPSSE};
+use std::{thread::sleep, time::Instant};
 
+use arrange_misc::error::ArrangeError;
+use log::{debug, error, info, log_enabled, warn, Level};
+
+use super::{
+    bitstream::Framing,
+    block_erase::BlockErase,
+    command_buffer::CommandBuffer,
+    mpsse::MPSSE,
+    pins::{PinByte, PinMap},
+    retry::RetryPolicy,
+    transport::{DefaultTransport, Transport},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum FlashCommand {
     ///  Write Enable
     WE = 0x06,
@@ -78,32 +89,148 @@ pub enum FlashCommand {
     RESET = 0x99,
 }
 
-pub struct Flash<'a, 'b> {
-    mpsse: &'b mut MPSSE<'a>,
+impl FlashCommand {
+    const ALL: [FlashCommand; 36] = [
+        FlashCommand::WE,
+        FlashCommand::SRWE,
+        FlashCommand::WD,
+        FlashCommand::RPD,
+        FlashCommand::MFGID,
+        FlashCommand::JEDECID,
+        FlashCommand::UID,
+        FlashCommand::RD,
+        FlashCommand::FR,
+        FlashCommand::PP,
+        FlashCommand::SE,
+        FlashCommand::BE32,
+        FlashCommand::BE64,
+        FlashCommand::CE,
+        FlashCommand::RSR1,
+        FlashCommand::WSR1,
+        FlashCommand::RSR2,
+        FlashCommand::WSR2,
+        FlashCommand::RSR3,
+        FlashCommand::WSR3,
+        FlashCommand::RSFDP,
+        FlashCommand::ESR,
+        FlashCommand::PSR,
+        FlashCommand::RSR,
+        FlashCommand::GBL,
+        FlashCommand::GBU,
+        FlashCommand::RBL,
+        FlashCommand::RPR,
+        FlashCommand::IBL,
+        FlashCommand::IBU,
+        FlashCommand::EPS,
+        FlashCommand::EPR,
+        FlashCommand::PD,
+        FlashCommand::QPI,
+        FlashCommand::ERESET,
+        FlashCommand::RESET,
+    ];
+
+    /// The command with the given opcode, if we know it.
+    pub fn from_opcode(opcode: u8) -> Option<Self> {
+        FlashCommand::ALL
+            .into_iter()
+            .find(|command| *command as u8 == opcode)
+    }
 }
 
-impl<'a, 'b> Flash<'a, 'b> {
-    pub fn new(mpsse: &'b mut MPSSE<'a>) -> Self {
-        Self { mpsse }
+pub struct Flash<'a, T: Transport = DefaultTransport> {
+    mpsse: &'a mut MPSSE<T>,
+    pins: PinMap,
+    retry: RetryPolicy,
+}
+
+impl<'a, T: Transport> Flash<'a, T> {
+    pub fn new(mpsse: &'a mut MPSSE<T>, pins: PinMap) -> Self {
+        Self {
+            mpsse,
+            pins,
+            retry: RetryPolicy::default(),
+        }
     }
 
-    fn set_cs_creset(&mut self, cs_b: u32, creset_b: u32) -> Result<(), ArrangeError> {
+    /// Uses `retry` instead of the default [`RetryPolicy`].
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Runs `f`, tagging any error it returns with the flash operation and address.
+    fn with_context<R>(
+        &mut self,
+        operation: &'static str,
+        address: Option<usize>,
+        f: impl FnOnce(&mut Self) -> Result<R, ArrangeError>,
+    ) -> Result<R, ArrangeError> {
+        f(self).map_err(|error| error.in_flash(operation, address))
+    }
+
+    /// Queues driving CS and CRESET. A signal is pulled low by making its pin an output (all
+    /// outputs are driven low), and released by making it an input so the board's pull-up takes
+    /// over.
+    fn queue_cs_creset(&self, commands: &mut CommandBuffer, cs_b: u32, creset_b: u32) {
         let gpio: u8 = 0;
-        let mut direction: u8 = 0x03;
+        let mut direction: [u8; 2] = [0, 0];
+        let byte = |pin_byte: PinByte| match pin_byte {
+            PinByte::Low => 0,
+            PinByte::High => 1,
+        };
+
+        direction[byte(self.pins.sck.byte)] |= self.pins.sck.mask();
+        direction[byte(self.pins.mosi.byte)] |= self.pins.mosi.mask();
 
         if cs_b == 0 {
-            direction |= 0x10;
+            direction[byte(self.pins.cs.byte)] |= self.pins.cs.mask();
         }
 
         if creset_b == 0 {
-            direction |= 0x80;
+            direction[byte(self.pins.creset.byte)] |= self.pins.creset.mask();
+        }
+
+        commands.set_gpio(gpio, direction[0]);
+        if self.pins.uses_high_byte() {
+            commands.set_gpio_high(gpio, direction[1]);
         }
+    }
+
+    fn set_cs_creset(&mut self, cs_b: u32, creset_b: u32) -> Result<(), ArrangeError> {
+        let mut commands = CommandBuffer::new();
+        self.queue_cs_creset(&mut commands, cs_b, creset_b);
+        self.mpsse.execute(&commands).map(|_| ())
+    }
 
-        self.mpsse.set_gpio(gpio, direction)
+    /// Selects the flash, runs the commands queued by `queue` and deselects it again, all in a
+    /// single USB round trip. Returns everything the queued commands read.
+    fn transaction(
+        &mut self,
+        operation: &'static str,
+        address: Option<usize>,
+        queue: impl FnOnce(&mut CommandBuffer) -> &mut CommandBuffer,
+    ) -> Result<Vec<u8>, ArrangeError> {
+        let mut commands = CommandBuffer::new();
+        self.queue_cs_creset(&mut commands, 0, 0);
+        queue(&mut commands);
+        self.queue_cs_creset(&mut commands, 1, 0);
+
+        self.with_context(operation, address, |flash| flash.mpsse.execute(&commands))
+    }
+
+    /// Reads the iCE40 CDONE signal, high once the FPGA has been configured.
+    pub fn cdone(&mut self) -> Result<bool, ArrangeError> {
+        let cdone = self.pins.cdone;
+        let value = self.with_context("read CDONE", None, |flash| match cdone.byte {
+            PinByte::Low => flash.mpsse.read_low_byte(),
+            PinByte::High => flash.mpsse.read_high_byte(),
+        })?;
+
+        Ok(value & cdone.mask() != 0)
     }
 
     pub fn release_reset(&mut self) -> Result<(), ArrangeError> {
-        self.set_cs_creset(1, 1)
+        self.with_context("release reset", None, |flash| flash.set_cs_creset(1, 1))
     }
 
     pub fn chip_select(&mut self) -> Result<(), ArrangeError> {
@@ -127,26 +254,35 @@ impl<'a, 'b> Flash<'a, 'b> {
 
         let data: [u8; 5] = [FlashCommand::JEDECID as u8; 5];
         debug!("Read Flash ID...");
-        self.chip_select()?;
+        let (jedec, e_dev) = self.with_context("read JEDEC ID", None, |flash| {
+            let mut commands = CommandBuffer::new();
+            flash.queue_cs_creset(&mut commands, 0, 0);
+            commands.spi_transfer(&data[..5]);
+            let jedec = flash.mpsse.execute(&commands)?;
+
+            let e_dev = {
+                if jedec[4] == 0xff {
+                    error!(
+                    "Extended Device String Length is 0xFF, this is likely a read error. Ignoring..."
+                );
+                    return Err(ArrangeError::UnexpectedResponse {
+                        operation: "read JEDEC ID",
+                        message: "extended device string length is 0xFF".to_string(),
+                    });
+                } else if jedec[4] != 0 {
+                    // We should read out the rest of the bytes...
+                    debug!("Getting Extended Device String of length: {}", jedec[4]);
+                    flash.mpsse.transfer_spi(&vec![0; jedec[4] as usize])?
+                } else {
+                    return Err(ArrangeError::UnexpectedResponse {
+                        operation: "read JEDEC ID",
+                        message: "extended device string length is 0".to_string(),
+                    });
+                }
+            };
 
-        let jedec = self.mpsse.transfer_spi(&data[..5]).unwrap();
-
-        let e_dev = {
-            if jedec[4] == 0xff {
-                error!(
-                "Extended Device String Length is 0xFF, this is likely a read error. Ignoring..."
-            );
-                return Err(ArrangeError::ReadError);
-            } else if jedec[4] != 0 {
-                // We should read out the rest of the bytes...
-                debug!("Getting Extended Device String of length: {}", jedec[4]);
-                self.mpsse
-                    .transfer_spi(&vec![0; jedec[4] as usize])
-                    .unwrap()
-            } else {
-                return Err(ArrangeError::DeviceError);
-            }
-        };
+            Ok((jedec, e_dev))
+        })?;
 
         debug!("Flash MFG ID: {:#x}", jedec[1]);
         debug!("Flash Dev ID #1: {:#x}", jedec[2]);
@@ -157,7 +293,7 @@ impl<'a, 'b> Flash<'a, 'b> {
         flash_id.push_str(&format!("{:#02X} ", jedec[1]));
         flash_id.push_str(&format!("{:#02X} ", jedec[2]));
         flash_id.push_str(&format!("{:#02X} ", jedec[3]));
-        for d in e_dev[1..e_dev.len()].into_iter() {
+        for d in e_dev[1..e_dev.len()].iter() {
             flash_id.push_str(&format!("{:#02X} ", d));
         }
 
@@ -168,34 +304,36 @@ impl<'a, 'b> Flash<'a, 'b> {
     pub fn reset(&mut self) -> Result<(), ArrangeError> {
         let cmd: [u8; 8] = [0xff; 8];
 
-        self.chip_select()?;
-        self.mpsse.transfer_spi(&cmd)?;
-        self.chip_deselect()?;
+        self.transaction("reset", None, |commands| commands.spi_transfer(&cmd))?;
+        self.transaction("reset", None, |commands| commands.spi_transfer_bits(0xff, 2))?;
+        Ok(())
+    }
 
-        self.chip_select()?;
-        self.mpsse.transfer_spi_bits(0xff, 2)?;
-        self.chip_deselect()
+    /// Resets the flash itself, abandoning any program or erase in progress.
+    pub fn software_reset(&mut self) -> Result<(), ArrangeError> {
+        let enable: [u8; 1] = [FlashCommand::ERESET as u8];
+        let reset: [u8; 1] = [FlashCommand::RESET as u8];
+        self.transaction("software reset", None, |commands| commands.spi_write(&enable))?;
+        self.transaction("software reset", None, |commands| commands.spi_write(&reset))?;
+        Ok(())
     }
 
     pub fn power_up(&mut self) -> Result<(), ArrangeError> {
         let cmd: [u8; 1] = [FlashCommand::RPD as u8];
-        self.chip_select()?;
-        self.mpsse.transfer_spi(&cmd)?;
-        self.chip_deselect()
+        self.transaction("power up", None, |commands| commands.spi_write(&cmd))?;
+        Ok(())
     }
 
     pub fn power_down(&mut self) -> Result<(), ArrangeError> {
         let cmd: [u8; 1] = [FlashCommand::PD as u8];
-        self.chip_select()?;
-        self.mpsse.transfer_spi(&cmd)?;
-        self.chip_deselect()
+        self.transaction("power down", None, |commands| commands.spi_write(&cmd))?;
+        Ok(())
     }
 
     pub fn read_status(&mut self) -> Result<u8, ArrangeError> {
         let cmd: [u8; 2] = [FlashCommand::RSR1 as u8; 2];
-        self.chip_select()?;
-        let response = self.mpsse.transfer_spi(&cmd)?;
-        self.chip_deselect()?;
+        let response =
+            self.transaction("read status", None, |commands| commands.spi_transfer(&cmd))?;
 
         debug!("SR1: {:#02X}", response[1]);
         debug!(
@@ -261,22 +399,23 @@ impl<'a, 'b> Flash<'a, 'b> {
     }
 
     pub fn write_enable(&mut self) -> Result<(), ArrangeError> {
-        debug!("Status before enable: {}", self.read_status()?);
+        // Reading the status costs a round trip, only do it when someone will see it.
+        if log_enabled!(Level::Debug) {
+            debug!("Status before enable: {}", self.read_status()?);
+        }
         debug!("Enabling Write...");
 
         let cmd: [u8; 1] = [FlashCommand::WE as u8];
-        self.chip_select()?;
-        self.mpsse.transfer_spi(&cmd)?;
-        self.chip_deselect()
+        self.transaction("write enable", None, |commands| commands.spi_write(&cmd))?;
+        Ok(())
     }
 
     pub fn bulk_erase(&mut self) -> Result<(), ArrangeError> {
         info!("Bulk Erase...");
 
         let cmd: [u8; 1] = [FlashCommand::CE as u8];
-        self.chip_select()?;
-        self.mpsse.transfer_spi(&cmd)?;
-        self.chip_deselect()
+        self.transaction("bulk erase", None, |commands| commands.spi_write(&cmd))?;
+        Ok(())
     }
 
     pub fn sector_erase(&mut self, be: BlockErase, addr: usize) -> Result<(), ArrangeError> {
@@ -303,9 +442,10 @@ impl<'a, 'b> Flash<'a, 'b> {
             ],
         };
 
-        self.chip_select()?;
-        self.mpsse.send_spi(&command)?;
-        self.chip_deselect()
+        self.transaction("sector erase", Some(addr), |commands| {
+            commands.spi_write(&command)
+        })?;
+        Ok(())
     }
 
     pub fn prog(&mut self, addr: usize, data: &[u8]) -> Result<(), ArrangeError> {
@@ -318,28 +458,30 @@ impl<'a, 'b> Flash<'a, 'b> {
             addr as u8,
         ];
 
-        self.chip_select()?;
-        self.mpsse.send_spi(&cmd)?;
-        self.mpsse.send_spi(data)?;
-        self.chip_deselect()?;
-
-        let mut debug_str = String::new();
-        for i in 0..data.len() {
-            debug_str.push_str(&format!(
-                "{:#02x}{}",
-                data[i],
-                if i == data.len() - 1 || i % 32 == 31 {
-                    '\n'
-                } else {
-                    ' '
-                }
-            ));
+        self.transaction("page program", Some(addr), |commands| {
+            commands.spi_write(&cmd).spi_write(data)
+        })?;
+
+        if log_enabled!(Level::Debug) {
+            let mut debug_str = String::new();
+            for (i, byte) in data.iter().enumerate() {
+                debug_str.push_str(&format!(
+                    "{:#02x}{}",
+                    byte,
+                    if i == data.len() - 1 || i % 32 == 31 {
+                        '\n'
+                    } else {
+                        ' '
+                    }
+                ));
+            }
+            debug!("\n{}", debug_str.trim_end_matches('\n'));
         }
-        debug!("\n{}", debug_str.trim_end_matches('\n'));
 
         Ok(())
     }
 
+    /// Reads `n` bytes starting at `addr`, in a single command however large `n` is.
     pub fn read(&mut self, addr: usize, n: usize) -> Result<Vec<u8>, ArrangeError> {
         debug!("read {:#06X} +{:#03X}", addr, n);
 
@@ -350,38 +492,82 @@ impl<'a, 'b> Flash<'a, 'b> {
             addr as u8,
         ];
 
-        self.chip_select()?;
-        self.mpsse.send_spi(&cmd)?;
-        let response = self.mpsse.transfer_spi(&vec![0; n])?;
-        self.chip_deselect()?;
-
-        let mut debug_str = String::new();
-        for i in 0..response.len() {
-            debug_str.push_str(&format!(
-                "{:#02x}{}",
-                response[i],
-                if i == response.len() - 1 || i % 32 == 31 {
-                    '\n'
-                } else {
-                    ' '
-                }
-            ));
+        let response = self.transaction("read", Some(addr), |commands| {
+            commands.spi_write(&cmd).spi_read(n)
+        })?;
+
+        if log_enabled!(Level::Debug) {
+            let mut debug_str = String::new();
+            for (i, byte) in response.iter().enumerate() {
+                debug_str.push_str(&format!(
+                    "{:#02x}{}",
+                    byte,
+                    if i == response.len() - 1 || i % 32 == 31 {
+                        '\n'
+                    } else {
+                        ' '
+                    }
+                ));
+            }
+            debug!("\n{}", debug_str.trim_end_matches('\n'));
         }
-        debug!("\n{}", debug_str.trim_end_matches('\n'));
 
         Ok(response)
     }
 
+    /// Reads the bitstream at the start of the flash, following its framing to find where it
+    /// ends rather than reading the whole chip.
+    pub fn read_bitstream(&mut self) -> Result<Vec<u8>, ArrangeError> {
+        // Bitstreams are read a piece at a time, within the 24 bit address space.
+        const CHUNK: usize = 16 << 10;
+        const LIMIT: usize = 1 << 24;
+
+        let mut framing = Framing::new();
+        let mut image = Vec::new();
+        while image.len() < LIMIT {
+            image.extend(self.read(image.len(), CHUNK)?);
+            if let Some(length) = framing.advance(&image)? {
+                image.truncate(length);
+                return Ok(image);
+            }
+        }
+
+        Err(ArrangeError::UnexpectedResponse {
+            operation: "bitstream",
+            message: format!("no end found in the first {LIMIT} bytes of the flash"),
+        })
+    }
+
+    /// Polls the status register until the flash has finished programming or erasing.
+    ///
+    /// Status reads that go wrong are retried after resynchronizing the MPSSE, as the
+    /// [`RetryPolicy`] allows. Gives up with [`ArrangeError::Busy`] if the flash is still busy
+    /// after its `busy_timeout`.
     pub fn wait(&mut self) -> Result<(), ArrangeError> {
         debug!("Waiting...");
 
+        let deadline = Instant::now() + self.retry.busy_timeout;
         let mut count = 0;
+        let mut failures = 0;
 
         loop {
             let cmd: [u8; 2] = [FlashCommand::RSR1 as u8; 2];
-            self.chip_select()?;
-            let response = self.mpsse.transfer_spi(&cmd)?;
-            self.chip_deselect()?;
+            let response =
+                match self.transaction("wait", None, |commands| commands.spi_transfer(&cmd)) {
+                    Ok(response) => {
+                        // The limit is on failures in a row, a long erase may see a few.
+                        failures = 0;
+                        response
+                    }
+                    Err(error) if error.is_transient() && failures + 1 < self.retry.attempts => {
+                        failures += 1;
+                        warn!("Status read failed ({error}), retrying...");
+                        sleep(self.retry.backoff);
+                        self.with_context("wait", None, |flash| flash.mpsse.resync())?;
+                        continue;
+                    }
+                    Err(error) => return Err(error),
+                };
 
             if response[1] & 0x01 == 0 {
                 if count < 2 {
@@ -393,6 +579,13 @@ impl<'a, 'b> Flash<'a, 'b> {
                 }
             } else {
                 count = 0;
+                if Instant::now() >= deadline {
+                    return Err(ArrangeError::Busy {
+                        operation: "wait",
+                        timeout: self.retry.busy_timeout,
+                    }
+                    .in_flash("wait", None));
+                }
             }
 
             //sleep(Duration::from_millis(1));
@@ -405,15 +598,15 @@ impl<'a, 'b> Flash<'a, 'b> {
         info!("Disable Flash Protection...");
 
         let cmd: [u8; 2] = [FlashCommand::WSR1 as u8, 0];
-        self.chip_select()?;
-        self.mpsse.transfer_spi(&cmd).unwrap();
-        self.chip_deselect()?;
+        self.transaction("disable protection", None, |commands| {
+            commands.spi_write(&cmd)
+        })?;
         self.wait()?;
 
         let cmd2: [u8; 2] = [FlashCommand::RSR1 as u8, 0];
-        self.chip_select()?;
-        let response = self.mpsse.transfer_spi(&cmd2).unwrap();
-        self.chip_deselect()?;
+        let response = self.transaction("disable protection", None, |commands| {
+            commands.spi_transfer(&cmd2)
+        })?;
 
         if response[1] != 0 {
             error!(
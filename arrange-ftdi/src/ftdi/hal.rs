@@ -0,0 +1,454 @@
+//! [`embedded-hal`](embedded_hal) 1.0 on top of an [`MPSSE`], so existing device drivers can
+//! talk to peripherals on the board from the host.
+//!
+//! A [`Hal`] takes over the GPIO of an interface and hands out the SPI bus, SPI devices with a
+//! chip select of their own, the other pins as inputs or outputs and delays. Each pin can only be
+//! handed out once at a time, SCK, MOSI and MISO (ADBUS0 to ADBUS2) never.
+//!
+//! ```no_run
+//! use arrange_ftdi::{
+//!     ftdi::{pins::Pin, spi::SpiFormat},
+//!     ArrangeFTDI,
+//! };
+//! use arrange_misc::traits::Arrange;
+//! use embedded_hal::{digital::OutputPin, spi::SpiDevice};
+//!
+//! let mut arrange = ArrangeFTDI::with_config(Default::default());
+//! arrange.init().unwrap();
+//! let hal = arrange.hal(SpiFormat::default(), 1_000_000).unwrap();
+//!
+//! let mut adc = hal.spi_device(Pin::low(3)).unwrap();
+//! let mut sample = [0; 2];
+//! adc.transfer(&mut sample, &[0x80, 0x00]).unwrap();
+//!
+//! let mut led = hal.output(Pin::high(0), false).unwrap();
+//! led.set_high().unwrap();
+//! ```
+//!
+//! Everything a call sends is done by the time it returns, except that writes may still be
+//! clocking out of the chip. Delays wait for the chip to catch up before they start.
+
+use std::{
+    fmt,
+    sync::{Mutex, MutexGuard},
+    thread::sleep,
+    time::Duration,
+};
+
+use arrange_misc::error::ArrangeError;
+use embedded_hal::{
+    delay::DelayNs,
+    digital::{self, InputPin, OutputPin, StatefulOutputPin},
+    spi::{self, Operation, SpiBus},
+};
+use log::debug;
+
+use super::{
+    command_buffer::CommandBuffer,
+    mpsse::MPSSE,
+    pins::{Pin, PinByte},
+    spi::SpiFormat,
+    transport::{DefaultTransport, Transport},
+};
+
+/// An [`ArrangeError`] as the error of the embedded-hal traits.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HalError(pub ArrangeError);
+
+impl fmt::Display for HalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for HalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl From<ArrangeError> for HalError {
+    fn from(error: ArrangeError) -> Self {
+        Self(error)
+    }
+}
+
+impl spi::Error for HalError {
+    fn kind(&self) -> spi::ErrorKind {
+        spi::ErrorKind::Other
+    }
+}
+
+impl digital::Error for HalError {
+    fn kind(&self) -> digital::ErrorKind {
+        digital::ErrorKind::Other
+    }
+}
+
+fn byte_index(pin: Pin) -> usize {
+    match pin.byte {
+        PinByte::Low => 0,
+        PinByte::High => 1,
+    }
+}
+
+/// The interface and what the `Hal` made of its GPIO.
+struct State<'a, T: Transport> {
+    mpsse: &'a mut MPSSE<T>,
+    format: SpiFormat,
+    /// Output values and directions (1 = output) of the low and high GPIO bytes.
+    gpio: [u8; 2],
+    direction: [u8; 2],
+    /// The pins handed out, plus the ones SPI needs.
+    claimed: [u8; 2],
+}
+
+impl<T: Transport> State<'_, T> {
+    /// Queues driving the GPIO byte `pin` is on.
+    fn queue_gpio(&self, commands: &mut CommandBuffer, pin: Pin) {
+        let byte = byte_index(pin);
+        match pin.byte {
+            PinByte::Low => commands.set_gpio(self.gpio[byte], self.direction[byte]),
+            PinByte::High => commands.set_gpio_high(self.gpio[byte], self.direction[byte]),
+        };
+    }
+
+    /// Makes `pin` an output at `high`, or an input.
+    fn queue_pin(&mut self, commands: &mut CommandBuffer, pin: Pin, output: Option<bool>) {
+        let byte = byte_index(pin);
+        self.gpio[byte] &= !pin.mask();
+        self.direction[byte] &= !pin.mask();
+        if let Some(high) = output {
+            self.direction[byte] |= pin.mask();
+            self.gpio[byte] |= if high { pin.mask() } else { 0 };
+        }
+        self.queue_gpio(commands, pin);
+    }
+
+    fn set_pin(&mut self, pin: Pin, output: Option<bool>) -> Result<(), ArrangeError> {
+        let mut commands = CommandBuffer::new();
+        self.queue_pin(&mut commands, pin, output);
+        self.mpsse.execute(&commands).map(|_| ())
+    }
+
+    /// Waits until the chip has done everything sent so far.
+    fn sync(&mut self) -> Result<(), ArrangeError> {
+        self.mpsse.read_low_byte().map(|_| ())
+    }
+
+    /// Runs `operations` with `cs` (active low) selected throughout, if there is one.
+    fn run(
+        &mut self,
+        cs: Option<Pin>,
+        operations: &mut [Operation<'_, u8>],
+    ) -> Result<(), ArrangeError> {
+        let mut commands = CommandBuffer::new();
+        commands.spi_format(self.format);
+        if let Some(cs) = cs {
+            self.queue_pin(&mut commands, cs, Some(false));
+        }
+
+        // Delays split the operations, everything between two of them is a single round trip.
+        let mut start = 0;
+        for index in 0..=operations.len() {
+            let delay = match operations.get(index) {
+                Some(Operation::DelayNs(ns)) => Some(*ns),
+                Some(operation) => {
+                    queue_operation(&mut commands, operation);
+                    continue;
+                }
+                None => None,
+            };
+
+            match (delay, cs) {
+                (Some(_), _) => {
+                    commands.read_low_byte();
+                }
+                (None, Some(cs)) => self.queue_pin(&mut commands, cs, Some(true)),
+                (None, None) => {}
+            }
+            let response = self.mpsse.execute(&commands)?;
+            store_responses(&mut operations[start..index], &response);
+            if let Some(ns) = delay {
+                sleep(Duration::from_nanos(ns as u64));
+            }
+
+            commands = CommandBuffer::new();
+            commands.spi_format(self.format);
+            start = index + 1;
+        }
+
+        Ok(())
+    }
+}
+
+/// Queues the commands of an SPI operation other than a delay.
+fn queue_operation(commands: &mut CommandBuffer, operation: &Operation<'_, u8>) {
+    match operation {
+        Operation::Read(words) => commands.spi_read(words.len()),
+        Operation::Write(words) => commands.spi_write(words),
+        Operation::Transfer(read, write) => {
+            // Zeros once `write` runs out.
+            let mut words = write.to_vec();
+            words.resize(read.len().max(write.len()), 0);
+            commands.spi_transfer(&words)
+        }
+        Operation::TransferInPlace(words) => commands.spi_transfer(words),
+        Operation::DelayNs(_) => commands,
+    };
+}
+
+/// Hands out what the commands of `operations` read.
+fn store_responses(operations: &mut [Operation<'_, u8>], mut response: &[u8]) {
+    for operation in operations {
+        let (words, len): (&mut [u8], usize) = match operation {
+            Operation::Read(words) | Operation::TransferInPlace(words) => {
+                let len = words.len();
+                (words, len)
+            }
+            Operation::Transfer(read, write) => {
+                let len = read.len().max(write.len());
+                (read, len)
+            }
+            Operation::Write(_) | Operation::DelayNs(_) => continue,
+        };
+
+        let count = words.len();
+        words.copy_from_slice(&response[..count]);
+        response = &response[len..];
+    }
+}
+
+/// The GPIO of an interface, for embedded-hal, see the [module documentation](self).
+pub struct Hal<'a, T: Transport = DefaultTransport> {
+    state: Mutex<State<'a, T>>,
+}
+
+impl<'a, T: Transport> Hal<'a, T> {
+    /// Sets the SPI clock to `frequency` and leaves every pin but SCK and MOSI an input.
+    pub fn new(
+        mpsse: &'a mut MPSSE<T>,
+        format: SpiFormat,
+        frequency: u32,
+    ) -> Result<Self, ArrangeError> {
+        let actual = mpsse.set_frequency(frequency)?;
+        debug!("embedded-hal SPI in {:?} at {actual} Hz", format.mode);
+
+        let sck = Pin::low(0).mask();
+        let spi = sck | Pin::low(1).mask();
+        let state = State {
+            mpsse,
+            format,
+            gpio: [if format.mode.idle_high() { sck } else { 0 }, 0],
+            direction: [spi, 0],
+            claimed: [spi | Pin::low(2).mask(), 0],
+        };
+        let mut commands = CommandBuffer::new();
+        state.queue_gpio(&mut commands, Pin::low(0));
+        state.queue_gpio(&mut commands, Pin::high(0));
+        state.mpsse.execute(&commands)?;
+
+        Ok(Self {
+            state: Mutex::new(state),
+        })
+    }
+
+    /// A poisoned lock is taken over, every call drives the pins it needs anew.
+    fn state(&self) -> MutexGuard<'_, State<'a, T>> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Reserves `pin` and makes it an output at `high`, or an input.
+    fn claim(&self, pin: Pin, output: Option<bool>) -> Result<(), ArrangeError> {
+        let mut state = self.state();
+        let byte = byte_index(pin);
+        if pin.bit > 7 || state.claimed[byte] & pin.mask() != 0 {
+            return Err(ArrangeError::InvalidConfiguration {
+                message: format!("{pin} is taken, by SPI or an earlier pin"),
+            });
+        }
+
+        state.set_pin(pin, output)?;
+        state.claimed[byte] |= pin.mask();
+        Ok(())
+    }
+
+    /// Makes `pin` an input and free to be handed out again.
+    fn release(&self, pin: Pin) {
+        let mut state = self.state();
+        state.claimed[byte_index(pin)] &= !pin.mask();
+        // Best effort, the device may already be gone.
+        let _ = state.set_pin(pin, None);
+    }
+
+    /// The SPI bus, with nothing selected unless the driver does it through an [`Output`].
+    pub fn spi(&self) -> Spi<'_, 'a, T> {
+        Spi { hal: self }
+    }
+
+    /// The device behind chip select `cs` (active low), which is selected for every
+    /// transaction.
+    pub fn spi_device(&self, cs: Pin) -> Result<SpiDevice<'_, 'a, T>, ArrangeError> {
+        self.claim(cs, Some(true))?;
+        Ok(SpiDevice { hal: self, cs })
+    }
+
+    /// `pin` as an output, starting at `high`.
+    pub fn output(&self, pin: Pin, high: bool) -> Result<Output<'_, 'a, T>, ArrangeError> {
+        self.claim(pin, Some(high))?;
+        Ok(Output { hal: self, pin })
+    }
+
+    pub fn input(&self, pin: Pin) -> Result<Input<'_, 'a, T>, ArrangeError> {
+        self.claim(pin, None)?;
+        Ok(Input { hal: self, pin })
+    }
+
+    pub fn delay(&self) -> Delay<'_, 'a, T> {
+        Delay { hal: self }
+    }
+}
+
+/// The SPI bus of a [`Hal`].
+pub struct Spi<'h, 'a, T: Transport = DefaultTransport> {
+    hal: &'h Hal<'a, T>,
+}
+
+impl<T: Transport> spi::ErrorType for Spi<'_, '_, T> {
+    type Error = HalError;
+}
+
+impl<T: Transport> SpiBus for Spi<'_, '_, T> {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        Ok(self.hal.state().run(None, &mut [Operation::Read(words)])?)
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        Ok(self.hal.state().run(None, &mut [Operation::Write(words)])?)
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        Ok(self
+            .hal
+            .state()
+            .run(None, &mut [Operation::Transfer(read, write)])?)
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        Ok(self
+            .hal
+            .state()
+            .run(None, &mut [Operation::TransferInPlace(words)])?)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(self.hal.state().sync()?)
+    }
+}
+
+/// A device on the SPI bus of a [`Hal`], with its chip select.
+pub struct SpiDevice<'h, 'a, T: Transport = DefaultTransport> {
+    hal: &'h Hal<'a, T>,
+    cs: Pin,
+}
+
+impl<T: Transport> Drop for SpiDevice<'_, '_, T> {
+    fn drop(&mut self) {
+        self.hal.release(self.cs);
+    }
+}
+
+impl<T: Transport> spi::ErrorType for SpiDevice<'_, '_, T> {
+    type Error = HalError;
+}
+
+impl<T: Transport> spi::SpiDevice for SpiDevice<'_, '_, T> {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Self::Error> {
+        Ok(self.hal.state().run(Some(self.cs), operations)?)
+    }
+}
+
+/// A GPIO output of a [`Hal`].
+pub struct Output<'h, 'a, T: Transport = DefaultTransport> {
+    hal: &'h Hal<'a, T>,
+    pin: Pin,
+}
+
+impl<T: Transport> Drop for Output<'_, '_, T> {
+    fn drop(&mut self) {
+        self.hal.release(self.pin);
+    }
+}
+
+impl<T: Transport> digital::ErrorType for Output<'_, '_, T> {
+    type Error = HalError;
+}
+
+impl<T: Transport> OutputPin for Output<'_, '_, T> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(self.hal.state().set_pin(self.pin, Some(false))?)
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(self.hal.state().set_pin(self.pin, Some(true))?)
+    }
+}
+
+impl<T: Transport> StatefulOutputPin for Output<'_, '_, T> {
+    fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+        let state = self.hal.state();
+        Ok(state.gpio[byte_index(self.pin)] & self.pin.mask() != 0)
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.is_set_high()?)
+    }
+}
+
+/// A GPIO input of a [`Hal`].
+pub struct Input<'h, 'a, T: Transport = DefaultTransport> {
+    hal: &'h Hal<'a, T>,
+    pin: Pin,
+}
+
+impl<T: Transport> Drop for Input<'_, '_, T> {
+    fn drop(&mut self) {
+        self.hal.release(self.pin);
+    }
+}
+
+impl<T: Transport> digital::ErrorType for Input<'_, '_, T> {
+    type Error = HalError;
+}
+
+impl<T: Transport> InputPin for Input<'_, '_, T> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        let mut state = self.hal.state();
+        let value = match self.pin.byte {
+            PinByte::Low => state.mpsse.read_low_byte()?,
+            PinByte::High => state.mpsse.read_high_byte()?,
+        };
+        Ok(value & self.pin.mask() != 0)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.is_high()?)
+    }
+}
+
+/// Delays of a [`Hal`], which start once the chip has caught up with what was sent before.
+pub struct Delay<'h, 'a, T: Transport = DefaultTransport> {
+    hal: &'h Hal<'a, T>,
+}
+
+impl<T: Transport> DelayNs for Delay<'_, '_, T> {
+    fn delay_ns(&mut self, ns: u32) {
+        if let Err(error) = self.hal.state().sync() {
+            // The delay still happens, the next call reports the problem.
+            debug!("Delay could not wait for the chip: {error}");
+        }
+        sleep(Duration::from_nanos(ns as u64));
+    }
+}
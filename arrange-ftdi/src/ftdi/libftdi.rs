@@ -0,0 +1,380 @@
+use std::{
+    ffi::{c_char, c_int, c_uchar, CStr, CString},
+    mem::MaybeUninit,
+    ptr::{self, NonNull},
+    time::Duration,
+};
+
+use arrange_misc::error::ArrangeError;
+use libftdi1_sys::{
+    ftdi_bits_type, ftdi_context, ftdi_device_list, ftdi_free, ftdi_get_error_string,
+    ftdi_get_latency_timer, ftdi_list_free, ftdi_new, ftdi_parity_type, ftdi_read_data,
+    ftdi_read_data_set_chunksize, ftdi_set_baudrate, ftdi_set_bitmode, ftdi_set_interface,
+    ftdi_set_latency_timer, ftdi_set_line_property, ftdi_setflowctrl, ftdi_setflowctrl_xonxoff,
+    ftdi_stopbits_type, ftdi_usb_close, ftdi_usb_find_all, ftdi_usb_get_strings, ftdi_usb_open,
+    ftdi_usb_open_string, ftdi_usb_purge_buffers, ftdi_usb_reset, ftdi_write_data,
+    libusb1_sys::{
+        libusb_config_descriptor, libusb_device, libusb_free_config_descriptor,
+        libusb_get_bus_number, libusb_get_config_descriptor, libusb_get_device_address,
+        libusb_get_device_descriptor,
+    },
+    SIO_DISABLE_FLOW_CTRL, SIO_DTR_DSR_HS, SIO_RTS_CTS_HS,
+};
+use log::debug;
+
+use super::{
+    board::Interface,
+    device_string::DeviceString,
+    discovery::DeviceInfo,
+    transport::{BitMode, Transport},
+    uart::{DataBits, FlowControl, Parity, StopBits},
+};
+
+/// A [`Transport`] over libftdi, talking to real hardware.
+///
+/// The libftdi context is allocated when the device is first opened and freed on drop.
+#[derive(Default)]
+pub struct LibFtdi {
+    context: Option<NonNull<ftdi_context>>,
+    open: bool,
+    /// How long a USB transfer may take, libftdi's default of 5 s until told otherwise.
+    timeout: Option<Duration>,
+}
+
+// SAFETY: the context is only ever reached through `&mut self`, libftdi keeps no thread local
+// state, so moving it to another thread is fine. It is not `Sync`, concurrent use of one context
+// is not.
+unsafe impl Send for LibFtdi {}
+
+impl Drop for LibFtdi {
+    fn drop(&mut self) {
+        self.close();
+        if let Some(context) = self.context.take() {
+            unsafe { ftdi_free(context.as_ptr()) };
+        }
+    }
+}
+
+impl LibFtdi {
+    /// Size of the USB bulk reads libftdi issues.
+    const READ_CHUNK_SIZE: u32 = 64 * 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The context, allocating it on first use.
+    fn context(&mut self) -> Result<*mut ftdi_context, ArrangeError> {
+        if let Some(context) = self.context {
+            return Ok(context.as_ptr());
+        }
+
+        let context = NonNull::new(unsafe { ftdi_new() }).ok_or(ArrangeError::Ftdi {
+            operation: "ftdi_new",
+            code: 0,
+            message: "could not allocate ftdi context".to_string(),
+        })?;
+        self.context = Some(context);
+        Ok(context.as_ptr())
+    }
+
+    /// Hands the transfer timeout to the context, if both exist. libftdi reads it on every
+    /// transfer.
+    fn apply_timeout(&mut self) {
+        if let (Some(context), Some(timeout)) = (self.context, self.timeout) {
+            let millis = timeout.as_millis().min(c_int::MAX as u128) as c_int;
+            unsafe {
+                (*context.as_ptr()).usb_read_timeout = millis;
+                (*context.as_ptr()).usb_write_timeout = millis;
+            }
+        }
+    }
+
+    /// Builds an [`ArrangeError::Ftdi`] from a libftdi return code and the context's error string.
+    fn ftdi_error(&mut self, operation: &'static str, code: c_int) -> ArrangeError {
+        let message = match self.context {
+            Some(context) => unsafe { CStr::from_ptr(ftdi_get_error_string(context.as_ptr())) }
+                .to_string_lossy()
+                .into_owned(),
+            None => "no ftdi context".to_string(),
+        };
+
+        ArrangeError::Ftdi {
+            operation,
+            code,
+            message,
+        }
+    }
+
+    /// Turns a libftdi status code into an error if it is not 0.
+    fn check(&mut self, operation: &'static str, status: c_int) -> Result<(), ArrangeError> {
+        debug!("Status of {operation}: {status}");
+        if status != 0 {
+            return Err(self.ftdi_error(operation, status));
+        }
+
+        Ok(())
+    }
+}
+
+impl Transport for LibFtdi {
+    fn open(
+        &mut self,
+        interface: Interface,
+        ids: &[(u16, u16)],
+        device_string: Option<&DeviceString>,
+    ) -> Result<(), ArrangeError> {
+        self.close();
+        let context = self.context()?;
+        self.apply_timeout();
+        unsafe { ftdi_set_interface(context, interface.into()) };
+
+        // Opening the USB connection with the FTDI device.
+        match device_string {
+            Some(device_string) => {
+                let description = CString::new(device_string.to_string())
+                    .expect("device strings never contain NUL bytes");
+                let open_status = unsafe { ftdi_usb_open_string(context, description.as_ptr()) };
+                debug!("Status of ftdi_usb_open_string on {device_string}: {open_status}");
+                if open_status != 0 {
+                    let error = self.ftdi_error("ftdi_usb_open_string", open_status);
+                    debug!("Can't find iCE FTDI USB Device {device_string}: {error}");
+
+                    return Err(ArrangeError::DeviceNotFound {
+                        description: format!("{device_string}: {error}"),
+                    });
+                }
+            }
+            None => {
+                let opened = ids.iter().find(|(vendor, product)| {
+                    let open_status =
+                        unsafe { ftdi_usb_open(context, *vendor as c_int, *product as c_int) };
+                    debug!(
+                        "Status of ftdi_usb_open on Device ID {:#06X}:{:#06X}: {open_status}",
+                        vendor, product
+                    );
+                    open_status == 0
+                });
+
+                if opened.is_none() {
+                    let ids = ids
+                        .iter()
+                        .map(|(vendor, product)| format!("{vendor:04x}:{product:04x}"))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    debug!("Can't find iCE FTDI USB Device (IDs: {ids})");
+
+                    return Err(ArrangeError::DeviceNotFound {
+                        description: format!("no device with IDs {ids}"),
+                    });
+                }
+            }
+        }
+        self.open = true;
+
+        // Larger bulk transfers mean fewer round trips when reading lots of data.
+        let chunksize_status =
+            unsafe { ftdi_read_data_set_chunksize(context, LibFtdi::READ_CHUNK_SIZE) };
+        self.check("ftdi_read_data_set_chunksize", chunksize_status)
+    }
+
+    fn close(&mut self) {
+        if let (true, Some(context)) = (self.open, self.context) {
+            unsafe { ftdi_usb_close(context.as_ptr()) };
+        }
+        self.open = false;
+    }
+
+    fn reset(&mut self) -> Result<(), ArrangeError> {
+        let status = unsafe { ftdi_usb_reset(self.context()?) };
+        self.check("ftdi_usb_reset", status)
+    }
+
+    fn purge(&mut self) -> Result<(), ArrangeError> {
+        let status = unsafe { ftdi_usb_purge_buffers(self.context()?) };
+        self.check("ftdi_usb_purge_buffers", status)
+    }
+
+    fn latency_timer(&mut self) -> Result<u8, ArrangeError> {
+        let mut latency: c_uchar = 0;
+        let status = unsafe { ftdi_get_latency_timer(self.context()?, &mut latency) };
+        self.check("ftdi_get_latency_timer", status)?;
+        Ok(latency)
+    }
+
+    fn set_latency_timer(&mut self, latency: u8) -> Result<(), ArrangeError> {
+        let status = unsafe { ftdi_set_latency_timer(self.context()?, latency) };
+        self.check("ftdi_set_latency_timer", status)
+    }
+
+    fn set_bitmode(&mut self, mask: u8, mode: BitMode) -> Result<(), ArrangeError> {
+        let status = unsafe { ftdi_set_bitmode(self.context()?, mask, mode as u8) };
+        self.check("ftdi_set_bitmode", status)
+    }
+
+    fn set_baud_rate(&mut self, baud: u32) -> Result<u32, ArrangeError> {
+        let context = self.context()?;
+        let status = unsafe { ftdi_set_baudrate(context, baud as c_int) };
+        self.check("ftdi_set_baudrate", status)?;
+
+        // libftdi keeps the rate it worked out in the context.
+        Ok(unsafe { (*context).baudrate } as u32)
+    }
+
+    fn set_line_properties(
+        &mut self,
+        data_bits: DataBits,
+        stop_bits: StopBits,
+        parity: Parity,
+    ) -> Result<(), ArrangeError> {
+        let status = unsafe {
+            ftdi_set_line_property(
+                self.context()?,
+                ftdi_bits_type(data_bits as u32),
+                ftdi_stopbits_type(stop_bits as u32),
+                ftdi_parity_type(parity as u32),
+            )
+        };
+        self.check("ftdi_set_line_property", status)
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> Result<(), ArrangeError> {
+        let context = self.context()?;
+        let status = match flow_control {
+            FlowControl::None => unsafe { ftdi_setflowctrl(context, SIO_DISABLE_FLOW_CTRL) },
+            FlowControl::RtsCts => unsafe { ftdi_setflowctrl(context, SIO_RTS_CTS_HS) },
+            FlowControl::DtrDsr => unsafe { ftdi_setflowctrl(context, SIO_DTR_DSR_HS) },
+            FlowControl::XonXoff => unsafe {
+                ftdi_setflowctrl_xonxoff(context, FlowControl::XON, FlowControl::XOFF)
+            },
+        };
+        self.check("ftdi_setflowctrl", status)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+        self.apply_timeout();
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, ArrangeError> {
+        let write_count =
+            unsafe { ftdi_write_data(self.context()?, data.as_ptr(), data.len() as c_int) };
+        if write_count < 0 {
+            return Err(self.ftdi_error("ftdi_write_data", write_count));
+        }
+
+        Ok(write_count as usize)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ArrangeError> {
+        let read_count = unsafe {
+            ftdi_read_data(self.context()?, buffer.as_mut_ptr(), buffer.len() as c_int)
+        };
+        if read_count < 0 {
+            debug!("Read Error!");
+            return Err(self.ftdi_error("ftdi_read_data", read_count));
+        }
+
+        Ok(read_count as usize)
+    }
+}
+
+/// Lists every connected device matching one of the given vendor and product IDs.
+pub fn list_devices_with_ids(ids: &[(u16, u16)]) -> Result<Vec<DeviceInfo>, ArrangeError> {
+    let context = unsafe { ftdi_new() };
+    if context.is_null() {
+        return Err(ArrangeError::Ftdi {
+            operation: "ftdi_new",
+            code: 0,
+            message: "could not allocate ftdi context".to_string(),
+        });
+    }
+
+    let mut devices = vec![];
+    let mut result = Ok(());
+    for &(vendor, product) in ids {
+        let mut list: *mut ftdi_device_list = ptr::null_mut();
+        let count =
+            unsafe { ftdi_usb_find_all(context, &mut list, vendor as c_int, product as c_int) };
+        debug!("Found {count} devices with ID {vendor:04x}:{product:04x}");
+
+        if count < 0 {
+            let message = unsafe { CStr::from_ptr(ftdi_get_error_string(context)) }
+                .to_string_lossy()
+                .into_owned();
+            result = Err(ArrangeError::Ftdi {
+                operation: "ftdi_usb_find_all",
+                code: count,
+                message,
+            });
+            break;
+        }
+
+        let mut node = list;
+        while let Some(entry) = unsafe { node.as_ref() } {
+            devices.push(unsafe { describe(context, entry.dev) });
+            node = entry.next;
+        }
+
+        unsafe { ftdi_list_free(&mut list) };
+    }
+
+    unsafe { ftdi_free(context) };
+    result.map(|_| devices)
+}
+
+/// Reads the descriptors of a single device.
+///
+/// The string descriptors require opening the device, so they are left empty when we do not
+/// have permission to do so.
+unsafe fn describe(context: *mut ftdi_context, dev: *mut libusb_device) -> DeviceInfo {
+    let mut descriptor = MaybeUninit::zeroed();
+    libusb_get_device_descriptor(dev, descriptor.as_mut_ptr());
+    let descriptor = descriptor.assume_init();
+
+    let mut config: *const libusb_config_descriptor = ptr::null();
+    let interfaces = if libusb_get_config_descriptor(dev, 0, &mut config) == 0 {
+        let interfaces = (*config).bNumInterfaces;
+        libusb_free_config_descriptor(config);
+        interfaces
+    } else {
+        0
+    };
+
+    let mut manufacturer = [0 as c_char; 128];
+    let mut product = [0 as c_char; 128];
+    let mut serial = [0 as c_char; 128];
+    let strings_status = ftdi_usb_get_strings(
+        context,
+        dev,
+        manufacturer.as_mut_ptr(),
+        manufacturer.len() as c_int,
+        product.as_mut_ptr(),
+        product.len() as c_int,
+        serial.as_mut_ptr(),
+        serial.len() as c_int,
+    );
+
+    let string = |buffer: &[c_char]| {
+        if strings_status == 0 {
+            Some(
+                CStr::from_ptr(buffer.as_ptr())
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        } else {
+            None
+        }
+    };
+
+    DeviceInfo {
+        vendor_id: descriptor.idVendor,
+        product_id: descriptor.idProduct,
+        manufacturer: string(&manufacturer),
+        product: string(&product),
+        serial: string(&serial),
+        bus: libusb_get_bus_number(dev),
+        address: libusb_get_device_address(dev),
+        interfaces,
+    }
+}
@@ -1,4 +1,28 @@
 pub mod flash;
 pub mod mpsse;
+pub mod bitstream;
 pub mod block_erase;
+pub mod board;
+pub mod capture;
+pub mod channel;
+pub mod comm;
+pub mod command_buffer;
+pub mod config;
+pub mod device_string;
+pub mod discovery;
+#[cfg(feature = "emulator")]
+pub mod emulator;
+#[cfg(feature = "embedded-hal")]
+pub mod hal;
+#[cfg(feature = "libftdi")]
+pub mod libftdi;
+#[cfg(feature = "nusb")]
+pub mod nusb;
+pub mod packet;
+pub mod pins;
+pub mod registers;
+pub mod retry;
+pub mod spi;
 pub mod test_mode;
+pub mod transport;
+pub mod uart;
@@ -1,16 +1,18 @@
-use std::ffi::{c_int, c_uchar};
+use std::time::{Duration, Instant};
 
 use arrange_misc::error::ArrangeError;
-use libftdi1_sys::{
-    ftdi_context, ftdi_free, ftdi_get_error_string, ftdi_get_latency_timer,
-    ftdi_interface, ftdi_mpsse_mode, ftdi_new, ftdi_read_data, ftdi_set_bitmode,
-    ftdi_set_interface, ftdi_set_latency_timer, ftdi_usb_close, ftdi_usb_open,
-    ftdi_usb_purge_buffers, ftdi_usb_reset, ftdi_write_data,
-};
 use log::{debug, info};
 
+use super::{
+    board::Interface,
+    command_buffer::CommandBuffer,
+    device_string::DeviceString,
+    pins::Pin,
+    transport::{BitMode, DefaultTransport, Transport},
+};
+
 /// Mode commands
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum MPSSECommand {
     ///  Set Data bits Low Byte
@@ -69,290 +71,518 @@ pub enum MPSSECommand {
     CPUWE = 0x93,
 }
 
-/// Encapsulates all of the MPSSE (Multi-Protocol Synchronous Serial Engine) instructions used.
+impl MPSSECommand {
+    const ALL: [MPSSECommand; 27] = [
+        MPSSECommand::SETBLOW,
+        MPSSECommand::READBLOW,
+        MPSSECommand::SETBHIGH,
+        MPSSECommand::READBHIGH,
+        MPSSECommand::LOOPBACKEN,
+        MPSSECommand::LOOPBACKDIS,
+        MPSSECommand::SETCLKDIV,
+        MPSSECommand::FLUSH,
+        MPSSECommand::WAITH,
+        MPSSECommand::WAITL,
+        MPSSECommand::TCKX5,
+        MPSSECommand::TCKD5,
+        MPSSECommand::EN3PHCLK,
+        MPSSECommand::DIS3PHCLK,
+        MPSSECommand::CLKN,
+        MPSSECommand::CLKN8,
+        MPSSECommand::CLKTOH,
+        MPSSECommand::CLKTOL,
+        MPSSECommand::ENADPTCLK,
+        MPSSECommand::DISADPTCLK,
+        MPSSECommand::CLK8TOH,
+        MPSSECommand::CLK8TOL,
+        MPSSECommand::TRI,
+        MPSSECommand::CPURS,
+        MPSSECommand::CPURE,
+        MPSSECommand::CPUWS,
+        MPSSECommand::CPUWE,
+    ];
+
+    /// The command with the given opcode, if we know it. Data shifting commands are not in here,
+    /// their opcodes are made of the `DATA_*` flags of [`MPSSE`].
+    pub fn from_opcode(opcode: u8) -> Option<Self> {
+        MPSSECommand::ALL
+            .into_iter()
+            .find(|command| *command as u8 == opcode)
+    }
+}
 
-pub struct MPSSE<'a> {
-    context: &'a mut ftdi_context,
-    latency: c_uchar,
+/// Encapsulates all of the MPSSE (Multi-Protocol Synchronous Serial Engine) instructions used.
+///
+/// Talks to the chip through a [`Transport`], [`DefaultTransport`] unless told otherwise. The device is closed
+/// by [`MPSSE::close`] or when the `MPSSE` is dropped, whichever comes first.
+pub struct MPSSE<T: Transport = DefaultTransport> {
+    transport: T,
+    open: bool,
+    latency: u8,
     latency_set: bool,
+    frequency: u32,
+    timeout: Duration,
 }
 
-impl<'a> Drop for MPSSE<'a> {
+impl<T: Transport> Drop for MPSSE<T> {
     fn drop(&mut self) {
-        self.close()
+        self.close();
     }
 }
 
-impl<'a> MPSSE<'a> {
-    const FTDI_VENDOR: c_int = 0x0403;
-    const DEVICE_ID_1: c_int = 0x6010;
-    const DEVICE_ID_2: c_int = 0x6014;
-
+impl MPSSE {
     ///  When set use TMS mode
-    const _DATA_TMS: u8 = 0x40;
+    pub(crate) const DATA_TMS: u8 = 0x40;
     ///  When set read data (Data IN)
-    const DATA_IN: u8 = 0x20;
+    pub(crate) const DATA_IN: u8 = 0x20;
     ///  When set write data (Data OUT)
-    const DATA_OUT: u8 = 0x10;
+    pub(crate) const DATA_OUT: u8 = 0x10;
     ///  When set input/output data LSB first.
-    const _DATA_LSB: u8 = 0x08;
+    pub(crate) const DATA_LSB: u8 = 0x08;
     ///  When set receive data on negative clock edge
-    const _DATA_ICN: u8 = 0x04;
+    pub(crate) const DATA_ICN: u8 = 0x04;
     ///  When set count bits not bytes
-    const DATA_BITS: u8 = 0x02;
+    pub(crate) const DATA_BITS: u8 = 0x02;
     ///  When set update data on negative clock edge
-    const DATA_OCN: u8 = 0x01;
-
+    pub(crate) const DATA_OCN: u8 = 0x01;
+
+    /// The fastest SPI clock, 60 MHz master clock with the divisor at 0.
+    pub const MAX_FREQUENCY: u32 = 30_000_000;
+    /// The slowest SPI clock, 12 MHz master clock with the divisor at 0xFFFF.
+    pub const MIN_FREQUENCY: u32 = 92;
+    /// The clock `slow_clock` used to select.
+    pub const SLOW_FREQUENCY: u32 = 50_000;
+    /// How long reads wait for the device unless told otherwise.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+    /// The pin `WAITH` and `WAITL` watch, GPIOL1.
+    pub const GPIOL1: Pin = Pin::low(5);
+    /// The most response bytes we let the chip buffer before reading them back.
+    const MAX_PENDING_READ: usize = 4096;
+
+    /// An MPSSE over the default transport, the device itself is opened by [`MPSSE::init`].
     pub fn new() -> Self {
+        Self::with_transport(DefaultTransport::default())
+    }
+}
+
+impl Default for MPSSE {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Transport> MPSSE<T> {
+    /// An MPSSE over `transport`, the device itself is opened by [`MPSSE::init`].
+    pub fn with_transport(transport: T) -> Self {
         Self {
-            context: unsafe { ftdi_new().as_mut().unwrap() },
-            latency: b'0',
+            transport,
+            open: false,
+            latency: 0,
             latency_set: false,
+            frequency: 0,
+            timeout: MPSSE::DEFAULT_TIMEOUT,
         }
     }
 
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
     pub fn init(
         &mut self,
-        interface: ftdi_interface,
-        device_string: Option<String>,
-        slow_clock: bool,
+        interface: Interface,
+        ids: &[(u16, u16)],
+        device_string: Option<&DeviceString>,
+        frequency: u32,
     ) -> Result<(), ArrangeError> {
-        unsafe { ftdi_set_interface(self.context, interface) };
-
-        // Opening the USB connection with the FTDI device.
-        match device_string {
-            Some(_) => todo!("Implement Device String"),
-            None => {
-                if unsafe { ftdi_usb_open(self.context, MPSSE::FTDI_VENDOR, MPSSE::DEVICE_ID_1) } == 0 {
-                    // First Device ID Failed.
-                    debug!(
-                        "Status of ftdi_usb_open on Device ID: {:#X} is 0",
-                        MPSSE::DEVICE_ID_1
-                    );
-                } else if unsafe { ftdi_usb_open(self.context, MPSSE::FTDI_VENDOR, MPSSE::DEVICE_ID_2) } == 0 {
-                    // Second Device ID Failed.
-                    debug!(
-                        "Status of ftdi_usb_open on Device ID: {:#X} is 0",
-                        MPSSE::DEVICE_ID_2
-                    );
-                } else {
-                    debug!(
-                        "Can't find iCE FTDI USB Device (Vendor ID: {:#X} with Device IDs {:#X} or {:#x})",
-                        MPSSE::FTDI_VENDOR,
-                        MPSSE::DEVICE_ID_1,
-                        MPSSE::DEVICE_ID_2,
-                    );
-
-                    return Err(ArrangeError::DeviceError);
-                }
-            }
-        }
+        // Re-initializing starts from a closed device.
+        self.close();
+        self.transport.set_timeout(self.timeout);
+        self.transport.open(interface, ids, device_string)?;
+        self.open = true;
 
         // Try to reset the FTDI Chip.
-        let reset_status = unsafe { ftdi_usb_reset(self.context) };
-        debug!("FTDI USB Reset Status: {reset_status}");
-        if reset_status != 0 {
-            debug!("Failed to reset iCE FTDI USB device.\n");
-            return Err(ArrangeError::DeviceError);
-        }
+        self.transport.reset().inspect_err(|_| {
+            debug!("Failed to reset iCE FTDI USB device.");
+        })?;
 
         // Purge USB Buffers.
-        let purge_status = unsafe { ftdi_usb_purge_buffers(self.context) };
-        debug!("FTDI USB Buffer Purge Status: {reset_status}");
-        if purge_status != 0 {
-            debug!("Failed to purge buffers on iCE FTDI USB device.\n");
-            return Err(ArrangeError::DeviceError);
-        }
+        self.transport.purge().inspect_err(|_| {
+            debug!("Failed to purge buffers on iCE FTDI USB device.");
+        })?;
 
         // Gets the latency.
-        let latency_ptr: *mut c_uchar = &mut self.latency;
-        let get_latency_status = unsafe { ftdi_get_latency_timer(self.context, latency_ptr) };
-        debug!("FTDI USB Get Latency Status: {get_latency_status}");
+        self.latency = self.transport.latency_timer().inspect_err(|error| {
+            debug!("Failed to get latency timer: {error}.");
+        })?;
         debug!("FTDI USB Latency Value: {:#x}", self.latency);
-        if get_latency_status != 0 {
-            debug!("Failed to get latency timer: {:?}.", unsafe {
-                ftdi_get_error_string(self.context)
-            });
-
-            return Err(ArrangeError::DeviceError);
-        }
 
         // Sets the latency to 1 kHz polling.
-        let set_latency_status = unsafe { ftdi_set_latency_timer(self.context, 1) };
-        debug!("FTDI USB Set Latency Status: {set_latency_status}");
-        if set_latency_status != 0 {
-            debug!("Failed to get latency timer: {:?}.", unsafe {
-                ftdi_get_error_string(self.context)
-            });
-            return Err(ArrangeError::DeviceError);
-        }
+        self.transport.set_latency_timer(1).inspect_err(|error| {
+            debug!("Failed to set latency timer: {error}.");
+        })?;
         self.latency_set = true;
 
-        let set_mpsse_mode_status =
-            unsafe { ftdi_set_bitmode(self.context, 0xff, ftdi_mpsse_mode::BITMODE_MPSSE.0 as u8) };
-        debug!("FTDI USB Set MPSSE Mode Status: {set_mpsse_mode_status}");
-        if set_mpsse_mode_status != 0 {
-            debug!("Failed to set MPSSE mode on iCE FTDI USB device.\n");
-            return Err(ArrangeError::DeviceError);
-        }
+        self.transport
+            .set_bitmode(0xff, BitMode::Mpsse)
+            .inspect_err(|_| {
+                debug!("Failed to set MPSSE mode on iCE FTDI USB device.");
+            })?;
 
-        // clock divide by 5.
-        // maybe don't? could potentially be faster...
-        // self.send_byte(MPSSECommand::TCKD5 as u8)?;
-        //
-        // this instead uses the 60 MHz main clock.
-        self.send_byte(MPSSECommand::TCKX5 as u8)?;
-
-        if slow_clock {
-            info!("Setting FTDI USB to Slow Mode: 50 kHz");
-            self.send_byte(MPSSECommand::TCKD5 as u8)?;
-            self.send_byte(MPSSECommand::SETCLKDIV as u8)?;
-            self.send_byte(119)?;
-            self.send_byte(0)?;
-        } else {
-            info!("Setting FTDI USB to Normal Mode");
-            self.send_byte(MPSSECommand::SETCLKDIV as u8)?;
-            self.send_byte(0)?;
-            self.send_byte(0)?;
-        }
+        self.set_frequency(frequency)?;
 
         Ok(())
     }
 
-    /// Blocks while waiting to receive a byte.
-    pub fn recv_byte(&mut self) -> Result<u8, ArrangeError> {
-        let mut data: u8 = 0;
-        let data_ptr: *mut u8 = &mut data;
-        loop {
-            let read_count = unsafe { ftdi_read_data(self.context, data_ptr, 1) };
-            if read_count < 0 {
-                debug!("Read Error!");
-                return Err(ArrangeError::ReadError);
-            }
-
-            if read_count == 1 {
-                break;
-            }
-            //sleep(Duration::from_millis(1));
+    /// Sets the SPI clock to the fastest frequency not above `hz`, returning the frequency
+    /// actually achieved.
+    ///
+    /// The clock is `master / ((1 + divisor) * 2)`, where the master clock is 60 MHz or, when the
+    /// divisor would not fit in 16 bits, 12 MHz with the /5 prescaler enabled.
+    pub fn set_frequency(&mut self, hz: u32) -> Result<u32, ArrangeError> {
+        if !(MPSSE::MIN_FREQUENCY..=MPSSE::MAX_FREQUENCY).contains(&hz) {
+            return Err(ArrangeError::InvalidConfiguration {
+                message: format!(
+                    "SPI frequency {hz} Hz is outside of {} Hz to {} Hz",
+                    MPSSE::MIN_FREQUENCY,
+                    MPSSE::MAX_FREQUENCY
+                ),
+            });
         }
 
-        Ok(data)
+        // Rounding the divisor up keeps us at or below the requested frequency.
+        let divisor = |base: u32| base.div_ceil(hz) - 1;
+        let fast_base = MPSSE::MAX_FREQUENCY;
+        let slow_base = MPSSE::MAX_FREQUENCY / 5;
+
+        let (prescaler, base, divisor) = if divisor(fast_base) <= 0xFFFF {
+            (MPSSECommand::TCKX5, fast_base, divisor(fast_base))
+        } else {
+            (MPSSECommand::TCKD5, slow_base, divisor(slow_base))
+        };
+        let actual = base / (divisor + 1);
+
+        self.send_bytes(&[
+            prescaler as u8,
+            MPSSECommand::SETCLKDIV as u8,
+            divisor as u8,
+            (divisor >> 8) as u8,
+        ])?;
+
+        info!("Setting SPI clock to {actual} Hz (requested {hz} Hz)");
+        self.frequency = actual;
+        Ok(actual)
     }
 
-    // Writes multiple bytes to the FTDI Device.
-    pub fn send_bytes(&mut self, data: &[u8]) -> Result<(), ArrangeError> {
-        let data_len: i32 = data.len() as i32;
-        let data_ptr: *const u8 = data.as_ptr();
-        let write_count = unsafe { ftdi_write_data(self.context, data_ptr, data_len) };
-        if write_count != data_len {
+    /// The SPI clock frequency currently configured.
+    pub fn frequency(&self) -> u32 {
+        self.frequency
+    }
+
+    /// Sets how long [`MPSSE::execute`] and friends wait for a response, and any single USB
+    /// transfer of the transport.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+        self.transport.set_timeout(timeout);
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Turns the number of bytes the transport accepted into an error if not everything was
+    /// written.
+    fn check_write(
+        &mut self,
+        operation: &'static str,
+        expected: usize,
+        write_count: usize,
+    ) -> Result<(), ArrangeError> {
+        if write_count != expected {
             debug!(
                 "Error writing bytes to FTDI. Expected {} bytes to be written, only got {}",
-                data_len, write_count
+                expected, write_count
             );
-            return Err(ArrangeError::WriteError);
+            return Err(ArrangeError::ShortWrite {
+                operation,
+                expected,
+                actual: write_count,
+            });
         }
 
         Ok(())
     }
 
+    /// Waits up to the configured timeout to receive a byte.
+    pub fn recv_byte(&mut self) -> Result<u8, ArrangeError> {
+        let data = self.recv_exact(1, self.timeout)?;
+        Ok(data[0])
+    }
+
+    // Writes multiple bytes to the FTDI Device.
+    pub fn send_bytes(&mut self, data: &[u8]) -> Result<(), ArrangeError> {
+        let write_count = self.transport.write(data)?;
+        self.check_write("ftdi_write_data", data.len(), write_count)
+    }
+
     /// Writes a byte to the FTDI Device.
     pub fn send_byte(&mut self, data: u8) -> Result<(), ArrangeError> {
-        let data_ptr: *const u8 = &data;
-        let write_count = unsafe { ftdi_write_data(self.context, data_ptr, 1) };
-        if write_count != 1 {
-            debug!(
-                "Error writing byte to FTDI. Expected {} bytes to be written, only got {}",
-                1, write_count
-            );
-            return Err(ArrangeError::WriteError);
+        self.send_bytes(&[data])
+    }
+
+    /// Receives exactly `len` bytes, failing with [`ArrangeError::Timeout`] if they have not all
+    /// arrived within `timeout`.
+    pub fn recv_exact(&mut self, len: usize, timeout: Duration) -> Result<Vec<u8>, ArrangeError> {
+        let deadline = Instant::now() + timeout;
+        let mut data = vec![0; len];
+        let mut received = 0;
+        while received < len {
+            received += self.transport.read(&mut data[received..])?;
+            if received < len && Instant::now() >= deadline {
+                debug!("Timed out with {received} of {len} bytes read");
+                return Err(ArrangeError::Timeout {
+                    operation: "ftdi_read_data",
+                    expected: len,
+                    actual: received,
+                    timeout,
+                });
+            }
         }
 
-        Ok(())
+        Ok(data)
     }
 
-    pub fn send_spi(&mut self, data: &[u8]) -> Result<(), ArrangeError> {
-        if data.len() < 1 {
-            return Ok(());
-        }
+    /// Sends every command in `commands` and returns everything they read.
+    ///
+    /// Commands are sent in as few USB transfers as possible. A new transfer is only started
+    /// once the responses still owed by the device would no longer fit in its buffer, so that it
+    /// never stalls waiting for us to read while we are still writing.
+    pub fn execute(&mut self, commands: &CommandBuffer) -> Result<Vec<u8>, ArrangeError> {
+        self.execute_within(commands, self.timeout)
+    }
 
-        self.send_byte(MPSSE::DATA_OUT | MPSSE::DATA_OCN)?;
-        self.send_byte((data.len() - 1) as u8)?;
-        self.send_byte(((data.len() - 1) >> 8) as u8)?;
+    /// [`MPSSE::execute`], waiting up to `timeout` for each batch of responses.
+    fn execute_within(
+        &mut self,
+        commands: &CommandBuffer,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, ArrangeError> {
+        if commands.read_len() == 0 {
+            return self.send_bytes(commands.bytes()).map(|_| vec![]);
+        }
 
-        let data_ptr: *const u8 = data.as_ptr();
-        let write_count = unsafe { ftdi_write_data(self.context, data_ptr, data.len() as c_int) };
-        if write_count != data.len() as i32 {
-            debug!(
-                "Error writing data. Expected {} bytes to be written, only got {}",
-                data.len(),
-                write_count
-            );
+        let mut response = Vec::with_capacity(commands.read_len());
+        let (mut sent, mut read) = (0, 0);
+        let segments = commands.segments();
+        for (i, segment) in segments.iter().enumerate() {
+            let next_read_len = segments.get(i + 1).map(|next| next.read_len);
+            let last = next_read_len.is_none();
+            let pending = segment.read_len - read;
+            let full = next_read_len
+                .is_some_and(|next| pending > 0 && next - read > MPSSE::MAX_PENDING_READ);
+
+            if last || full {
+                let mut bytes = Vec::with_capacity(segment.end - sent + 1);
+                bytes.extend_from_slice(&commands.bytes()[sent..segment.end]);
+                if pending > 0 {
+                    // Ask the chip to send the responses back now rather than when the latency
+                    // timer expires.
+                    bytes.push(MPSSECommand::FLUSH as u8);
+                }
 
-            return Err(ArrangeError::ReadError);
+                self.send_bytes(&bytes)?;
+                response.extend(self.recv_exact(pending, timeout)?);
+                sent = segment.end;
+                read = segment.read_len;
+            }
         }
 
-        Ok(())
+        Ok(response)
+    }
+
+    pub fn send_spi(&mut self, data: &[u8]) -> Result<(), ArrangeError> {
+        self.execute(CommandBuffer::new().spi_write(data)).map(|_| ())
     }
 
     pub fn transfer_spi(&mut self, data: &[u8]) -> Result<Vec<u8>, ArrangeError> {
-        if data.len() < 1 {
-            return Ok(vec![]);
+        self.execute(CommandBuffer::new().spi_transfer(data))
+    }
+
+    pub fn transfer_spi_bits(&mut self, data: u8, n: u8) -> Result<u8, ArrangeError> {
+        let response = self.execute(CommandBuffer::new().spi_transfer_bits(data, n))?;
+        Ok(response[0])
+    }
+
+    pub fn set_gpio(&mut self, gpio: u8, direction: u8) -> Result<(), ArrangeError> {
+        self.execute(CommandBuffer::new().set_gpio(gpio, direction)).map(|_| ())
+    }
+
+    pub fn set_gpio_high(&mut self, gpio: u8, direction: u8) -> Result<(), ArrangeError> {
+        self.execute(CommandBuffer::new().set_gpio_high(gpio, direction)).map(|_| ())
+    }
+
+    pub fn read_low_byte(&mut self) -> Result<u8, ArrangeError> {
+        let response = self.execute(CommandBuffer::new().read_low_byte())?;
+        Ok(response[0])
+    }
+
+    pub fn read_high_byte(&mut self) -> Result<u8, ArrangeError> {
+        let response = self.execute(CommandBuffer::new().read_high_byte())?;
+        Ok(response[0])
+    }
+
+    /// Blocks until something outside drives GPIOL1 (ADBUS5) `high` or low, for up to
+    /// `timeout`. The wait happens in the chip, so the line is not polled over USB.
+    ///
+    /// Fails with [`ArrangeError::Timeout`] if the line did not change in time. The chip is
+    /// still waiting then, so it is [resynchronized](MPSSE::resync), which leaves every GPIO an
+    /// input again.
+    pub fn wait_gpiol1(&mut self, high: bool, timeout: Duration) -> Result<(), ArrangeError> {
+        // The read only comes back once the wait is over.
+        let mut commands = CommandBuffer::new();
+        commands.wait_gpiol1(high).read_low_byte();
+
+        match self.execute_within(&commands, timeout) {
+            Ok(_) => Ok(()),
+            Err(ArrangeError::Timeout { .. }) => {
+                debug!("GPIOL1 did not go {}", if high { "high" } else { "low" });
+                self.resync()?;
+                Err(ArrangeError::Timeout {
+                    operation: "GPIOL1 wait",
+                    expected: 1,
+                    actual: 0,
+                    timeout,
+                })
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Switches the interface to MCU host bus emulation, where it runs the read and write cycles
+    /// of an 8051 style bus on its own instead of clocking serial data. ADBUS0 to ADBUS7 carry the
+    /// multiplexed low address byte and data, ACBUS0 to ACBUS7 the high address byte, and the
+    /// strobes are on the remaining pins of the chip, see FTDI's AN_108. The iCEstick and the HX8K
+    /// breakout do not route the bus to the FPGA, so it needs a board that does.
+    ///
+    /// Only the `cpu_*` commands work in this mode, besides the `FLUSH` that [`MPSSE::execute`]
+    /// appends, until [`MPSSE::leave_cpu_mode`].
+    pub fn enter_cpu_mode(&mut self) -> Result<(), ArrangeError> {
+        if !self.open {
+            return Err(ArrangeError::NotOpen {
+                description: "MPSSE interface".to_string(),
+            });
         }
 
-        let intro: [u8; 3] = [
-            MPSSE::DATA_IN | MPSSE::DATA_OUT | MPSSE::DATA_OCN,
-            (data.len() - 1) as u8,
-            ((data.len() - 1) >> 8) as u8,
-        ];
-        self.send_bytes(&intro)?;
+        debug!("Entering MCU host bus emulation...");
+        self.transport.set_bitmode(0xff, BitMode::Mcu)
+    }
 
-        let data_ptr: *const u8 = data.as_ptr();
-        let write_count = unsafe { ftdi_write_data(self.context, data_ptr, data.len() as c_int) };
-        if write_count != data.len() as i32 {
-            debug!(
-                "Error writing data to FTDI. Expected {} bytes to be written, only got {}",
-                data.len(),
-                write_count
-            );
-            return Err(ArrangeError::WriteError);
+    /// Switches back from MCU host bus emulation to MPSSE and restores the clock, if one was set.
+    /// The GPIO directions are reset and need to be set again.
+    pub fn leave_cpu_mode(&mut self) -> Result<(), ArrangeError> {
+        if !self.open {
+            return Err(ArrangeError::NotOpen {
+                description: "MPSSE interface".to_string(),
+            });
         }
 
-        let mut return_vec = vec![];
-        while return_vec.len() < data.len() {
-            return_vec.push(self.recv_byte()?)
+        debug!("Leaving MCU host bus emulation...");
+        self.transport.set_bitmode(0xff, BitMode::Reset)?;
+        self.transport.set_bitmode(0xff, BitMode::Mpsse)?;
+        if self.frequency != 0 {
+            self.set_frequency(self.frequency)?;
         }
+        Ok(())
+    }
 
-        Ok(return_vec)
+    pub fn cpu_read_short(&mut self, address: u8) -> Result<u8, ArrangeError> {
+        let response = self.execute(CommandBuffer::new().cpu_read_short(address))?;
+        Ok(response[0])
     }
 
-    pub fn transfer_spi_bits(&mut self, data: u8, n: u8) -> Result<u8, ArrangeError> {
-        self.send_byte(MPSSE::DATA_IN | MPSSE::DATA_OUT | MPSSE::DATA_OCN | MPSSE::DATA_BITS)?;
-        self.send_byte(n - 1)?;
-        self.send_byte(data)?;
+    pub fn cpu_read_extended(&mut self, address: u16) -> Result<u8, ArrangeError> {
+        let response = self.execute(CommandBuffer::new().cpu_read_extended(address))?;
+        Ok(response[0])
+    }
 
-        self.recv_byte()
+    pub fn cpu_write_short(&mut self, address: u8, data: u8) -> Result<(), ArrangeError> {
+        self.execute(CommandBuffer::new().cpu_write_short(address, data)).map(|_| ())
     }
 
-    pub fn set_gpio(&mut self, gpio: u8, direction: u8) -> Result<(), ArrangeError> {
-        self.send_byte(MPSSECommand::SETBLOW as u8)?;
-        self.send_byte(gpio)?;
-        self.send_byte(direction)
+    pub fn cpu_write_extended(&mut self, address: u16, data: u8) -> Result<(), ArrangeError> {
+        self.execute(CommandBuffer::new().cpu_write_extended(address, data)).map(|_| ())
     }
 
-    pub fn read_low_byte(&mut self) -> Result<u8, ArrangeError> {
-        self.send_byte(MPSSECommand::READBLOW as u8)?;
-        self.recv_byte()
+    /// Checks that `len` bus addresses from `address` fit in the 16 bit address space.
+    fn check_cpu_range(address: u16, len: usize) -> Result<(), ArrangeError> {
+        if address as usize + len > 1 << 16 {
+            return Err(ArrangeError::InvalidConfiguration {
+                message: format!("{len} bytes from {address:#06X} go past the end of the bus"),
+            });
+        }
+
+        Ok(())
     }
 
-    pub fn read_high_byte(&mut self) -> Result<u8, ArrangeError> {
-        self.send_byte(MPSSECommand::READBHIGH as u8)?;
-        self.recv_byte()
+    /// Reads `len` consecutive bus addresses from `address`, batched into as few USB round
+    /// trips as the chip's buffers allow.
+    pub fn cpu_read_block(&mut self, address: u16, len: usize) -> Result<Vec<u8>, ArrangeError> {
+        Self::check_cpu_range(address, len)?;
+
+        let mut commands = CommandBuffer::new();
+        for offset in 0..len {
+            commands.cpu_read_extended(address + offset as u16);
+        }
+        self.execute(&commands)
+    }
+
+    /// Writes `data` to consecutive bus addresses from `address`, batched into as few USB
+    /// round trips as the chip's buffers allow.
+    pub fn cpu_write_block(&mut self, address: u16, data: &[u8]) -> Result<(), ArrangeError> {
+        Self::check_cpu_range(address, data.len())?;
+
+        let mut commands = CommandBuffer::new();
+        for (offset, byte) in data.iter().enumerate() {
+            commands.cpu_write_extended(address + offset as u16, *byte);
+        }
+        self.execute(&commands).map(|_| ())
+    }
+
+    /// Brings the MPSSE engine back to a known state after a failed exchange: drops whatever is
+    /// left in the chip's buffers, including half a command, restarts the engine and restores
+    /// the clock, if one was set.
+    pub fn resync(&mut self) -> Result<(), ArrangeError> {
+        debug!("Resynchronizing MPSSE...");
+        self.transport.purge()?;
+        self.transport.set_bitmode(0xff, BitMode::Reset)?;
+        self.transport.set_bitmode(0xff, BitMode::Mpsse)?;
+        if self.frequency != 0 {
+            self.set_frequency(self.frequency)?;
+        }
+        Ok(())
+    }
+
+    /// Whether [`MPSSE::init`] opened a device that has not been closed since.
+    pub fn is_open(&self) -> bool {
+        self.open
     }
 
-    /// This closes our FTDI context.
-    pub fn close(&mut self) -> () {
-        unsafe { ftdi_set_latency_timer(self.context, self.latency) };
-        unsafe { ftdi_usb_close(self.context) };
-        unsafe { ftdi_free(self.context) };
+    /// Restores the latency timer and closes the device. Closing more than once does nothing.
+    pub fn close(&mut self) {
+        if !self.open {
+            return;
+        }
+
+        if self.latency_set {
+            // Best effort, the device may already be gone.
+            let _ = self.transport.set_latency_timer(self.latency);
+            self.latency_set = false;
+        }
+        self.transport.close();
+        self.open = false;
     }
 }
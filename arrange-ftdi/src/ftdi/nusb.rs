@@ -0,0 +1,382 @@
+use std::{fmt::Display, time::Duration};
+
+use ::nusb::{
+    transfer::{Buffer, Bulk, ControlIn, ControlOut, ControlType, In, Out, Recipient},
+    Endpoint, MaybeFuture,
+};
+use arrange_misc::error::ArrangeError;
+use log::debug;
+
+use super::{
+    board::Interface,
+    device_string::DeviceString,
+    discovery::DeviceInfo,
+    transport::{BitMode, Transport},
+    uart::{self, DataBits, FlowControl, Parity, StopBits},
+};
+
+/// A [`Transport`] that speaks the FTDI USB protocol itself, in pure Rust, on top of `nusb`.
+///
+/// Behaves like the libftdi backend without needing libftdi, libusb or a C toolchain.
+pub struct Nusb {
+    device: Option<OpenDevice>,
+    /// How long a USB transfer may take.
+    timeout: Duration,
+}
+
+impl Default for Nusb {
+    fn default() -> Self {
+        Self {
+            device: None,
+            timeout: Nusb::USB_TIMEOUT,
+        }
+    }
+}
+
+/// A claimed FTDI interface and its bulk endpoints.
+struct OpenDevice {
+    interface: ::nusb::Interface,
+    /// The interface number the FTDI control requests address, A is 1.
+    index: u16,
+    writer: Endpoint<Bulk, Out>,
+    reader: Endpoint<Bulk, In>,
+    /// Data already received but not yet returned by [`Transport::read`].
+    pending: Vec<u8>,
+}
+
+/// Builds an [`ArrangeError::Ftdi`] from a USB error.
+fn usb_error(operation: &'static str, error: impl Display) -> ArrangeError {
+    ArrangeError::Ftdi {
+        operation,
+        code: -1,
+        message: error.to_string(),
+    }
+}
+
+/// Lists every connected device matching one of the given vendor and product IDs.
+pub fn list_devices_with_ids(ids: &[(u16, u16)]) -> Result<Vec<DeviceInfo>, ArrangeError> {
+    let devices = ::nusb::list_devices()
+        .wait()
+        .map_err(|error| usb_error("list_devices", error))?;
+
+    Ok(devices
+        .filter(|device| ids.contains(&(device.vendor_id(), device.product_id())))
+        .map(|device| DeviceInfo {
+            vendor_id: device.vendor_id(),
+            product_id: device.product_id(),
+            manufacturer: device.manufacturer_string().map(str::to_string),
+            product: device.product_string().map(str::to_string),
+            serial: device.serial_number().map(str::to_string),
+            bus: device.bus_id().parse().unwrap_or(0),
+            address: device.device_address(),
+            interfaces: device.interfaces().count() as u8,
+        })
+        .collect())
+}
+
+impl Nusb {
+    /// FTDI vendor requests, see libftdi's `ftdi.h`.
+    const SIO_RESET: u8 = 0x00;
+    const SIO_SET_FLOW_CTRL: u8 = 0x02;
+    const SIO_SET_BAUDRATE: u8 = 0x03;
+    const SIO_SET_DATA: u8 = 0x04;
+    const SIO_SET_LATENCY_TIMER: u8 = 0x09;
+    const SIO_GET_LATENCY_TIMER: u8 = 0x0A;
+    const SIO_SET_BITMODE: u8 = 0x0B;
+
+    /// `SIO_RESET` values.
+    const SIO_RESET_SIO: u16 = 0;
+    const SIO_RESET_PURGE_RX: u16 = 1;
+    const SIO_RESET_PURGE_TX: u16 = 2;
+
+    /// `SIO_SET_FLOW_CTRL` handshakes, in the high byte of the index.
+    const SIO_DISABLE_FLOW_CTRL: u16 = 0x0000;
+    const SIO_RTS_CTS_HS: u16 = 0x0100;
+    const SIO_DTR_DSR_HS: u16 = 0x0200;
+    const SIO_XON_XOFF_HS: u16 = 0x0400;
+
+    /// How long a USB transfer may take until told otherwise, the same as libftdi's default.
+    const USB_TIMEOUT: Duration = Duration::from_millis(5000);
+    /// Size of the USB bulk reads, like libftdi's read chunksize.
+    const READ_CHUNK_SIZE: usize = 64 * 1024;
+    /// Every packet the chip sends starts with two modem status bytes.
+    const STATUS_LEN: usize = 2;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn device(&mut self) -> Result<&mut OpenDevice, ArrangeError> {
+        self.device.as_mut().ok_or(ArrangeError::NotOpen {
+            description: "USB device".to_string(),
+        })
+    }
+
+    /// Finds the device `open` was asked for.
+    fn find(
+        ids: &[(u16, u16)],
+        device_string: Option<&DeviceString>,
+    ) -> Result<::nusb::DeviceInfo, ArrangeError> {
+        let devices: Vec<_> = ::nusb::list_devices()
+            .wait()
+            .map_err(|error| usb_error("list_devices", error))?
+            .collect();
+        let matches = |device: &&::nusb::DeviceInfo, vendor: u16, product: u16| {
+            device.vendor_id() == vendor && device.product_id() == product
+        };
+
+        let found = match device_string {
+            Some(DeviceString::BusDevice { bus, device }) => devices.iter().find(|info| {
+                info.bus_id().parse() == Ok(*bus) && info.device_address() == *device
+            }),
+            Some(DeviceString::VendorProduct {
+                vendor,
+                product,
+                index,
+            }) => devices
+                .iter()
+                .filter(|info| matches(info, *vendor, *product))
+                .nth(*index as usize),
+            Some(DeviceString::Serial {
+                vendor,
+                product,
+                serial,
+            }) => devices.iter().find(|info| {
+                matches(info, *vendor, *product) && info.serial_number() == Some(serial.as_str())
+            }),
+            None => ids.iter().find_map(|(vendor, product)| {
+                devices.iter().find(|info| matches(info, *vendor, *product))
+            }),
+        };
+
+        found.cloned().ok_or_else(|| {
+            let description = match device_string {
+                Some(device_string) => format!("{device_string}: device not found"),
+                None => {
+                    let ids = ids
+                        .iter()
+                        .map(|(vendor, product)| format!("{vendor:04x}:{product:04x}"))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    format!("no device with IDs {ids}")
+                }
+            };
+            debug!("Can't find iCE FTDI USB Device ({description})");
+            ArrangeError::DeviceNotFound { description }
+        })
+    }
+
+    fn control_out(
+        &mut self,
+        operation: &'static str,
+        request: u8,
+        value: u16,
+    ) -> Result<(), ArrangeError> {
+        self.control_out_with_index(operation, request, value, 0)
+    }
+
+    /// Like [`Nusb::control_out`], with `index_high` in the high byte of the index, next to the
+    /// interface number.
+    fn control_out_with_index(
+        &mut self,
+        operation: &'static str,
+        request: u8,
+        value: u16,
+        index_high: u16,
+    ) -> Result<(), ArrangeError> {
+        let timeout = self.timeout;
+        let device = self.device()?;
+        let result = device
+            .interface
+            .control_out(
+                ControlOut {
+                    control_type: ControlType::Vendor,
+                    recipient: Recipient::Device,
+                    request,
+                    value,
+                    index: index_high | device.index,
+                    data: &[],
+                },
+                timeout,
+            )
+            .wait();
+        debug!("Status of {operation}: {result:?}");
+        result.map_err(|error| usb_error(operation, error))
+    }
+}
+
+impl Transport for Nusb {
+    fn open(
+        &mut self,
+        interface: Interface,
+        ids: &[(u16, u16)],
+        device_string: Option<&DeviceString>,
+    ) -> Result<(), ArrangeError> {
+        self.close();
+        let info = Nusb::find(ids, device_string)?;
+        let device = info.open().wait().map_err(|error| usb_error("open", error))?;
+
+        // Interface A is USB interface 0 with endpoints 0x81 and 0x02, B is 1 with 0x83 and 0x04...
+        let number = interface as u8;
+        let claimed = device
+            .detach_and_claim_interface(number)
+            .wait()
+            .map_err(|error| usb_error("claim_interface", error))?;
+        let reader = claimed
+            .endpoint::<Bulk, In>(0x81 + 2 * number)
+            .map_err(|error| usb_error("endpoint", error))?;
+        let writer = claimed
+            .endpoint::<Bulk, Out>(0x02 + 2 * number)
+            .map_err(|error| usb_error("endpoint", error))?;
+
+        self.device = Some(OpenDevice {
+            interface: claimed,
+            index: number as u16 + 1,
+            writer,
+            reader,
+            pending: vec![],
+        });
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        // Dropping the interface releases it.
+        self.device = None;
+    }
+
+    fn reset(&mut self) -> Result<(), ArrangeError> {
+        self.control_out("reset", Nusb::SIO_RESET, Nusb::SIO_RESET_SIO)
+    }
+
+    fn purge(&mut self) -> Result<(), ArrangeError> {
+        self.device()?.pending.clear();
+        self.control_out("purge rx", Nusb::SIO_RESET, Nusb::SIO_RESET_PURGE_RX)?;
+        self.control_out("purge tx", Nusb::SIO_RESET, Nusb::SIO_RESET_PURGE_TX)
+    }
+
+    fn latency_timer(&mut self) -> Result<u8, ArrangeError> {
+        let timeout = self.timeout;
+        let device = self.device()?;
+        let response = device
+            .interface
+            .control_in(
+                ControlIn {
+                    control_type: ControlType::Vendor,
+                    recipient: Recipient::Device,
+                    request: Nusb::SIO_GET_LATENCY_TIMER,
+                    value: 0,
+                    index: device.index,
+                    length: 1,
+                },
+                timeout,
+            )
+            .wait()
+            .map_err(|error| usb_error("get latency timer", error))?;
+
+        response
+            .first()
+            .copied()
+            .ok_or(ArrangeError::ShortRead {
+                operation: "get latency timer",
+                expected: 1,
+                actual: 0,
+            })
+    }
+
+    fn set_latency_timer(&mut self, latency: u8) -> Result<(), ArrangeError> {
+        self.control_out(
+            "set latency timer",
+            Nusb::SIO_SET_LATENCY_TIMER,
+            latency as u16,
+        )
+    }
+
+    fn set_bitmode(&mut self, mask: u8, mode: BitMode) -> Result<(), ArrangeError> {
+        self.control_out(
+            "set bitmode",
+            Nusb::SIO_SET_BITMODE,
+            (mode as u16) << 8 | mask as u16,
+        )
+    }
+
+    fn set_baud_rate(&mut self, baud: u32) -> Result<u32, ArrangeError> {
+        let (divisor, actual) = uart::baud_divisor(baud)?;
+        self.control_out_with_index(
+            "set baud rate",
+            Nusb::SIO_SET_BAUDRATE,
+            divisor as u16,
+            ((divisor >> 8) & 0xFF00) as u16,
+        )?;
+
+        Ok(actual)
+    }
+
+    fn set_line_properties(
+        &mut self,
+        data_bits: DataBits,
+        stop_bits: StopBits,
+        parity: Parity,
+    ) -> Result<(), ArrangeError> {
+        self.control_out(
+            "set line properties",
+            Nusb::SIO_SET_DATA,
+            data_bits as u16 | (parity as u16) << 8 | (stop_bits as u16) << 11,
+        )
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> Result<(), ArrangeError> {
+        let (value, handshake) = match flow_control {
+            FlowControl::None => (0, Nusb::SIO_DISABLE_FLOW_CTRL),
+            FlowControl::RtsCts => (0, Nusb::SIO_RTS_CTS_HS),
+            FlowControl::DtrDsr => (0, Nusb::SIO_DTR_DSR_HS),
+            FlowControl::XonXoff => (
+                FlowControl::XON as u16 | (FlowControl::XOFF as u16) << 8,
+                Nusb::SIO_XON_XOFF_HS,
+            ),
+        };
+        self.control_out_with_index("set flow control", Nusb::SIO_SET_FLOW_CTRL, value, handshake)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, ArrangeError> {
+        let timeout = self.timeout;
+        let device = self.device()?;
+        let completion = device
+            .writer
+            .transfer_blocking(Buffer::from(data), timeout);
+
+        match completion.status {
+            Ok(()) => Ok(completion.actual_len),
+            // A partial write is reported as such by the caller.
+            Err(_) if completion.actual_len > 0 => Ok(completion.actual_len),
+            Err(error) => Err(usb_error("bulk write", error)),
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ArrangeError> {
+        let timeout = self.timeout;
+        let device = self.device()?;
+        if device.pending.is_empty() {
+            let request = device.reader.allocate(Nusb::READ_CHUNK_SIZE);
+            let completion = device.reader.transfer_blocking(request, timeout);
+            if let Err(error) = completion.status {
+                return Err(usb_error("bulk read", error));
+            }
+
+            let packet_size = device.reader.max_packet_size();
+            for packet in completion.buffer[..completion.actual_len].chunks(packet_size) {
+                device
+                    .pending
+                    .extend_from_slice(packet.get(Nusb::STATUS_LEN..).unwrap_or_default());
+            }
+        }
+
+        let count = buffer.len().min(device.pending.len());
+        buffer[..count].copy_from_slice(&device.pending[..count]);
+        device.pending.drain(..count);
+        Ok(count)
+    }
+}
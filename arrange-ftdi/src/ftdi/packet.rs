@@ -0,0 +1,437 @@
+//! Reliable messages over a byte stream such as the comm [`Channel`](super::channel::Channel),
+//! for request and response exchanges with accelerators over a link that may drop or corrupt
+//! bytes.
+//!
+//! # Frames
+//!
+//! | Byte     | Field                                                  |
+//! |----------|--------------------------------------------------------|
+//! | 0        | [`Frame::SYNC`], `A5`                                  |
+//! | 1        | Kind: `01` DATA, `02` ACK, `03` NAK, `04` RESET        |
+//! | 2        | Sequence number                                        |
+//! | 3, 4     | Payload length `N`, little endian, at most [`Frame::MAX_PAYLOAD`] |
+//! | 5 ..     | `N` payload bytes, DATA only                           |
+//! | 5+N, 6+N | CRC, little endian                                     |
+//!
+//! The CRC is CRC-16/CCITT-FALSE (polynomial `1021`, initial value `FFFF`, no reflection, no
+//! final XOR) over the kind, sequence number, length and payload, see [`crc16`].
+//!
+//! # Exchange
+//!
+//! Each side sends one DATA frame at a time and waits for the ACK carrying its sequence number
+//! before sending the next, with the sequence number one higher. A DATA frame whose ACK does not
+//! come in time is sent again, so a receiver can get the same frame twice: it acknowledges it
+//! again but delivers it only once. A receiver that gets a corrupt frame answers with a NAK
+//! carrying the sequence number it expects next, which makes the sender resend without waiting
+//! for its timeout. ACKs and NAKs are never acknowledged, a lost one is made up for by the
+//! retransmit.
+//!
+//! The host opens a session with a RESET, acknowledged like a DATA frame, after which both
+//! sides start again from sequence number 0.
+//!
+//! # The gateware side
+//!
+//! The gateware receives with a state machine, computing the CRC a byte at a time as they come
+//! in:
+//!
+//! 1. HUNT: drop bytes until `A5`.
+//! 2. HEADER: take the kind, sequence number and length. An unknown kind, a length over
+//!    [`Frame::MAX_PAYLOAD`] or a payload on anything but DATA is a corrupt frame.
+//! 3. PAYLOAD: take the payload into a receive buffer of [`Frame::MAX_PAYLOAD`] bytes.
+//! 4. CRC: take the CRC and compare, then go back to HUNT.
+//!
+//! For a good frame:
+//!
+//! - RESET: forget the last sequence number received, set the next one to send to 0, drop any
+//!   unacknowledged response and send an ACK with the RESET's sequence number.
+//! - DATA: send an ACK with its sequence number. Unless that is the sequence number of the last
+//!   DATA frame received, pass the payload on to the accelerator and remember it.
+//! - ACK for the frame waiting in the transmit buffer: free the buffer.
+//! - NAK for the frame waiting in the transmit buffer: send it again.
+//!
+//! For a corrupt frame, send a NAK with the sequence number after the last one received. The
+//! transmit buffer holds the one response frame not acknowledged yet, which is sent again if no
+//! ACK comes in time.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+
+use arrange_misc::error::ArrangeError;
+use log::debug;
+
+use super::channel::from_io;
+
+/// What a [`Frame`] is for, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    Data = 0x01,
+    Ack = 0x02,
+    Nak = 0x03,
+    Reset = 0x04,
+}
+
+impl FrameKind {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x01 => Some(FrameKind::Data),
+            0x02 => Some(FrameKind::Ack),
+            0x03 => Some(FrameKind::Nak),
+            0x04 => Some(FrameKind::Reset),
+            _ => None,
+        }
+    }
+}
+
+/// A frame of the packet protocol, see the [module documentation](self).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub seq: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub const SYNC: u8 = 0xA5;
+    /// The sync byte, kind, sequence number and length.
+    pub const HEADER_LEN: usize = 5;
+    /// The bytes a frame adds to its payload.
+    pub const OVERHEAD: usize = Self::HEADER_LEN + 2;
+    /// The most payload bytes a frame carries, the size of the gateware's receive buffer.
+    pub const MAX_PAYLOAD: usize = 1024;
+
+    pub fn data(seq: u8, payload: &[u8]) -> Self {
+        Self {
+            kind: FrameKind::Data,
+            seq,
+            payload: payload.to_vec(),
+        }
+    }
+
+    pub fn ack(seq: u8) -> Self {
+        Self {
+            kind: FrameKind::Ack,
+            seq,
+            payload: vec![],
+        }
+    }
+
+    pub fn nak(seq: u8) -> Self {
+        Self {
+            kind: FrameKind::Nak,
+            seq,
+            payload: vec![],
+        }
+    }
+
+    pub fn reset(seq: u8) -> Self {
+        Self {
+            kind: FrameKind::Reset,
+            seq,
+            payload: vec![],
+        }
+    }
+
+    /// The frame as it goes over the wire.
+    pub fn encode(&self) -> Vec<u8> {
+        let length = self.payload.len() as u16;
+        let mut bytes = Vec::with_capacity(self.payload.len() + Self::OVERHEAD);
+        bytes.extend([Self::SYNC, self.kind as u8, self.seq]);
+        bytes.extend(length.to_le_bytes());
+        bytes.extend(&self.payload);
+        bytes.extend(crc16(&bytes[1..]).to_le_bytes());
+        bytes
+    }
+}
+
+/// CRC-16/CCITT-FALSE, as used by the frames.
+///
+/// ```
+/// # use arrange_ftdi::ftdi::packet::crc16;
+/// assert_eq!(crc16(b"123456789"), 0x29B1);
+/// ```
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, byte| {
+        (0..8).fold(crc ^ (*byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// What [`FrameDecoder::decode`] found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decoded {
+    Frame(Frame),
+    /// Something that started like a frame but had a bad header or CRC.
+    Corrupt,
+}
+
+/// Picks frames out of a byte stream, skipping whatever is not part of one.
+#[derive(Clone, Debug, Default)]
+pub struct FrameDecoder {
+    buffer: VecDeque<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds bytes received from the stream.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend(bytes);
+    }
+
+    /// How many bytes are waiting to be decoded.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Decodes the next frame, or returns `None` if more bytes are needed.
+    pub fn decode(&mut self) -> Option<Decoded> {
+        let start = self.buffer.iter().position(|byte| *byte == Frame::SYNC);
+        self.buffer.drain(..start.unwrap_or(self.buffer.len()));
+        if self.buffer.len() < Frame::HEADER_LEN {
+            return None;
+        }
+
+        // Rule out what cannot be a header before waiting for the rest of a frame.
+        let kind = FrameKind::from_byte(self.buffer[1]);
+        let length = u16::from_le_bytes([self.buffer[3], self.buffer[4]]) as usize;
+        let plausible = match kind {
+            Some(FrameKind::Data) => length <= Frame::MAX_PAYLOAD,
+            Some(_) => length == 0,
+            None => false,
+        };
+        let (Some(kind), true) = (kind, plausible) else {
+            // Hunt for the next sync byte.
+            self.buffer.pop_front();
+            return Some(Decoded::Corrupt);
+        };
+        let end = Frame::HEADER_LEN + length;
+        if self.buffer.len() < end + 2 {
+            return None;
+        }
+
+        let bytes: Vec<u8> = self.buffer.range(1..end + 2).copied().collect();
+        let (contents, crc) = bytes.split_at(end - 1);
+        if crc16(contents) != u16::from_le_bytes([crc[0], crc[1]]) {
+            self.buffer.pop_front();
+            return Some(Decoded::Corrupt);
+        }
+        self.buffer.drain(..end + 2);
+
+        Some(Decoded::Frame(Frame {
+            kind,
+            seq: contents[1],
+            payload: contents[Frame::HEADER_LEN - 1..].to_vec(),
+        }))
+    }
+}
+
+/// Timing of the packet protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketConfig {
+    /// How long to wait for an ACK before sending a frame again.
+    pub ack_timeout: Duration,
+    /// How many times a frame is sent in total before giving up, at least 1.
+    pub attempts: u32,
+    /// How long [`Packets::recv`] waits for a message.
+    pub recv_timeout: Duration,
+}
+
+impl Default for PacketConfig {
+    fn default() -> Self {
+        Self {
+            ack_timeout: Duration::from_millis(50),
+            attempts: 5,
+            recv_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// The host end of the packet protocol, over `stream`.
+///
+/// Reads from `stream` should not block for much longer than the ACK timeout, or lost frames
+/// are noticed late. A read that times out counts as nothing received yet.
+pub struct Packets<S: Read + Write> {
+    stream: S,
+    config: PacketConfig,
+    decoder: FrameDecoder,
+    /// The sequence number of the next DATA frame to send.
+    send_seq: u8,
+    /// The sequence number of the last DATA frame delivered.
+    recv_seq: Option<u8>,
+    /// Messages that arrived while waiting for an ACK.
+    inbox: VecDeque<Vec<u8>>,
+}
+
+impl<S: Read + Write> Packets<S> {
+    /// Opens a session over `stream`, resetting the other end.
+    pub fn connect(stream: S, config: PacketConfig) -> Result<Self, ArrangeError> {
+        let mut packets = Self {
+            stream,
+            config,
+            decoder: FrameDecoder::new(),
+            send_seq: 0,
+            recv_seq: None,
+            inbox: VecDeque::new(),
+        };
+
+        packets.exchange(Frame::reset(0), "packet reset")?;
+        Ok(packets)
+    }
+
+    /// The stream underneath.
+    pub fn stream(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    fn write_frame(&mut self, frame: &Frame) -> Result<(), ArrangeError> {
+        self.stream.write_all(&frame.encode()).map_err(from_io)?;
+        self.stream.flush().map_err(from_io)
+    }
+
+    /// The next frame from the stream, or `None` if nothing came before `deadline`. Answers
+    /// corrupt frames with a NAK.
+    fn next_frame(&mut self, deadline: Instant) -> Result<Option<Frame>, ArrangeError> {
+        let mut buffer = [0; 256];
+        loop {
+            match self.decoder.decode() {
+                Some(Decoded::Frame(frame)) => return Ok(Some(frame)),
+                Some(Decoded::Corrupt) => {
+                    debug!("packet corrupt frame");
+                    let expected = self.recv_seq.map_or(0, |seq| seq.wrapping_add(1));
+                    self.write_frame(&Frame::nak(expected))?;
+                    continue;
+                }
+                None => {}
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    return Err(ArrangeError::UnexpectedResponse {
+                        operation: "packet stream",
+                        message: "the stream ended".to_string(),
+                    })
+                }
+                Ok(count) => self.decoder.push(&buffer[..count]),
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::TimedOut
+                            | io::ErrorKind::WouldBlock
+                            | io::ErrorKind::Interrupted
+                    ) => {}
+                Err(error) => return Err(from_io(error)),
+            }
+        }
+    }
+
+    /// Acknowledges a DATA frame, and keeps its payload unless it was delivered already.
+    fn accept(&mut self, frame: Frame) -> Result<Option<Vec<u8>>, ArrangeError> {
+        self.write_frame(&Frame::ack(frame.seq))?;
+        if self.recv_seq == Some(frame.seq) {
+            debug!("packet duplicate {}", frame.seq);
+            return Ok(None);
+        }
+
+        self.recv_seq = Some(frame.seq);
+        Ok(Some(frame.payload))
+    }
+
+    /// Sends `frame` until it is acknowledged.
+    fn exchange(&mut self, frame: Frame, operation: &'static str) -> Result<(), ArrangeError> {
+        for attempt in 0..self.config.attempts.max(1) {
+            if attempt > 0 {
+                debug!("{operation} {} resent, attempt {}", frame.seq, attempt + 1);
+            }
+            self.write_frame(&frame)?;
+
+            let deadline = Instant::now() + self.config.ack_timeout;
+            while let Some(reply) = self.next_frame(deadline)? {
+                match reply.kind {
+                    FrameKind::Ack if reply.seq == frame.seq => return Ok(()),
+                    FrameKind::Nak if reply.seq == frame.seq => break,
+                    FrameKind::Data => {
+                        if let Some(payload) = self.accept(reply)? {
+                            self.inbox.push_back(payload);
+                        }
+                    }
+                    // Stale, or meant for a host.
+                    _ => {}
+                }
+            }
+        }
+
+        Err(ArrangeError::Timeout {
+            operation,
+            expected: frame.payload.len(),
+            actual: 0,
+            timeout: self.config.ack_timeout * self.config.attempts.max(1),
+        })
+    }
+
+    /// Sends `payload` as one message, returning once the other end has acknowledged it.
+    ///
+    /// Fails with [`ArrangeError::Timeout`] if no acknowledgement came after the configured
+    /// number of attempts, and with [`ArrangeError::InvalidConfiguration`] if `payload` is
+    /// longer than [`Frame::MAX_PAYLOAD`].
+    pub fn send(&mut self, payload: &[u8]) -> Result<(), ArrangeError> {
+        if payload.len() > Frame::MAX_PAYLOAD {
+            return Err(ArrangeError::InvalidConfiguration {
+                message: format!(
+                    "a {} byte message is longer than the {} bytes a frame carries",
+                    payload.len(),
+                    Frame::MAX_PAYLOAD
+                ),
+            });
+        }
+
+        let seq = self.send_seq;
+        self.exchange(Frame::data(seq, payload), "packet send")?;
+        self.send_seq = seq.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Receives the next message.
+    ///
+    /// Fails with [`ArrangeError::Timeout`] if none came within the configured timeout.
+    pub fn recv(&mut self) -> Result<Vec<u8>, ArrangeError> {
+        if let Some(payload) = self.inbox.pop_front() {
+            return Ok(payload);
+        }
+
+        let deadline = Instant::now() + self.config.recv_timeout;
+        while let Some(frame) = self.next_frame(deadline)? {
+            if frame.kind == FrameKind::Data {
+                if let Some(payload) = self.accept(frame)? {
+                    return Ok(payload);
+                }
+            }
+        }
+
+        Err(ArrangeError::Timeout {
+            operation: "packet recv",
+            expected: Frame::OVERHEAD,
+            actual: self.decoder.buffered(),
+            timeout: self.config.recv_timeout,
+        })
+    }
+
+    /// Sends `request` and waits for the response.
+    pub fn request(&mut self, request: &[u8]) -> Result<Vec<u8>, ArrangeError> {
+        self.send(request)?;
+        self.recv()
+    }
+}
@@ -0,0 +1,157 @@
+use core::fmt;
+use std::str::FromStr;
+
+use arrange_misc::error::ArrangeError;
+
+/// The two 8 bit GPIO ports of an MPSSE interface.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PinByte {
+    /// ADBUS / BDBUS, set with `SETBLOW`.
+    Low,
+    /// ACBUS / BCBUS, set with `SETBHIGH`.
+    High,
+}
+
+/// A single MPSSE GPIO pin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pin {
+    pub byte: PinByte,
+    /// Bit number within the byte, 0 to 7.
+    pub bit: u8,
+}
+
+impl Pin {
+    pub const fn low(bit: u8) -> Self {
+        Self {
+            byte: PinByte::Low,
+            bit,
+        }
+    }
+
+    pub const fn high(bit: u8) -> Self {
+        Self {
+            byte: PinByte::High,
+            bit,
+        }
+    }
+
+    /// The bit mask of this pin within its byte.
+    pub fn mask(&self) -> u8 {
+        1 << self.bit
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.byte {
+            PinByte::Low => write!(f, "ADBUS{}", self.bit),
+            PinByte::High => write!(f, "ACBUS{}", self.bit),
+        }
+    }
+}
+
+impl FromStr for Pin {
+    type Err = ArrangeError;
+
+    /// Parses pin names such as `ADBUS4` or `ACBUS3`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (byte, bit) = if let Some(bit) = s.strip_prefix("ADBUS") {
+            (PinByte::Low, bit)
+        } else if let Some(bit) = s.strip_prefix("ACBUS") {
+            (PinByte::High, bit)
+        } else {
+            return Err(ArrangeError::InvalidConfiguration {
+                message: format!("unknown pin '{s}', expected ADBUSn or ACBUSn"),
+            });
+        };
+
+        match bit.parse() {
+            Ok(bit) if bit < 8 => Ok(Self { byte, bit }),
+            _ => Err(ArrangeError::InvalidConfiguration {
+                message: format!("unknown pin '{s}', bit must be 0 to 7"),
+            }),
+        }
+    }
+}
+
+/// Where the SPI and iCE40 configuration signals are wired on the MPSSE GPIO.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PinMap {
+    pub sck: Pin,
+    pub mosi: Pin,
+    pub miso: Pin,
+    /// SPI flash chip select (active low).
+    pub cs: Pin,
+    /// iCE40 CRESET_B (active low).
+    pub creset: Pin,
+    /// iCE40 CDONE.
+    pub cdone: Pin,
+}
+
+impl Default for PinMap {
+    /// The wiring used by the iCEstick, HX8K breakout board and iCEBreaker.
+    fn default() -> Self {
+        Self {
+            sck: Pin::low(0),
+            mosi: Pin::low(1),
+            miso: Pin::low(2),
+            cs: Pin::low(4),
+            creset: Pin::low(7),
+            cdone: Pin::low(6),
+        }
+    }
+}
+
+impl PinMap {
+    fn signals(&self) -> [(&'static str, Pin); 6] {
+        [
+            ("SCK", self.sck),
+            ("MOSI", self.mosi),
+            ("MISO", self.miso),
+            ("CS", self.cs),
+            ("CRESET", self.creset),
+            ("CDONE", self.cdone),
+        ]
+    }
+
+    /// Checks that every signal is on a real pin, that no two signals share a pin and that the
+    /// SPI signals are where the MPSSE engine drives them.
+    pub fn validate(&self) -> Result<(), ArrangeError> {
+        let invalid = |message: String| Err(ArrangeError::InvalidConfiguration { message });
+        let signals = self.signals();
+
+        for (name, pin) in signals.iter() {
+            if pin.bit > 7 {
+                return invalid(format!("{name} is assigned to bit {} of a byte", pin.bit));
+            }
+        }
+
+        // The MPSSE clocks SPI data on fixed pins, they cannot be rerouted.
+        for (name, pin, expected) in [
+            ("SCK", self.sck, Pin::low(0)),
+            ("MOSI", self.mosi, Pin::low(1)),
+            ("MISO", self.miso, Pin::low(2)),
+        ] {
+            if pin != expected {
+                return invalid(format!(
+                    "{name} must be on {expected}, the MPSSE cannot drive it from {pin}"
+                ));
+            }
+        }
+
+        for (i, (name, pin)) in signals.iter().enumerate() {
+            if let Some((other, _)) = signals[i + 1..].iter().find(|(_, other)| other == pin) {
+                return invalid(format!("{name} and {other} are both assigned to {pin}"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether any signal needs the high GPIO byte.
+    pub fn uses_high_byte(&self) -> bool {
+        self.signals()
+            .iter()
+            .any(|(_, pin)| pin.byte == PinByte::High)
+    }
+}
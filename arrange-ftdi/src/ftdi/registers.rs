@@ -0,0 +1,181 @@
+//! Memory mapped registers in the gateware, over a byte stream such as the comm
+//! [`Channel`](super::channel::Channel).
+//!
+//! # Wire format
+//!
+//! Registers are 32 bits wide at byte addresses aligned to 4. Every command starts with an
+//! opcode and the address of its first register, little endian, and covers `N` consecutive
+//! registers, `N` from 1 to [`RegisterBus::MAX_BURST`]. Register values are little endian too.
+//!
+//! | Command | Host sends                            | Gateware answers          |
+//! |---------|---------------------------------------|---------------------------|
+//! | `READ`  | `10 a0 a1 a2 a3 N`                    | `N` values, then a status |
+//! | `WRITE` | `20 a0 a1 a2 a3 N M`, then `N` values | a status                  |
+//!
+//! `M` is the byte mask of a write: when bit `i` is set byte `i` of every register is written,
+//! when it is clear that byte keeps its value. The status is `50` when every address was a
+//! register, `51` when some were not: reading those gives 0 and writing them does nothing.
+//!
+//! The gateware answers every command, in order, and the host waits for the answer before sending
+//! the next, so neither side needs more buffering than one command. `arrange-demo` has a bridge
+//! in Verilog, `reg_bridge.v`.
+
+use std::io::{Read, Write};
+
+use arrange_misc::error::ArrangeError;
+use log::debug;
+
+use super::channel::from_io;
+
+/// The command bytes of the register protocol, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RegisterCommand {
+    Read = 0x10,
+    Write = 0x20,
+}
+
+/// The status byte closing every answer, see the [module documentation](self).
+pub const STATUS_OK: u8 = 0x50;
+/// Set in the status when some address was not a register.
+pub const STATUS_UNMAPPED: u8 = 0x01;
+/// The byte mask that writes every byte of a register.
+pub const ALL_BYTES: u8 = 0x0F;
+
+/// The register file of the gateware, over `stream`.
+pub struct RegisterBus<S: Read + Write> {
+    stream: S,
+}
+
+impl<S: Read + Write> RegisterBus<S> {
+    /// The most registers a single command covers.
+    pub const MAX_BURST: usize = 0xFF;
+
+    pub fn new(stream: S) -> Self {
+        Self { stream }
+    }
+
+    /// The stream underneath.
+    pub fn stream(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Checks that `count` registers from `address` are aligned and within the address space.
+    fn check(address: u32, count: usize) -> Result<(), ArrangeError> {
+        let end = address as u64 + count as u64 * 4;
+        if address & 0x3 != 0 || end > 1 << 32 {
+            return Err(ArrangeError::InvalidConfiguration {
+                message: format!(
+                    "{count} registers from {address:#010X} are not aligned to 4 bytes or go past \
+                     the end of the address space"
+                ),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Reads the status that closes an answer.
+    fn status(
+        &mut self,
+        operation: &'static str,
+        address: u32,
+        count: usize,
+    ) -> Result<(), ArrangeError> {
+        let mut status = [0];
+        self.stream.read_exact(&mut status).map_err(from_io)?;
+        let [status] = status;
+
+        if status & !STATUS_UNMAPPED != STATUS_OK {
+            return Err(ArrangeError::UnexpectedResponse {
+                operation,
+                message: format!("status {status:#04X}, the gateware is out of step"),
+            });
+        }
+        if status & STATUS_UNMAPPED != 0 {
+            let last = address + (count as u32 - 1) * 4;
+            return Err(ArrangeError::UnexpectedResponse {
+                operation,
+                message: format!("no register somewhere in {address:#010X} to {last:#010X}"),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Reads `count` consecutive registers from `address`, in as many commands as needed.
+    pub fn read_burst(&mut self, address: u32, count: usize) -> Result<Vec<u32>, ArrangeError> {
+        Self::check(address, count)?;
+
+        let mut values = Vec::with_capacity(count);
+        while values.len() < count {
+            let start = address + values.len() as u32 * 4;
+            let chunk = (count - values.len()).min(Self::MAX_BURST);
+            debug!("register read {chunk} from {start:#010X}");
+
+            let mut command = vec![RegisterCommand::Read as u8];
+            command.extend(start.to_le_bytes());
+            command.push(chunk as u8);
+            self.stream.write_all(&command).map_err(from_io)?;
+
+            let mut bytes = vec![0; chunk * 4];
+            self.stream.read_exact(&mut bytes).map_err(from_io)?;
+            self.status("register read", start, chunk)?;
+            values.extend(
+                bytes
+                    .chunks_exact(4)
+                    .map(|value| u32::from_le_bytes([value[0], value[1], value[2], value[3]])),
+            );
+        }
+
+        Ok(values)
+    }
+
+    /// Writes the bytes of `values` selected by `mask` to consecutive registers from `address`,
+    /// in as many commands as needed.
+    pub fn write_burst_masked(
+        &mut self,
+        address: u32,
+        values: &[u32],
+        mask: u8,
+    ) -> Result<(), ArrangeError> {
+        Self::check(address, values.len())?;
+
+        for (index, chunk) in values.chunks(Self::MAX_BURST).enumerate() {
+            let start = address + (index * Self::MAX_BURST) as u32 * 4;
+            debug!("register write {} from {start:#010X}, mask {mask:#03X}", chunk.len());
+
+            let mut command = vec![RegisterCommand::Write as u8];
+            command.extend(start.to_le_bytes());
+            command.extend([chunk.len() as u8, mask & ALL_BYTES]);
+            command.extend(chunk.iter().flat_map(|value| value.to_le_bytes()));
+            self.stream.write_all(&command).map_err(from_io)?;
+            self.status("register write", start, chunk.len())?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `values` to consecutive registers from `address`.
+    pub fn write_burst(&mut self, address: u32, values: &[u32]) -> Result<(), ArrangeError> {
+        self.write_burst_masked(address, values, ALL_BYTES)
+    }
+
+    pub fn read32(&mut self, address: u32) -> Result<u32, ArrangeError> {
+        Ok(self.read_burst(address, 1)?[0])
+    }
+
+    pub fn write32(&mut self, address: u32, value: u32) -> Result<(), ArrangeError> {
+        self.write_burst(address, &[value])
+    }
+
+    /// Writes only the bytes of `value` selected by `mask`, bit `i` for byte `i`.
+    pub fn write32_masked(
+        &mut self,
+        address: u32,
+        value: u32,
+        mask: u8,
+    ) -> Result<(), ArrangeError> {
+        self.write_burst_masked(address, &[value], mask)
+    }
+}
@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+/// How hard to try before giving up on the flash.
+///
+/// [`ArrangeFTDI::burn`](crate::ArrangeFTDI) starts over after a transient failure (see
+/// [`ArrangeError::is_transient`](arrange_misc::error::ArrangeError::is_transient)) and
+/// [`Flash::wait`](super::flash::Flash::wait) retries status reads that went wrong.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times an operation is attempted in total, at least 1.
+    pub attempts: u32,
+    /// How long to pause before the next attempt.
+    pub backoff: Duration,
+    /// How long [`Flash::wait`](super::flash::Flash::wait) polls a busy flash before giving up.
+    pub busy_timeout: Duration,
+}
+
+impl RetryPolicy {
+    /// Gives up on the first failure.
+    pub fn never() -> Self {
+        Self {
+            attempts: 1,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_millis(10),
+            // A chip erase of a large flash takes minutes.
+            busy_timeout: Duration::from_secs(300),
+        }
+    }
+}
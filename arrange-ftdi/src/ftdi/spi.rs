@@ -0,0 +1,281 @@
+//! A general purpose SPI master on an MPSSE interface, for SPI slaves in the gateware and other
+//! SPI peripherals on the board.
+//!
+//! SCK, MOSI and MISO are on the MPSSE's fixed pins, ADBUS0 to ADBUS2. Any other pin can be the
+//! (active low) chip select of a device, so several devices can share the bus:
+//!
+//! ```no_run
+//! use arrange_ftdi::{
+//!     ftdi::{
+//!         pins::Pin,
+//!         spi::{SpiConfig, SpiMode},
+//!     },
+//!     ArrangeFTDI,
+//! };
+//! use arrange_misc::traits::Arrange;
+//!
+//! let mut arrange = ArrangeFTDI::with_config(Default::default());
+//! arrange.init().unwrap();
+//! let mut spi = arrange
+//!     .spi(SpiConfig {
+//!         mode: SpiMode::Mode3,
+//!         cs: vec![Pin::low(3), Pin::high(1)],
+//!         ..Default::default()
+//!     })
+//!     .unwrap();
+//! let id = spi.write_read(1, &[0x0F], 2).unwrap();
+//! ```
+
+use arrange_misc::error::ArrangeError;
+use log::debug;
+
+use super::{
+    command_buffer::CommandBuffer,
+    mpsse::MPSSE,
+    pins::{Pin, PinByte},
+    transport::{DefaultTransport, Transport},
+};
+
+/// The clock polarity (CPOL) and phase (CPHA) of an SPI bus.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpiMode {
+    /// SCK idles low, data is sampled on the rising edge.
+    #[default]
+    Mode0,
+    /// SCK idles low, data is sampled on the falling edge.
+    Mode1,
+    /// SCK idles high, data is sampled on the falling edge.
+    Mode2,
+    /// SCK idles high, data is sampled on the rising edge.
+    Mode3,
+}
+
+impl SpiMode {
+    /// The level SCK idles at.
+    pub fn idle_high(&self) -> bool {
+        matches!(self, SpiMode::Mode2 | SpiMode::Mode3)
+    }
+
+    /// Whether data is sampled on the falling edge of SCK, and so changes on the rising edge.
+    fn samples_on_falling_edge(&self) -> bool {
+        matches!(self, SpiMode::Mode1 | SpiMode::Mode2)
+    }
+}
+
+/// Which bit of each byte goes over the wire first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BitOrder {
+    #[default]
+    MsbFirst,
+    LsbFirst,
+}
+
+/// How SPI data is clocked, see [`CommandBuffer::spi_format`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpiFormat {
+    pub mode: SpiMode,
+    pub bit_order: BitOrder,
+}
+
+impl SpiFormat {
+    /// The edge and bit order flags of the MPSSE data shifting commands.
+    pub(crate) fn data_flags(&self) -> u8 {
+        let edges = if self.mode.samples_on_falling_edge() {
+            MPSSE::DATA_ICN
+        } else {
+            MPSSE::DATA_OCN
+        };
+        let order = match self.bit_order {
+            BitOrder::MsbFirst => 0,
+            BitOrder::LsbFirst => MPSSE::DATA_LSB,
+        };
+
+        edges | order
+    }
+}
+
+/// The bus and the devices on it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpiConfig {
+    pub mode: SpiMode,
+    pub bit_order: BitOrder,
+    /// SCK frequency in Hz.
+    pub frequency: u32,
+    /// The chip select (active low) of each device, devices are numbered in this order.
+    pub cs: Vec<Pin>,
+}
+
+impl Default for SpiConfig {
+    /// A single device in mode 0 with CS on ADBUS3, at 6 MHz.
+    fn default() -> Self {
+        Self {
+            mode: SpiMode::default(),
+            bit_order: BitOrder::default(),
+            frequency: 6_000_000,
+            cs: vec![Pin::low(3)],
+        }
+    }
+}
+
+impl SpiConfig {
+    /// The mode and bit order.
+    pub fn format(&self) -> SpiFormat {
+        SpiFormat {
+            mode: self.mode,
+            bit_order: self.bit_order,
+        }
+    }
+
+    /// Checks the frequency, and that every CS is on a pin of its own that the MPSSE does not
+    /// need for SPI.
+    pub fn validate(&self) -> Result<(), ArrangeError> {
+        let invalid = |message: String| Err(ArrangeError::InvalidConfiguration { message });
+
+        if !(MPSSE::MIN_FREQUENCY..=MPSSE::MAX_FREQUENCY).contains(&self.frequency) {
+            return invalid(format!(
+                "SPI frequency {} Hz is outside of {} Hz to {} Hz",
+                self.frequency,
+                MPSSE::MIN_FREQUENCY,
+                MPSSE::MAX_FREQUENCY
+            ));
+        }
+        if self.cs.is_empty() {
+            return invalid("an SPI bus needs at least one chip select".to_string());
+        }
+
+        for (device, cs) in self.cs.iter().enumerate() {
+            if cs.bit > 7 || (cs.byte == PinByte::Low && cs.bit < 3) {
+                return invalid(format!(
+                    "CS of device {device} cannot be on {cs}, ADBUS0 to ADBUS2 carry SPI"
+                ));
+            }
+            if let Some(other) = self.cs[device + 1..].iter().position(|other| other == cs) {
+                let other = device + 1 + other;
+                return invalid(format!("devices {device} and {other} share CS on {cs}"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn uses_high_byte(&self) -> bool {
+        self.cs.iter().any(|cs| cs.byte == PinByte::High)
+    }
+}
+
+/// An SPI master driving the bus of `mpsse`.
+///
+/// It owns the GPIO of the interface: every pin that is neither SPI nor a chip select is an
+/// input while it is in use.
+pub struct SpiMaster<'a, T: Transport = DefaultTransport> {
+    mpsse: &'a mut MPSSE<T>,
+    config: SpiConfig,
+}
+
+impl<'a, T: Transport> SpiMaster<'a, T> {
+    /// Sets the clock and deselects every device.
+    pub fn new(mpsse: &'a mut MPSSE<T>, config: SpiConfig) -> Result<Self, ArrangeError> {
+        config.validate()?;
+        let actual = mpsse.set_frequency(config.frequency)?;
+        debug!("SPI master in {:?} at {actual} Hz", config.mode);
+
+        let mut spi = Self { mpsse, config };
+        spi.idle()?;
+        Ok(spi)
+    }
+
+    /// Drives the bus of `mpsse` as it is, for callers that have already validated `config`, set
+    /// the clock and left the bus idle.
+    pub(crate) fn attach(mpsse: &'a mut MPSSE<T>, config: SpiConfig) -> Self {
+        Self { mpsse, config }
+    }
+
+    pub fn config(&self) -> &SpiConfig {
+        &self.config
+    }
+
+    /// The interface underneath.
+    pub fn mpsse(&mut self) -> &mut MPSSE<T> {
+        self.mpsse
+    }
+
+    /// Queues driving the bus, with SCK at its idle level and only `selected` selected.
+    fn queue_select(&self, commands: &mut CommandBuffer, selected: Option<usize>) {
+        let sck = Pin::low(0).mask();
+        let mut gpio = [if self.config.mode.idle_high() { sck } else { 0 }, 0];
+        // SCK and MOSI are outputs, MISO an input.
+        let mut direction = [sck | Pin::low(1).mask(), 0];
+
+        for (device, cs) in self.config.cs.iter().enumerate() {
+            let byte = match cs.byte {
+                PinByte::Low => 0,
+                PinByte::High => 1,
+            };
+            direction[byte] |= cs.mask();
+            if selected != Some(device) {
+                gpio[byte] |= cs.mask();
+            }
+        }
+
+        commands.set_gpio(gpio[0], direction[0]);
+        if self.config.uses_high_byte() {
+            commands.set_gpio_high(gpio[1], direction[1]);
+        }
+    }
+
+    /// Deselects every device and leaves the bus idle.
+    pub fn idle(&mut self) -> Result<(), ArrangeError> {
+        let mut commands = CommandBuffer::new();
+        self.queue_select(&mut commands, None);
+        self.mpsse.execute(&commands).map(|_| ())
+    }
+
+    /// Selects `device`, runs the commands queued by `queue` and deselects it again, all in a
+    /// single USB round trip. Returns everything the queued commands read.
+    pub fn transaction(
+        &mut self,
+        device: usize,
+        queue: impl FnOnce(&mut CommandBuffer) -> &mut CommandBuffer,
+    ) -> Result<Vec<u8>, ArrangeError> {
+        if device >= self.config.cs.len() {
+            return Err(ArrangeError::InvalidConfiguration {
+                message: format!(
+                    "no SPI device {device}, the bus has {} devices",
+                    self.config.cs.len()
+                ),
+            });
+        }
+
+        let mut commands = CommandBuffer::new();
+        self.queue_select(&mut commands, Some(device));
+        queue(commands.spi_format(self.config.format()));
+        self.queue_select(&mut commands, None);
+
+        self.mpsse.execute(&commands)
+    }
+
+    pub fn write(&mut self, device: usize, data: &[u8]) -> Result<(), ArrangeError> {
+        self.transaction(device, |commands| commands.spi_write(data))
+            .map(|_| ())
+    }
+
+    pub fn read(&mut self, device: usize, len: usize) -> Result<Vec<u8>, ArrangeError> {
+        self.transaction(device, |commands| commands.spi_read(len))
+    }
+
+    /// Clocks `data` out while clocking in as many bytes.
+    pub fn transfer(&mut self, device: usize, data: &[u8]) -> Result<Vec<u8>, ArrangeError> {
+        self.transaction(device, |commands| commands.spi_transfer(data))
+    }
+
+    /// Writes `data` and then reads `len` bytes, in one transaction. Suits the command and
+    /// response of most peripherals.
+    pub fn write_read(
+        &mut self,
+        device: usize,
+        data: &[u8],
+        len: usize,
+    ) -> Result<Vec<u8>, ArrangeError> {
+        self.transaction(device, |commands| commands.spi_write(data).spi_read(len))
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use arrange_misc::error::ArrangeError;
+
+use super::{
+    board::Interface,
+    device_string::DeviceString,
+    uart::{DataBits, FlowControl, Parity, StopBits},
+};
+
+#[cfg(not(any(feature = "libftdi", feature = "nusb")))]
+compile_error!("arrange-ftdi needs the `libftdi` or `nusb` feature to talk to hardware");
+
+/// The transport used unless another one is given: libftdi, or nusb when built without libftdi.
+#[cfg(feature = "libftdi")]
+pub type DefaultTransport = super::libftdi::LibFtdi;
+#[cfg(not(feature = "libftdi"))]
+pub type DefaultTransport = super::nusb::Nusb;
+
+/// The mode the FTDI interface pins are driven in, see `ftdi_set_bitmode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BitMode {
+    /// Back to the default mode of the interface (UART / FIFO).
+    Reset = 0x00,
+    /// Multi-Protocol Synchronous Serial Engine.
+    Mpsse = 0x02,
+    /// MCU host bus emulation, see [`MPSSE::enter_cpu_mode`](super::mpsse::MPSSE::enter_cpu_mode).
+    Mcu = 0x08,
+}
+
+/// The USB side of an FTDI interface, everything [`MPSSE`](super::mpsse::MPSSE) needs from the
+/// chip.
+///
+/// [`LibFtdi`](super::libftdi::LibFtdi) talks to real hardware, other implementations can stand
+/// in for it.
+pub trait Transport {
+    /// Opens `interface` of the device selected by `device_string`, or of the first device with
+    /// one of `ids` when there is none.
+    fn open(
+        &mut self,
+        interface: Interface,
+        ids: &[(u16, u16)],
+        device_string: Option<&DeviceString>,
+    ) -> Result<(), ArrangeError>;
+
+    /// Closes the device. Closing more than once does nothing.
+    fn close(&mut self);
+
+    /// Resets the interface.
+    fn reset(&mut self) -> Result<(), ArrangeError>;
+
+    /// Discards anything left in the chip's receive and transmit buffers.
+    fn purge(&mut self) -> Result<(), ArrangeError>;
+
+    /// Reads the latency timer, in milliseconds.
+    fn latency_timer(&mut self) -> Result<u8, ArrangeError>;
+
+    /// Sets the latency timer, in milliseconds.
+    fn set_latency_timer(&mut self, latency: u8) -> Result<(), ArrangeError>;
+
+    /// Switches the interface to `mode`, `mask` selects which pins are outputs in bitbang modes.
+    fn set_bitmode(&mut self, mask: u8, mode: BitMode) -> Result<(), ArrangeError>;
+
+    /// Sets the UART baud rate, returning the rate the chip actually settled on.
+    fn set_baud_rate(&mut self, baud: u32) -> Result<u32, ArrangeError>;
+
+    /// Sets the UART character format.
+    fn set_line_properties(
+        &mut self,
+        data_bits: DataBits,
+        stop_bits: StopBits,
+        parity: Parity,
+    ) -> Result<(), ArrangeError>;
+
+    /// Sets the UART flow control.
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> Result<(), ArrangeError>;
+
+    /// Sets how long a single USB transfer may take before it fails. Transports that never wait
+    /// on USB ignore it.
+    fn set_timeout(&mut self, _timeout: Duration) {}
+
+    /// Writes `data` to the chip, returning how many bytes were accepted.
+    fn write(&mut self, data: &[u8]) -> Result<usize, ArrangeError>;
+
+    /// Reads whatever the chip has available into `buffer`, returning how many bytes were read.
+    ///
+    /// Returning 0 is not an error, the caller decides how long to keep polling.
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ArrangeError>;
+}
@@ -0,0 +1,247 @@
+//! UART settings for an interface switched out of MPSSE mode, see
+//! [`CommMode::Uart`](super::comm::CommMode::Uart).
+
+use core::fmt;
+use std::str::FromStr;
+
+use arrange_misc::error::ArrangeError;
+
+/// Bits per character.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DataBits {
+    Seven = 7,
+    Eight = 8,
+}
+
+/// The parity bit sent after the data bits, if any.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Parity {
+    None = 0,
+    Odd = 1,
+    Even = 2,
+    /// Always 1.
+    Mark = 3,
+    /// Always 0.
+    Space = 4,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum StopBits {
+    One = 0,
+    OnePointFive = 1,
+    Two = 2,
+}
+
+/// How the chip and the gateware stop each other from sending.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlowControl {
+    None,
+    RtsCts,
+    DtrDsr,
+    /// In band, with [`FlowControl::XON`] and [`FlowControl::XOFF`].
+    XonXoff,
+}
+
+impl FlowControl {
+    pub const XON: u8 = 0x11;
+    pub const XOFF: u8 = 0x13;
+}
+
+/// The serial settings of the comm interface in UART mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UartConfig {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+impl Default for UartConfig {
+    /// 115200 baud, 8N1, no flow control.
+    fn default() -> Self {
+        Self {
+            baud: 115_200,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+impl UartConfig {
+    /// The slowest and fastest rates of the FT2232H.
+    pub const MIN_BAUD: u32 = 183;
+    pub const MAX_BAUD: u32 = 12_000_000;
+    /// How far the achieved rate may be from the one asked for, in percent. UART receivers cope
+    /// with a few percent.
+    pub const MAX_BAUD_ERROR: u32 = 3;
+
+    /// Checks that `actual`, the rate the chip settled on, is close enough to the one asked for.
+    pub fn check_baud(&self, actual: u32) -> Result<(), ArrangeError> {
+        if self.baud.abs_diff(actual) * 100 > self.baud * Self::MAX_BAUD_ERROR {
+            return Err(ArrangeError::InvalidConfiguration {
+                message: format!("{} baud cannot be reached, the closest is {actual}", self.baud),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Works out the FT2232H baud rate divisor for `baud`, like libftdi does: returns the encoded
+/// divisor, whose low 16 bits go in the `SIO_SET_BAUDRATE` value and the rest in the high byte
+/// of its index, and the rate it gives.
+pub fn baud_divisor(baud: u32) -> Result<(u32, u32), ArrangeError> {
+    if !(UartConfig::MIN_BAUD..=UartConfig::MAX_BAUD).contains(&baud) {
+        return Err(ArrangeError::InvalidConfiguration {
+            message: format!(
+                "{baud} baud is outside of {} to {}",
+                UartConfig::MIN_BAUD,
+                UartConfig::MAX_BAUD
+            ),
+        });
+    }
+
+    // Fast rates divide the 120 MHz clock by 10, slow ones the 48 MHz clock by 16.
+    const H_CLOCK: u32 = 120_000_000;
+    const C_CLOCK: u32 = 48_000_000;
+    let (clock, clock_divisor, flag) = if baud * 10 > H_CLOCK / 0x3FFF {
+        (H_CLOCK, 10, 0x20000)
+    } else {
+        (C_CLOCK, 16, 0)
+    };
+
+    // The divisor has 3 fractional bits, encoded out of order.
+    const FRACTION_CODE: [u32; 8] = [0, 3, 2, 4, 1, 5, 6, 7];
+    let base = clock / clock_divisor;
+    let (encoded, actual) = if baud >= base {
+        (0, base)
+    } else if baud >= clock / (clock_divisor + clock_divisor / 2) {
+        (1, clock / (clock_divisor + clock_divisor / 2))
+    } else if baud >= base / 2 {
+        (2, base / 2)
+    } else {
+        // Eighths of the divisor, rounded to nearest.
+        let divisor = (clock as u64 * 16 / clock_divisor as u64 / baud as u64) as u32;
+        let divisor = (divisor / 2 + divisor % 2).min(0x1FFFF);
+        let actual = (clock as u64 * 16 / clock_divisor as u64 / divisor as u64) as u32;
+        let actual = actual / 2 + actual % 2;
+        (
+            (divisor >> 3) | (FRACTION_CODE[divisor as usize & 0x7] << 14),
+            actual,
+        )
+    };
+
+    Ok((encoded | flag, actual))
+}
+
+impl fmt::Display for Parity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Parity::None => "none",
+            Parity::Odd => "odd",
+            Parity::Even => "even",
+            Parity::Mark => "mark",
+            Parity::Space => "space",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for Parity {
+    type Err = ArrangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Parity::None),
+            "odd" => Ok(Parity::Odd),
+            "even" => Ok(Parity::Even),
+            "mark" => Ok(Parity::Mark),
+            "space" => Ok(Parity::Space),
+            _ => Err(ArrangeError::InvalidConfiguration {
+                message: format!("unknown parity '{s}', expected none, odd, even, mark or space"),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for StopBits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            StopBits::One => "1",
+            StopBits::OnePointFive => "1.5",
+            StopBits::Two => "2",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for StopBits {
+    type Err = ArrangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(StopBits::One),
+            "1.5" => Ok(StopBits::OnePointFive),
+            "2" => Ok(StopBits::Two),
+            _ => Err(ArrangeError::InvalidConfiguration {
+                message: format!("unknown stop bits '{s}', expected 1, 1.5 or 2"),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for DataBits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", *self as u8)
+    }
+}
+
+impl FromStr for DataBits {
+    type Err = ArrangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "7" => Ok(DataBits::Seven),
+            "8" => Ok(DataBits::Eight),
+            _ => Err(ArrangeError::InvalidConfiguration {
+                message: format!("unknown data bits '{s}', expected 7 or 8"),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for FlowControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FlowControl::None => "none",
+            FlowControl::RtsCts => "rts-cts",
+            FlowControl::DtrDsr => "dtr-dsr",
+            FlowControl::XonXoff => "xon-xoff",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for FlowControl {
+    type Err = ArrangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(FlowControl::None),
+            "rts-cts" => Ok(FlowControl::RtsCts),
+            "dtr-dsr" => Ok(FlowControl::DtrDsr),
+            "xon-xoff" => Ok(FlowControl::XonXoff),
+            _ => Err(ArrangeError::InvalidConfiguration {
+                message: format!(
+                    "unknown flow control '{s}', expected none, rts-cts, dtr-dsr or xon-xoff"
+                ),
+            }),
+        }
+    }
+}
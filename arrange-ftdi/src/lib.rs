@@ -1,19 +1,75 @@
+use std::{
+    sync::{Mutex, MutexGuard},
+    thread::sleep,
+    time::Duration,
+};
+
 use arrange_misc::{error::ArrangeError, traits::Arrange};
-use ftdi::{flash::Flash, mpsse::MPSSE};
-use libftdi1_sys::ftdi_interface;
-use log::{debug, info};
+use ftdi::{
+    channel::Channel,
+    comm::{Comm, CommMode},
+    config::ArrangeFTDIConfig,
+    flash::Flash,
+    mpsse::MPSSE,
+    packet::{PacketConfig, Packets},
+    registers::RegisterBus,
+    spi::{SpiConfig, SpiMaster},
+    transport::{DefaultTransport, Transport},
+};
+use log::{debug, info, warn};
 
+#[cfg(feature = "embedded-hal")]
+use crate::ftdi::{hal::Hal, spi::SpiFormat};
 use crate::ftdi::block_erase::BlockErase;
 
 pub mod ftdi;
 
-pub struct ArrangeFTDI<'a> {
-    flash_interface: MPSSE<'a>,
-    comm_interface: MPSSE<'a>,
+/// An iCE40 board behind an FTDI chip.
+///
+/// Owns both of its MPSSE interfaces, which are opened by [`Arrange::init`] and closed when the
+/// `ArrangeFTDI` is dropped (or by [`ArrangeFTDI::close`]). It is `Send` and `Sync` whenever its
+/// transport is `Send`, so it can be moved to a worker thread or kept in a long lived struct.
+/// The interfaces are behind locks so that [`Arrange::read`] can use them from a shared
+/// reference.
+pub struct ArrangeFTDI<T: Transport = DefaultTransport> {
+    config: ArrangeFTDIConfig,
+    flash_interface: Mutex<MPSSE<T>>,
+    comm_interface: Mutex<MPSSE<T>>,
 }
 
-impl<'a> ArrangeFTDI<'a> {
-    pub fn get_mpsse(&self, programming: bool) -> &MPSSE {
+impl ArrangeFTDI {
+    /// Creates a new ArrangeFTDI instance which will open the device described by `config`.
+    pub fn with_config(config: ArrangeFTDIConfig) -> Self {
+        Self::with_transports(config, DefaultTransport::default(), DefaultTransport::default())
+    }
+}
+
+impl<T: Transport> ArrangeFTDI<T> {
+    /// Creates a new ArrangeFTDI instance which will open its interfaces through the given
+    /// transports rather than the default one.
+    pub fn with_transports(config: ArrangeFTDIConfig, flash: T, comm: T) -> Self {
+        Self {
+            config,
+            flash_interface: Mutex::new(MPSSE::with_transport(flash)),
+            comm_interface: Mutex::new(MPSSE::with_transport(comm)),
+        }
+    }
+
+    pub fn config(&self) -> &ArrangeFTDIConfig {
+        &self.config
+    }
+
+    fn not_open(programming: bool) -> ArrangeError {
+        ArrangeError::NotOpen {
+            description: if programming {
+                "flash interface".to_string()
+            } else {
+                "comm interface".to_string()
+            },
+        }
+    }
+
+    fn interface(&self, programming: bool) -> &Mutex<MPSSE<T>> {
         if programming {
             &self.flash_interface
         } else {
@@ -21,36 +77,138 @@ impl<'a> ArrangeFTDI<'a> {
         }
     }
 
-    pub fn get_mpsse_mut(&mut self, programming: bool) -> &'a mut MPSSE {
-        if programming {
+    /// Locks an interface for use through a shared reference. A lock poisoned by a panic is
+    /// taken over, the next transaction on the interface starts from a clean slate anyway.
+    pub fn get_mpsse(&self, programming: bool) -> Result<MutexGuard<'_, MPSSE<T>>, ArrangeError> {
+        let mpsse = self
+            .interface(programming)
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !mpsse.is_open() {
+            return Err(Self::not_open(programming));
+        }
+
+        Ok(mpsse)
+    }
+
+    pub fn get_mpsse_mut(&mut self, programming: bool) -> Result<&mut MPSSE<T>, ArrangeError> {
+        let mpsse = if programming {
             &mut self.flash_interface
         } else {
             &mut self.comm_interface
+        };
+        let mpsse = mpsse.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !mpsse.is_open() {
+            return Err(Self::not_open(programming));
         }
+
+        Ok(mpsse)
     }
 
-    pub fn get_flash(&'a mut self, programming: bool) -> Flash {
-        Flash::new(if programming { &mut self.flash_interface } else { &mut self.comm_interface })
+    pub fn get_flash(&mut self, programming: bool) -> Result<Flash<'_, T>, ArrangeError> {
+        let pins = self.config.board.pins;
+        let retry = self.config.retry;
+        Ok(Flash::new(self.get_mpsse_mut(programming)?, pins).with_retry(retry))
     }
-}
 
-impl<'a> Arrange for ArrangeFTDI<'a> {
-    fn new() -> Self {
-        Self {
-            flash_interface: MPSSE::new(),
-            comm_interface: MPSSE::new(),
+    /// The gateware, over the comm interface.
+    pub fn get_comm(&mut self) -> Result<Comm<'_, T>, ArrangeError> {
+        let config = self.config.comm;
+        Ok(Comm::new(self.get_mpsse_mut(false)?, config))
+    }
+
+    /// The gateware, over the comm interface, as a byte stream implementing [`std::io::Read`]
+    /// and [`std::io::Write`].
+    pub fn channel(&mut self) -> Result<Channel<'_, T>, ArrangeError> {
+        Ok(Channel::new(self.get_comm()?))
+    }
+
+    /// Opens a session of the [packet protocol](ftdi::packet) with the gateware, over the comm
+    /// interface.
+    pub fn packets(
+        &mut self,
+        config: PacketConfig,
+    ) -> Result<Packets<Channel<'_, T>>, ArrangeError> {
+        // Reads give up in time for a lost frame to be resent.
+        let mut comm = self.config.comm;
+        comm.timeout = comm.timeout.min(config.ack_timeout);
+        let channel = Channel::new(Comm::new(self.get_mpsse_mut(false)?, comm));
+        Packets::connect(channel, config)
+    }
+
+    /// The [registers](ftdi::registers) of the gateware, over the comm interface.
+    pub fn registers(&mut self) -> Result<RegisterBus<Channel<'_, T>>, ArrangeError> {
+        Ok(RegisterBus::new(self.channel()?))
+    }
+
+    /// A general purpose [SPI master](ftdi::spi) on the comm interface, which keeps the clock
+    /// set by `config` afterwards. Only works with the comm interface in SPI mode.
+    pub fn spi(&mut self, config: SpiConfig) -> Result<SpiMaster<'_, T>, ArrangeError> {
+        self.check_mpsse("an SPI master")?;
+        SpiMaster::new(self.get_mpsse_mut(false)?, config)
+    }
+
+    /// [embedded-hal](ftdi::hal) on the comm interface, with its SPI bus in `format` at
+    /// `frequency`. Only works with the comm interface in SPI mode.
+    #[cfg(feature = "embedded-hal")]
+    pub fn hal(&mut self, format: SpiFormat, frequency: u32) -> Result<Hal<'_, T>, ArrangeError> {
+        self.check_mpsse("embedded-hal")?;
+        Hal::new(self.get_mpsse_mut(false)?, format, frequency)
+    }
+
+    /// Checks that the comm interface is an MPSSE, as `purpose` needs.
+    fn check_mpsse(&self, purpose: &str) -> Result<(), ArrangeError> {
+        if self.config.comm.mode != CommMode::Spi {
+            return Err(ArrangeError::InvalidConfiguration {
+                message: format!("{purpose} needs the comm interface in SPI mode"),
+            });
         }
+
+        Ok(())
     }
 
-    fn init(&mut self) -> Result<(), ArrangeError> {
-        // We can only program over Interface A.
-        // We can only communicate over Interface B.
-        self.flash_interface.init(ftdi_interface::INTERFACE_A, None, false)?;
-        self.comm_interface.init(ftdi_interface::INTERFACE_B, None, false)
+    /// Blocks until the gateware drives GPIOL1 of the comm interface `high` or low, for up to
+    /// `timeout`, see [`MPSSE::wait_gpiol1`]. Only works with the comm interface in SPI mode, in
+    /// UART mode the pin belongs to the UART.
+    pub fn wait_for_gateware(&mut self, high: bool, timeout: Duration) -> Result<(), ArrangeError> {
+        self.check_mpsse("waiting on GPIOL1")?;
+        let result = self.get_mpsse_mut(false)?.wait_gpiol1(high, timeout);
+        if let Err(ArrangeError::Timeout { .. }) = result {
+            // Giving up on the wait let go of the bus.
+            self.get_comm()?.idle()?;
+        }
+        result
     }
 
-    fn burn(&mut self, bytes: &[u8]) -> Result<(), ArrangeError> {
-        let mut flash = Flash::new(&mut self.flash_interface);
+    /// Runs an accelerator in the gateware: sends it `request` over the comm interface, waits up
+    /// to `timeout` for it to finish and returns the first `response_len` bytes it answered.
+    ///
+    /// The accelerator signals it is done on GPIOL1. It holds the line low from the first byte
+    /// of a request until its whole response is waiting to be read, then drives it high, so the
+    /// host never sees the end of the previous call.
+    pub fn call(
+        &mut self,
+        request: &[u8],
+        response_len: usize,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, ArrangeError> {
+        self.check_mpsse("waiting on GPIOL1")?;
+        self.get_comm()?.send(request)?;
+        self.wait_for_gateware(true, timeout)?;
+
+        let mut comm = self.get_comm()?;
+        let mut response = vec![0; response_len];
+        let mut received = 0;
+        while received < response_len {
+            received += comm.recv_into(&mut response[received..])?;
+        }
+
+        Ok(response)
+    }
+
+    /// A single attempt at [`Arrange::burn`].
+    fn burn_once(&mut self, bytes: &[u8]) -> Result<(), ArrangeError> {
+        let mut flash = self.get_flash(true)?;
 
         // Reset.
         flash.release_reset()?;
@@ -62,19 +220,9 @@ impl<'a> Arrange for ArrangeFTDI<'a> {
 
         // Read first to ensure we aren't writing the same stream back.
         info!("Checking...");
-        let mut addr = 0;
-        let mut same = true;
-        for chunk in bytes.chunks(256) {
-            debug!("addr {:#06X} {}", addr, 100 * addr / bytes_size);
-            let read = flash.read(addr, chunk.len())?;
-
-            if chunk != read {
-                info!("Difference. Let's program!");
-                same = false;
-                break;
-            }
-
-            addr += chunk.len();
+        let same = flash.read(0, bytes_size)? == bytes;
+        if !same {
+            info!("Difference. Let's program!");
         }
 
         if same {
@@ -111,17 +259,19 @@ impl<'a> Arrange for ArrangeFTDI<'a> {
             }
 
             info!("Verifying...");
-            addr = 0;
-            for chunk in bytes.chunks(256) {
-                debug!("addr {:#06X} {}", addr, 100 * addr / bytes_size);
-                let read = flash.read(addr, chunk.len())?;
-
-                if chunk != read {
-                    debug!("Found difference between flash and bytes!");
-                    return Err(ArrangeError::WriteError);
-                }
-
-                addr += chunk.len();
+            let read = flash.read(0, bytes_size)?;
+            if let Some((address, (expected, actual))) = bytes
+                .iter()
+                .zip(read.iter())
+                .enumerate()
+                .find(|(_, (expected, actual))| expected != actual)
+            {
+                debug!("Found difference between flash and bytes!");
+                return Err(ArrangeError::VerifyMismatch {
+                    address,
+                    expected: *expected,
+                    actual: *actual,
+                });
             }
 
             info!("Verified, OK!");
@@ -131,17 +281,112 @@ impl<'a> Arrange for ArrangeFTDI<'a> {
         Ok(())
     }
 
+    /// Gets the flash interface going again after a transient failure: resynchronizes the
+    /// MPSSE and resets the flash, abandoning whatever it was doing.
+    fn recover(&mut self) -> Result<(), ArrangeError> {
+        self.get_mpsse_mut(true)?.resync()?;
+        let mut flash = self.get_flash(true)?;
+        flash.software_reset()?;
+        flash.release_reset()
+    }
+
+    /// Closes both interfaces. They can be opened again with [`Arrange::init`].
+    pub fn close(&mut self) {
+        for mpsse in [&mut self.flash_interface, &mut self.comm_interface] {
+            mpsse.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner()).close();
+        }
+    }
+}
+
+impl<T: Transport + Default> Arrange for ArrangeFTDI<T> {
+    fn new() -> Self {
+        Self::with_transports(ArrangeFTDIConfig::default(), T::default(), T::default())
+    }
+
+    fn init(&mut self) -> Result<(), ArrangeError> {
+        self.close();
+
+        let board = &self.config.board;
+        let device_string = self.config.device_string.as_ref();
+        info!("Board: {}", board.name);
+        board.pins.validate()?;
+        let flash_interface = self
+            .flash_interface
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let comm_interface = self
+            .comm_interface
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        flash_interface.set_timeout(self.config.timeout);
+        comm_interface.set_timeout(self.config.timeout);
+
+        flash_interface.init(
+            board.flash_interface,
+            &board.ids,
+            device_string,
+            self.config.frequency,
+        )?;
+
+        if let Some(interface) = board.comm_interface {
+            self.config.comm.validate()?;
+            comm_interface.init(interface, &board.ids, device_string, self.config.comm.frequency)?;
+            Comm::new(comm_interface, self.config.comm).configure()?;
+        }
+
+        Ok(())
+    }
+
+    /// Programs `bytes` at the start of the flash and verifies them.
+    ///
+    /// Transient failures (see [`ArrangeError::is_transient`]) start the whole burn over, as
+    /// often as the configured [`RetryPolicy`](ftdi::retry::RetryPolicy) allows. Anything
+    /// already programmed correctly is then found by the initial check and not written again.
+    fn burn(&mut self, bytes: &[u8]) -> Result<(), ArrangeError> {
+        let retry = self.config.retry;
+        let mut attempt = 1;
+        loop {
+            match self.burn_once(bytes) {
+                Err(error) if error.is_transient() && attempt < retry.attempts => {
+                    warn!("Burn attempt {attempt} of {} failed: {error}", retry.attempts);
+                    sleep(retry.backoff);
+                    // The burn failure is the one worth reporting, not what stopped recovery.
+                    if let Err(recovery) = self.recover() {
+                        warn!("Could not recover from the failed burn: {recovery}");
+                        return Err(error);
+                    }
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Reads back the bitstream at the start of the flash, as long as its framing says it is.
+    ///
+    /// The FPGA is held in reset while the flash is read, and configures itself again afterwards.
     fn read(&self) -> Result<Vec<u8>, ArrangeError> {
-        todo!("Implement at some point");
+        let mut mpsse = self.get_mpsse(true)?;
+        let mut flash =
+            Flash::new(&mut mpsse, self.config.board.pins).with_retry(self.config.retry);
+
+        info!("Reading...");
+        let bitstream = flash.read_bitstream();
+        flash.release_reset()?;
+        let bitstream = bitstream?;
+        info!("Read {} bytes", bitstream.len());
+
+        Ok(bitstream)
     }
 
+    /// Sends `bytes` to the gateware over the comm interface, see [`Comm::send`].
     fn send(&mut self, bytes: &[u8]) -> Result<(), ArrangeError> {
-        // We are programming on interface A.
-        // When we are sending and recieving, we want to do it over interface B.
-        Ok(())
+        self.get_comm()?.send(bytes)
     }
 
+    /// Receives up to `length` bytes from the gateware over the comm interface, see
+    /// [`Comm::recv`].
     fn recv(&mut self, length: usize) -> Result<Vec<u8>, ArrangeError> {
-        todo!()
+        self.get_comm()?.recv(length)
     }
 }
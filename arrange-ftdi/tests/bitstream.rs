@@ -0,0 +1,108 @@
+mod common;
+
+use std::thread;
+
+use arrange_ftdi::ftdi::{bitstream::Framing, config::ArrangeFTDIConfig, emulator::Emulator};
+use arrange_misc::{error::ArrangeError, traits::Arrange};
+use common::arrange;
+
+/// A bitstream framed like `icepack` output, with a comment header and a single bank of
+/// configuration RAM `rows` rows high.
+fn bitstream(rows: u16) -> Vec<u8> {
+    let mut bytes = vec![0xFF, 0x00, b'a', b'r', b'r', 0x00, 0xFF];
+    bytes.extend([0x7E, 0xAA, 0x99, 0x7E, 0x51, 0x00, 0x01, 0x05, 0x92, 0x00, 0x20]);
+    // 332 bits wide, `rows` high, at offset 0 of bank 0.
+    bytes.extend([0x62, 0x01, 0x4B, 0x72, (rows >> 8) as u8, rows as u8]);
+    bytes.extend([0x82, 0x00, 0x00, 0x11, 0x00, 0x01, 0x01]);
+    bytes.extend((0..(332 * rows as usize).div_ceil(8)).map(|i| (i * 7 + i / 251) as u8));
+    bytes.extend([0x00, 0x00, 0x22, 0x12, 0x34, 0x01, 0x06, 0x00]);
+    bytes
+}
+
+#[test]
+fn framing_finds_the_end_of_the_bitstream() {
+    let image = bitstream(240);
+    let mut flash = image.clone();
+    flash.extend([0xFF; 100]);
+
+    assert_eq!(Framing::new().advance(&flash).unwrap(), Some(image.len()));
+
+    // Fed a piece at a time, it asks for more until the end is in sight.
+    let mut framing = Framing::new();
+    let ends: Vec<_> = (0..=flash.len())
+        .map(|len| framing.advance(&flash[..len]).unwrap())
+        .collect();
+    assert!(ends[..image.len()].iter().all(Option::is_none));
+    assert_eq!(ends[image.len()], Some(image.len()));
+}
+
+#[test]
+fn framing_rejects_what_is_not_a_bitstream() {
+    let erased = vec![0xFF; 5000];
+    assert!(Framing::new().advance(&erased).is_err());
+
+    let mut image = bitstream(16);
+    let trailer = image.len() - 8;
+    image[trailer] = 0x55;
+    assert!(Framing::new().advance(&image).is_err());
+
+    let warmboot = [0x7E, 0xAA, 0x99, 0x7E, 0x01, 0x08, 0x00];
+    assert!(Framing::new().advance(&warmboot).is_err());
+}
+
+#[test]
+fn framing_rejects_oversized_banks() {
+    for command in [0x68, 0x78] {
+        let mut image = vec![0x7E, 0xAA, 0x99, 0x7E, command];
+        image.extend([0xFF; 8]);
+        image.extend([0x01, 0x01]);
+        let error = Framing::new().advance(&image).unwrap_err();
+        assert!(matches!(error, ArrangeError::UnexpectedResponse { .. }), "{error}");
+    }
+
+    // The largest bank that fits the commands is only too large for the image.
+    let image = [0x7E, 0xAA, 0x99, 0x7E, 0x62, 0xFF, 0xFF, 0x72, 0xFF, 0xFF, 0x01, 0x01];
+    assert_eq!(Framing::new().advance(&image).unwrap(), None);
+}
+
+#[test]
+fn read_returns_the_burned_bitstream() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+    let image = bitstream(240);
+    arrange.burn(&image).unwrap();
+    // Leftovers of a longer image must not be read back.
+    emulator.load_flash(image.len(), &[0x5A; 300]);
+
+    assert_eq!(arrange.read().unwrap(), image);
+    assert!(emulator.cdone());
+    assert!(!emulator.in_reset());
+}
+
+#[test]
+fn read_needs_a_bitstream_in_the_flash() {
+    let emulator = Emulator::new();
+    let arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+
+    let error = arrange.read().unwrap_err();
+    assert!(
+        matches!(error.root_cause(), ArrangeError::UnexpectedResponse { .. }),
+        "{error}"
+    );
+    assert!(!emulator.in_reset());
+}
+
+#[test]
+fn read_can_be_shared_between_threads() {
+    let emulator = Emulator::new();
+    let image = bitstream(120);
+    emulator.load_flash(0, &image);
+    let arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+
+    thread::scope(|scope| {
+        let readers: Vec<_> = (0..4).map(|_| scope.spawn(|| arrange.read())).collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap().unwrap(), image);
+        }
+    });
+}
@@ -0,0 +1,224 @@
+mod common;
+
+use arrange_ftdi::{
+    ftdi::{
+        board::Interface,
+        capture::{
+            decode::{self, DecodedCommand},
+            Capture, CaptureLog, Event, Replay,
+        },
+        comm::CommMode,
+        config::ArrangeFTDIConfig,
+        emulator::{Emulator, Fault},
+        flash::{Flash, FlashCommand},
+        mpsse::MPSSE,
+        pins::PinMap,
+        uart::{FlowControl, UartConfig},
+    },
+    ArrangeFTDI,
+};
+use arrange_misc::{error::ArrangeError, traits::Arrange};
+use common::{bitstream, mpsse};
+
+/// Burns `image` to an emulated board, capturing everything.
+fn capture_burn(image: &[u8]) -> Capture {
+    let emulator = Emulator::new();
+    let log = CaptureLog::new();
+    let mut arrange = ArrangeFTDI::with_transports(
+        ArrangeFTDIConfig::default(),
+        log.recorder(emulator.clone()),
+        log.recorder(emulator.clone()),
+    );
+    arrange.init().unwrap();
+    arrange.burn(image).unwrap();
+    drop(arrange);
+
+    log.capture()
+}
+
+fn replay(capture: Capture) -> (Replay, ArrangeFTDI<Replay>) {
+    let replay = Replay::new(capture);
+    let arrange =
+        ArrangeFTDI::with_transports(ArrangeFTDIConfig::default(), replay.clone(), replay.clone());
+    (replay, arrange)
+}
+
+#[test]
+fn a_burn_decodes_into_flash_transactions() {
+    let capture = capture_burn(&bitstream(600));
+
+    let commands = decode::mpsse_commands(&capture, Interface::A);
+    assert!(commands.contains(&DecodedCommand::ClockDivisor(0)));
+    assert!(!commands.iter().any(|command| matches!(
+        command,
+        DecodedCommand::Invalid(_) | DecodedCommand::Truncated(_)
+    )));
+
+    let transactions: Vec<String> = decode::flash_transactions(&commands, &PinMap::default())
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    for expected in [
+        "RD 0x000000 600 bytes",
+        "WE",
+        "BE64 0x000000",
+        "RSR1 -> 0x01",
+        "RSR1 -> 0x00",
+        "PP 0x000000 256 bytes",
+        "PP 0x000100 256 bytes",
+        "PP 0x000200 88 bytes",
+    ] {
+        assert!(
+            transactions.iter().any(|transaction| transaction == expected),
+            "{expected} missing from {transactions:#?}"
+        );
+    }
+    assert!(decode::mpsse_commands(&capture, Interface::B)
+        .iter()
+        .all(|command| !matches!(command, DecodedCommand::Data { .. })));
+}
+
+#[test]
+fn captures_survive_a_round_trip_through_text() {
+    let capture = capture_burn(&bitstream(300));
+    let text = capture.to_string();
+
+    assert!(text.starts_with("# arrange capture\n"));
+    assert_eq!(text.parse::<Capture>().unwrap(), capture);
+    assert!(matches!(
+        "0.000001 E open".parse::<Capture>(),
+        Err(ArrangeError::InvalidConfiguration { .. })
+    ));
+}
+
+#[test]
+fn captures_are_written_to_a_file_as_they_happen() {
+    let path = std::env::temp_dir().join(format!("arrange-capture-{}.txt", std::process::id()));
+    let log = CaptureLog::create(&path).unwrap();
+    let mut mpsse = mpsse(log.recorder(Emulator::new()), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    mpsse.read_low_byte().unwrap();
+
+    let written: Capture = std::fs::read_to_string(&path).unwrap().parse().unwrap();
+    assert_eq!(written.events(Interface::A).next(), Some(&Event::Open));
+    assert!(written
+        .events(Interface::A)
+        .any(|event| matches!(event, Event::Read(_))));
+    // It is all in the file, none of it is kept in memory.
+    assert!(log.capture().records.is_empty());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn failed_calls_are_captured_and_replayed() {
+    let emulator = Emulator::new();
+    let log = CaptureLog::new();
+    let mut arrange = ArrangeFTDI::with_transports(
+        ArrangeFTDIConfig::default(),
+        log.recorder(emulator.clone()),
+        log.recorder(emulator.clone()),
+    );
+    arrange.init().unwrap();
+    emulator.inject(Fault::Disconnect {
+        opcode: FlashCommand::BE64 as u8,
+    });
+    let image = bitstream(600);
+    let error = arrange.burn(&image).unwrap_err();
+    drop(arrange);
+
+    let capture: Capture = log.capture().to_string().parse().unwrap();
+    assert_eq!(capture, log.capture());
+    let failure = capture
+        .events(Interface::A)
+        .find_map(|event| match event {
+            Event::Failed { error, .. } => Some(error.clone()),
+            _ => None,
+        })
+        .expect("the failure is captured");
+    assert_eq!(failure, error.root_cause().to_string());
+
+    let (_, mut arrange) = replay(capture);
+    arrange.init().unwrap();
+    let replayed = arrange.burn(&image).unwrap_err();
+    assert!(
+        matches!(replayed.root_cause(), ArrangeError::Ftdi { message, .. } if *message == failure),
+        "{replayed}"
+    );
+}
+
+#[test]
+fn a_replayed_burn_behaves_like_the_original() {
+    let image = bitstream(600);
+    let (replay, mut arrange) = replay(capture_burn(&image));
+
+    arrange.init().unwrap();
+    arrange.burn(&image).unwrap();
+    drop(arrange);
+
+    assert!(replay.is_finished());
+}
+
+#[test]
+fn a_replay_reports_where_it_diverges() {
+    let (_, mut arrange) = replay(capture_burn(&bitstream(600)));
+
+    arrange.init().unwrap();
+    let error = arrange.burn(&bitstream(700)).unwrap_err();
+    assert!(
+        matches!(error.root_cause(), ArrangeError::UnexpectedResponse { .. }),
+        "{error}"
+    );
+}
+
+#[test]
+fn flash_reads_can_be_replayed() {
+    let emulator = Emulator::new();
+    emulator.load_flash(0x100, b"hello, flash");
+    let log = CaptureLog::new();
+    let mut recorded = mpsse(log.recorder(emulator), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    let mut flash = Flash::new(&mut recorded, PinMap::default());
+    let data = flash.read(0x100, 12).unwrap();
+    assert_eq!(data, b"hello, flash");
+    let id = flash.read_id().unwrap();
+
+    let mut replayed = mpsse(Replay::new(log.capture()), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    let mut flash = Flash::new(&mut replayed, PinMap::default());
+    assert_eq!(flash.read(0x100, 12).unwrap(), data);
+    assert_eq!(flash.read_id().unwrap(), id);
+}
+
+#[test]
+fn uart_sessions_round_trip_and_replay() {
+    let mut config = ArrangeFTDIConfig::default();
+    config.comm.mode = CommMode::Uart(UartConfig {
+        flow_control: FlowControl::RtsCts,
+        ..Default::default()
+    });
+    let emulator = Emulator::new();
+    let log = CaptureLog::new();
+    let mut arrange = ArrangeFTDI::with_transports(
+        config.clone(),
+        log.recorder(emulator.clone()),
+        log.recorder(emulator.clone()),
+    );
+    arrange.init().unwrap();
+    arrange.send(b"serial").unwrap();
+    drop(arrange);
+
+    let text = log.capture().to_string();
+    for expected in ["baud 115200 115246", "line 8 none 1", "flow rts-cts"] {
+        assert!(text.contains(expected), "{expected} missing from {text}");
+    }
+    let capture: Capture = text.parse().unwrap();
+    assert_eq!(capture, log.capture());
+    // The serial bytes are not MPSSE commands.
+    assert!(decode::mpsse_commands(&capture, Interface::B)
+        .iter()
+        .all(|command| !matches!(command, DecodedCommand::Invalid(_))));
+
+    let replay = Replay::new(capture);
+    let mut arrange = ArrangeFTDI::with_transports(config, replay.clone(), replay.clone());
+    arrange.init().unwrap();
+    arrange.send(b"serial").unwrap();
+    drop(arrange);
+    assert!(replay.is_finished());
+}
@@ -0,0 +1,176 @@
+mod common;
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    time::Duration,
+};
+
+use arrange_ftdi::{
+    ftdi::{
+        comm::CommMode,
+        config::ArrangeFTDIConfig,
+        emulator::{Emulator, EmulatorConfig},
+        uart::UartConfig,
+    },
+    ArrangeFTDI,
+};
+use arrange_misc::error::ArrangeError;
+use common::arrange;
+
+fn config(mode: CommMode) -> ArrangeFTDIConfig {
+    let mut config = ArrangeFTDIConfig::default();
+    config.comm.mode = mode;
+    config.comm.timeout = Duration::from_millis(20);
+    config
+}
+
+#[test]
+fn lines_can_be_read_through_a_buffer() {
+    for mode in [CommMode::Spi, CommMode::Uart(UartConfig::default())] {
+        let emulator = Emulator::new();
+        let mut arrange = arrange(&emulator, config(mode));
+        emulator.with_gateware(|gateware| gateware.queue_for_host(b"first\nsecond\n"));
+
+        let mut reader = BufReader::new(arrange.channel().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "first\n");
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "second\n", "{mode:?}");
+    }
+}
+
+#[test]
+fn copies_stream_into_the_gateware() {
+    let emulator = Emulator::with_config(EmulatorConfig {
+        comm_capacity: 1000,
+        ..Default::default()
+    });
+    let mut arrange = arrange(&emulator, config(CommMode::Spi));
+    let data: Vec<u8> = (0..700).map(|i| (i % 253) as u8).collect();
+
+    let mut channel = arrange.channel().unwrap();
+    assert_eq!(io::copy(&mut data.as_slice(), &mut channel).unwrap(), 700);
+    channel.flush().unwrap();
+
+    assert_eq!(emulator.with_gateware(|gateware| gateware.take_from_host()), data);
+}
+
+#[test]
+fn writes_and_reads_are_partial() {
+    let emulator = Emulator::with_config(EmulatorConfig {
+        comm_capacity: 10,
+        ..Default::default()
+    });
+    let mut arrange = arrange(&emulator, config(CommMode::Spi));
+    let mut channel = arrange.channel().unwrap();
+
+    assert_eq!(channel.write(&[0x55; 25]).unwrap(), 10);
+    emulator.with_gateware(|gateware| gateware.queue_for_host(b"abc"));
+    let mut buffer = [0; 8];
+    assert_eq!(channel.read(&mut buffer).unwrap(), 3);
+    assert_eq!(&buffer[..3], b"abc");
+    assert_eq!(channel.read(&mut []).unwrap(), 0);
+}
+
+#[test]
+fn timeouts_keep_the_arrange_error() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, config(CommMode::Spi));
+
+    let error = arrange.channel().unwrap().read(&mut [0; 4]).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    let inner = error.get_ref().unwrap().downcast_ref::<ArrangeError>().unwrap();
+    assert!(matches!(inner, ArrangeError::Timeout { .. }), "{inner}");
+}
+
+#[test]
+fn a_channel_needs_an_open_interface() {
+    let emulator = Emulator::new();
+    let mut arrange = ArrangeFTDI::with_transports(
+        ArrangeFTDIConfig::default(),
+        emulator.clone(),
+        emulator.clone(),
+    );
+
+    assert!(matches!(arrange.channel(), Err(ArrangeError::NotOpen { .. })));
+}
+
+#[cfg(feature = "futures-io")]
+#[test]
+fn async_polls_do_not_block() {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use futures_io::{AsyncRead, AsyncWrite};
+
+    let emulator = Emulator::with_config(EmulatorConfig {
+        comm_capacity: 4,
+        ..Default::default()
+    });
+    let mut arrange = arrange(&emulator, config(CommMode::Spi));
+    let mut channel = arrange.channel().unwrap();
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut buffer = [0; 8];
+
+    assert!(Pin::new(&mut channel).poll_read(&mut cx, &mut buffer).is_pending());
+    emulator.with_gateware(|gateware| gateware.queue_for_host(b"async"));
+    assert!(matches!(
+        Pin::new(&mut channel).poll_read(&mut cx, &mut buffer),
+        Poll::Ready(Ok(5))
+    ));
+    assert_eq!(&buffer[..5], b"async");
+
+    assert!(matches!(
+        Pin::new(&mut channel).poll_write(&mut cx, b"too long"),
+        Poll::Ready(Ok(4))
+    ));
+    assert!(Pin::new(&mut channel).poll_write(&mut cx, b" long").is_pending());
+    assert_eq!(emulator.with_gateware(|gateware| gateware.take_from_host()), b"too ");
+}
+
+#[cfg(feature = "futures-io")]
+#[test]
+fn pending_async_polls_wake_after_the_poll_interval() {
+    use std::{
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        task::{Context, Wake, Waker},
+        thread,
+        time::Instant,
+    };
+
+    use futures_io::AsyncRead;
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let emulator = Emulator::new();
+    let mut config = config(CommMode::Spi);
+    config.comm.poll_interval = Duration::from_millis(50);
+    let mut arrange = arrange(&emulator, config);
+    let mut channel = arrange.channel().unwrap();
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let polled = Instant::now();
+    assert!(Pin::new(&mut channel).poll_read(&mut cx, &mut [0; 8]).is_pending());
+    assert!(!flag.0.load(Ordering::SeqCst), "woken straight away");
+    while !flag.0.load(Ordering::SeqCst) {
+        assert!(polled.elapsed() < Duration::from_secs(5), "never woken");
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(polled.elapsed() >= Duration::from_millis(50));
+}
@@ -0,0 +1,333 @@
+mod common;
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use arrange_ftdi::{
+    ftdi::{
+        board::Interface,
+        comm::CommMode,
+        config::ArrangeFTDIConfig,
+        emulator::{Emulator, EmulatorConfig},
+        pins::Pin,
+        transport::Transport,
+        uart::{DataBits, FlowControl, Parity, StopBits, UartConfig},
+    },
+    ArrangeFTDI,
+};
+use arrange_misc::{error::ArrangeError, traits::Arrange};
+use common::arrange;
+
+fn uart(uart: UartConfig) -> ArrangeFTDIConfig {
+    let mut config = quick_timeout();
+    config.comm.mode = CommMode::Uart(uart);
+    config
+}
+
+fn quick_timeout() -> ArrangeFTDIConfig {
+    let mut config = ArrangeFTDIConfig::default();
+    config.comm.timeout = Duration::from_millis(20);
+    config
+}
+
+#[test]
+fn send_reaches_the_gateware() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+
+    arrange.send(b"hello, gateware").unwrap();
+    arrange.send(&[]).unwrap();
+
+    assert_eq!(emulator.with_gateware(|gateware| gateware.take_from_host()), b"hello, gateware");
+}
+
+#[test]
+fn send_waits_for_the_gateware_to_make_room() {
+    let emulator = Emulator::with_config(EmulatorConfig {
+        comm_capacity: 100,
+        ..Default::default()
+    });
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+    let data: Vec<u8> = (0..2000).map(|i| (i % 251) as u8).collect();
+
+    let received = thread::scope(|scope| {
+        let sender = scope.spawn(|| arrange.send(&data));
+        let mut received = vec![];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.len() < data.len() && Instant::now() < deadline {
+            received.extend(emulator.with_gateware(|gateware| gateware.take_from_host()));
+            thread::sleep(Duration::from_millis(1));
+        }
+        sender.join().unwrap().unwrap();
+        received
+    });
+
+    assert_eq!(received, data);
+}
+
+#[test]
+fn send_times_out_when_the_gateware_stays_full() {
+    let emulator = Emulator::with_config(EmulatorConfig {
+        comm_capacity: 300,
+        ..Default::default()
+    });
+    let mut arrange = arrange(&emulator, quick_timeout());
+
+    let error = arrange.send(&[0x55; 1000]).unwrap_err();
+    assert!(
+        matches!(error, ArrangeError::Timeout { expected: 1000, actual: 300, .. }),
+        "{error}"
+    );
+}
+
+#[test]
+fn recv_returns_what_is_available() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+    emulator.with_gateware(|gateware| gateware.queue_for_host(b"0123456789"));
+
+    assert_eq!(arrange.recv(4).unwrap(), b"0123");
+    assert_eq!(arrange.recv(100).unwrap(), b"456789");
+    assert_eq!(arrange.recv(0).unwrap(), b"");
+}
+
+#[test]
+fn recv_reads_past_the_saturated_status() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+    let data: Vec<u8> = (0..1000).map(|i| (i * 3) as u8).collect();
+    emulator.with_gateware(|gateware| gateware.queue_for_host(&data));
+
+    assert_eq!(arrange.recv(2000).unwrap(), data);
+    assert_eq!(emulator.with_gateware(|gateware| gateware.pending_for_host()), 0);
+}
+
+#[test]
+fn recv_times_out_when_nothing_arrives() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, quick_timeout());
+
+    let error = arrange.recv(8).unwrap_err();
+    assert!(
+        matches!(error, ArrangeError::Timeout { expected: 8, actual: 0, .. }),
+        "{error}"
+    );
+}
+
+#[test]
+fn comm_speed_is_configurable() {
+    let emulator = Emulator::new();
+    let mut config = ArrangeFTDIConfig::default();
+    config.comm.frequency = 1_000_000;
+    let _arrange = arrange(&emulator, config);
+
+    assert_eq!(emulator.frequency(Interface::B), 1_000_000);
+}
+
+#[test]
+fn comm_cs_cannot_be_an_spi_pin() {
+    let emulator = Emulator::new();
+    let mut config = ArrangeFTDIConfig::default();
+    config.comm.cs = Pin::low(2);
+    let mut arrange = ArrangeFTDI::with_transports(config, emulator.clone(), emulator.clone());
+
+    let error = arrange.init().unwrap_err();
+    assert!(matches!(error, ArrangeError::InvalidConfiguration { .. }), "{error}");
+}
+
+#[test]
+fn uart_settings_reach_the_chip() {
+    let settings = UartConfig {
+        baud: 1_000_000,
+        data_bits: DataBits::Seven,
+        parity: Parity::Even,
+        stop_bits: StopBits::Two,
+        flow_control: FlowControl::RtsCts,
+    };
+    let emulator = Emulator::with_config(EmulatorConfig {
+        comm_uart: settings,
+        ..Default::default()
+    });
+    let _arrange = arrange(&emulator, uart(settings));
+
+    assert_eq!(emulator.uart_config(Interface::B), settings);
+    assert!(emulator.is_open(Interface::A));
+}
+
+#[test]
+fn uart_baud_rates_are_rounded_like_the_chip() {
+    let emulator = Emulator::new();
+    let mut transport = emulator.clone();
+    transport.open(Interface::B, &[(0x0403, 0x6010)], None).unwrap();
+
+    for (baud, actual) in [
+        (115_200, 115_246),
+        (9600, 9600),
+        (12_000_000, 12_000_000),
+        (3_000_000, 3_000_000),
+        (300, 300),
+    ] {
+        assert_eq!(transport.set_baud_rate(baud).unwrap(), actual, "{baud} baud");
+    }
+    assert!(transport.set_baud_rate(50).is_err());
+}
+
+#[test]
+fn uart_streams_bytes_both_ways() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, uart(UartConfig::default()));
+
+    arrange.send(b"over the uart").unwrap();
+    assert_eq!(emulator.with_gateware(|gateware| gateware.take_from_host()), b"over the uart");
+
+    emulator.with_gateware(|gateware| gateware.queue_for_host(b"and back"));
+    assert_eq!(arrange.recv(3).unwrap(), b"and");
+    assert_eq!(arrange.recv(100).unwrap(), b" back");
+    assert!(matches!(
+        arrange.recv(1).unwrap_err(),
+        ArrangeError::Timeout { actual: 0, .. }
+    ));
+}
+
+#[test]
+fn uart_with_the_wrong_settings_loses_everything() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(
+        &emulator,
+        uart(UartConfig {
+            baud: 9600,
+            ..Default::default()
+        }),
+    );
+
+    arrange.send(b"lost").unwrap();
+    emulator.with_gateware(|gateware| gateware.queue_for_host(b"lost too"));
+
+    assert!(arrange.recv(8).is_err());
+    assert!(emulator.with_gateware(|gateware| gateware.take_from_host()).is_empty());
+}
+
+#[test]
+fn uart_flow_control_holds_the_host_off() {
+    let emulator = Emulator::with_config(EmulatorConfig {
+        comm_capacity: 64,
+        ..Default::default()
+    });
+    let mut arrange = arrange(
+        &emulator,
+        uart(UartConfig {
+            flow_control: FlowControl::RtsCts,
+            ..Default::default()
+        }),
+    );
+    let error = arrange.send(&[0xA5; 100]).unwrap_err();
+    assert!(
+        matches!(error, ArrangeError::Timeout { expected: 100, actual: 64, .. }),
+        "{error}"
+    );
+
+    // Without it, what the gateware has no room for is lost.
+    let emulator = Emulator::with_config(EmulatorConfig {
+        comm_capacity: 64,
+        ..Default::default()
+    });
+    let mut arrange = common::arrange(&emulator, uart(UartConfig::default()));
+    arrange.send(&[0xA5; 100]).unwrap();
+    assert_eq!(emulator.with_gateware(|gateware| gateware.take_from_host()).len(), 64);
+}
+
+#[test]
+fn uart_rejects_rates_the_chip_cannot_reach() {
+    let emulator = Emulator::new();
+    let config = uart(UartConfig {
+        baud: 20_000_000,
+        ..Default::default()
+    });
+    let mut arrange = ArrangeFTDI::with_transports(config, emulator.clone(), emulator.clone());
+
+    let error = arrange.init().unwrap_err();
+    assert!(matches!(error, ArrangeError::InvalidConfiguration { .. }), "{error}");
+}
+
+#[test]
+fn waits_end_when_the_gateware_drives_gpiol1() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+    let start = Instant::now();
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            thread::sleep(Duration::from_millis(50));
+            emulator.with_gateware(|gateware| gateware.set_gpiol1(true));
+        });
+        arrange.wait_for_gateware(true, Duration::from_secs(1)).unwrap();
+    });
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    // Already high.
+    arrange.wait_for_gateware(true, Duration::from_millis(20)).unwrap();
+}
+
+#[test]
+fn waits_time_out_and_leave_the_comm_interface_usable() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+
+    let error = arrange
+        .wait_for_gateware(true, Duration::from_millis(20))
+        .unwrap_err();
+    assert!(
+        matches!(error, ArrangeError::Timeout { operation: "GPIOL1 wait", .. }),
+        "{error}"
+    );
+
+    arrange.send(b"still here").unwrap();
+    assert_eq!(emulator.with_gateware(|gateware| gateware.take_from_host()), b"still here");
+
+    drop(arrange);
+    let mut arrange = common::arrange(&emulator, uart(UartConfig::default()));
+    let error = arrange
+        .wait_for_gateware(false, Duration::from_millis(20))
+        .unwrap_err();
+    assert!(matches!(error, ArrangeError::InvalidConfiguration { .. }), "{error}");
+}
+
+#[test]
+fn calls_wait_for_the_accelerator_to_finish() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+    let done = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        // Sums its requests of 4 bytes, slowly.
+        scope.spawn(|| {
+            let mut request = vec![];
+            while !done.load(Ordering::Relaxed) {
+                emulator.with_gateware(|gateware| {
+                    let received = gateware.take_from_host();
+                    if !received.is_empty() {
+                        gateware.set_gpiol1(false);
+                    }
+                    request.extend(received);
+                });
+                if request.len() >= 4 {
+                    thread::sleep(Duration::from_millis(20));
+                    let sum: u16 = request.drain(..4).map(u16::from).sum();
+                    emulator.with_gateware(|gateware| {
+                        gateware.queue_for_host(&sum.to_le_bytes());
+                        gateware.set_gpiol1(true);
+                    });
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+
+        let timeout = Duration::from_secs(1);
+        assert_eq!(arrange.call(&[1, 2, 3, 4], 2, timeout).unwrap(), [10, 0]);
+        assert_eq!(arrange.call(&[0xFF; 4], 2, timeout).unwrap(), [0xFC, 0x03]);
+        done.store(true, Ordering::Relaxed);
+    });
+}
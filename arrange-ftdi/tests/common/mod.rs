@@ -0,0 +1,71 @@
+//! Fixtures shared by the integration tests, each of which uses only some of them.
+#![allow(dead_code)]
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use arrange_ftdi::{
+    ftdi::{
+        board::Interface, config::ArrangeFTDIConfig, emulator::Emulator, mpsse::MPSSE,
+        transport::Transport,
+    },
+    ArrangeFTDI,
+};
+use arrange_misc::traits::Arrange;
+
+/// A made up bitstream: the iCE40 preamble followed by `len - 4` bytes of noise.
+pub fn bitstream(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0x7E, 0xAA, 0x99, 0x7E];
+    bytes.extend((0..len - 4).map(|i| (i * 7 + i / 251) as u8));
+    bytes
+}
+
+/// An initialised [`ArrangeFTDI`] on the emulated board.
+pub fn arrange(emulator: &Emulator, config: ArrangeFTDIConfig) -> ArrangeFTDI<Emulator> {
+    let mut arrange = ArrangeFTDI::with_transports(config, emulator.clone(), emulator.clone());
+    arrange.init().unwrap();
+    arrange
+}
+
+/// An MPSSE at full speed on `interface` of an emulated FT2232H, waiting `timeout` for answers.
+/// The flash is on interface A with its CS on ADBUS4, the gateware on B.
+pub fn mpsse<T: Transport>(transport: T, interface: Interface, timeout: Duration) -> MPSSE<T> {
+    let mut mpsse = MPSSE::with_transport(transport);
+    mpsse.set_timeout(timeout);
+    mpsse
+        .init(interface, &[(0x0403, 0x6010)], None, MPSSE::MAX_FREQUENCY)
+        .unwrap();
+    mpsse
+}
+
+/// A model of some gateware, run in the background by [`run_with`].
+pub trait Step: Send {
+    /// Handles whatever the host has sent since the last step.
+    fn step(&mut self);
+}
+
+/// Runs `host` with `peer` stepping in the background every millisecond, and returns the peer
+/// afterwards. The peer stops even if `host` panics, so a failing test does not hang.
+pub fn run_with<P: Step>(mut peer: P, host: impl FnOnce()) -> P {
+    let done = AtomicBool::new(false);
+    let result = thread::scope(|scope| {
+        let background = scope.spawn(|| {
+            while !done.load(Ordering::Relaxed) {
+                peer.step();
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+        let result = panic::catch_unwind(AssertUnwindSafe(host));
+        done.store(true, Ordering::Relaxed);
+        background.join().unwrap();
+        result
+    });
+    if let Err(panic) = result {
+        panic::resume_unwind(panic);
+    }
+    peer
+}
@@ -0,0 +1,212 @@
+mod common;
+
+use arrange_ftdi::ftdi::{
+    block_erase::BlockErase,
+    board::{BoardProfile, Interface},
+    config::ArrangeFTDIConfig,
+    emulator::{Emulator, EmulatorConfig},
+    flash::{Flash, FlashCommand},
+    mpsse::MPSSE,
+    pins::PinMap,
+    transport::Transport,
+};
+use arrange_misc::{error::ArrangeError, traits::Arrange};
+use common::{arrange, bitstream, mpsse};
+
+#[test]
+fn burn_programs_and_configures_the_fpga() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+    let image = bitstream(100_000);
+
+    arrange.burn(&image).unwrap();
+
+    assert_eq!(&emulator.flash_memory()[..image.len()], &image[..]);
+    assert!(emulator.cdone());
+    assert!(!emulator.in_reset());
+    assert!(arrange.get_flash(true).unwrap().cdone().unwrap());
+}
+
+#[test]
+fn burn_skips_programming_an_identical_image() {
+    let emulator = Emulator::new();
+    let image = bitstream(5000);
+    emulator.load_flash(0, &image);
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+
+    arrange.burn(&image).unwrap();
+
+    let history = emulator.flash_history();
+    assert!(!history.contains(&(FlashCommand::PP as u8)));
+    assert!(!history.contains(&(FlashCommand::BE64 as u8)));
+}
+
+#[test]
+fn programming_only_clears_bits() {
+    let emulator = Emulator::new();
+    emulator.load_flash(0, &[0x00; 16]);
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+    let mut flash = arrange.get_flash(true).unwrap();
+    flash.write_enable().unwrap();
+    flash.prog(0, &[0xFF; 4]).unwrap();
+    flash.wait().unwrap();
+
+    assert_eq!(flash.read(0, 4).unwrap(), vec![0x00; 4]);
+}
+
+#[test]
+fn flash_reads_across_command_boundaries() {
+    let emulator = Emulator::new();
+    let data = bitstream(200_000);
+    emulator.load_flash(0x1000, &data);
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    let mut flash = Flash::new(&mut mpsse, PinMap::default());
+
+    assert_eq!(flash.read(0x1000, data.len()).unwrap(), data);
+}
+
+#[test]
+fn flash_erases_programs_and_waits() {
+    let emulator = Emulator::new();
+    emulator.with_flash(|flash| flash.set_busy_polls(10));
+    emulator.load_flash(0, &[0x00; 0x2000]);
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    let mut flash = Flash::new(&mut mpsse, PinMap::default());
+
+    flash.write_enable().unwrap();
+    flash.sector_erase(BlockErase::FourK, 0x1000).unwrap();
+    flash.wait().unwrap();
+    flash.write_enable().unwrap();
+    flash.prog(0x1080, &[1, 2, 3, 4]).unwrap();
+    flash.wait().unwrap();
+
+    let memory = emulator.flash_memory();
+    assert_eq!(&memory[..0x1000], &[0x00; 0x1000][..]);
+    assert_eq!(&memory[0x1080..0x1084], &[1, 2, 3, 4]);
+    assert!(memory[0x1084..0x2000].iter().all(|&byte| byte == 0xFF));
+}
+
+#[test]
+fn flash_reads_the_jedec_id() {
+    let emulator = Emulator::new();
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    let mut flash = Flash::new(&mut mpsse, PinMap::default());
+
+    let id = flash.read_id().unwrap();
+    assert!(id.starts_with("0x20 0xBA 0x16 "), "{id}");
+}
+
+#[test]
+fn flash_power_down_ignores_commands_until_woken() {
+    let emulator = Emulator::new();
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    let mut flash = Flash::new(&mut mpsse, PinMap::default());
+
+    flash.power_down().unwrap();
+    assert!(emulator.with_flash(|flash| flash.is_powered_down()));
+    flash.power_up().unwrap();
+    assert!(!emulator.with_flash(|flash| flash.is_powered_down()));
+}
+
+#[test]
+fn frequency_and_latency_reach_the_chip() {
+    let emulator = Emulator::new();
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    assert_eq!(emulator.frequency(Interface::A), MPSSE::MAX_FREQUENCY);
+    assert_eq!(emulator.latency_timer(Interface::A), 1);
+
+    let actual = mpsse.set_frequency(1_000_000).unwrap();
+    assert_eq!(emulator.frequency(Interface::A), actual);
+    let actual = mpsse.set_frequency(200).unwrap();
+    assert_eq!(emulator.frequency(Interface::A), actual);
+}
+
+#[test]
+fn unknown_commands_are_rejected() {
+    let emulator = Emulator::new();
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+
+    mpsse.send_byte(0xAB).unwrap();
+    assert_eq!(
+        mpsse.recv_exact(2, MPSSE::DEFAULT_TIMEOUT).unwrap(),
+        vec![Emulator::BAD_COMMAND, 0xAB]
+    );
+}
+
+#[test]
+fn missing_responses_time_out() {
+    let emulator = Emulator::new();
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+
+    let error = mpsse
+        .recv_exact(1, std::time::Duration::from_millis(10))
+        .unwrap_err();
+    assert!(matches!(error, ArrangeError::Timeout { .. }), "{error}");
+}
+
+#[test]
+fn opening_checks_ids_and_interfaces() {
+    let emulator = Emulator::with_config(EmulatorConfig {
+        id: (0x0403, 0x6014),
+        ..Default::default()
+    });
+    let mut transport = emulator.clone();
+
+    let error = transport
+        .open(Interface::A, &[(0x0403, 0x6010)], None)
+        .unwrap_err();
+    assert!(matches!(error, ArrangeError::DeviceNotFound { .. }), "{error}");
+    assert!(transport.open(Interface::C, &[(0x0403, 0x6014)], None).is_err());
+
+    transport.open(Interface::A, &[(0x0403, 0x6014)], None).unwrap();
+    assert!(emulator
+        .clone()
+        .open(Interface::A, &[(0x0403, 0x6014)], None)
+        .is_err());
+    transport.close();
+    assert!(!emulator.is_open(Interface::A));
+}
+
+#[test]
+fn an_ft232h_has_only_interface_a() {
+    let emulator = Emulator::with_config(EmulatorConfig {
+        id: (0x0403, 0x6014),
+        interfaces: 1,
+        ..Default::default()
+    });
+    let mut transport = emulator.clone();
+    assert!(transport.open(Interface::B, &[(0x0403, 0x6014)], None).is_err());
+
+    let mut arrange = arrange(
+        &emulator,
+        ArrangeFTDIConfig {
+            board: BoardProfile::ft232h(),
+            ..Default::default()
+        },
+    );
+    let image = bitstream(10_000);
+    arrange.burn(&image).unwrap();
+
+    assert_eq!(&emulator.flash_memory()[..image.len()], &image[..]);
+    assert!(emulator.cdone());
+    assert!(!emulator.is_open(Interface::B));
+}
+
+#[test]
+fn arrange_can_be_reopened_after_close() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+    assert!(emulator.is_open(Interface::A) && emulator.is_open(Interface::B));
+
+    arrange.close();
+    assert!(!emulator.is_open(Interface::A) && !emulator.is_open(Interface::B));
+    assert!(matches!(
+        arrange.get_flash(true),
+        Err(ArrangeError::NotOpen { .. })
+    ));
+
+    arrange.init().unwrap();
+    arrange.burn(&bitstream(300)).unwrap();
+    drop(arrange);
+    assert!(!emulator.is_open(Interface::A));
+}
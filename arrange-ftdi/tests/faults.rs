@@ -0,0 +1,239 @@
+mod common;
+
+use std::time::Duration;
+
+use arrange_ftdi::ftdi::{
+    block_erase::BlockErase,
+    board::Interface,
+    config::ArrangeFTDIConfig,
+    emulator::{Emulator, Fault},
+    flash::{Flash, FlashCommand},
+    pins::PinMap,
+    retry::RetryPolicy,
+};
+use arrange_misc::{error::ArrangeError, traits::Arrange};
+use common::{arrange, bitstream, mpsse, run_with, Step};
+
+fn retry() -> RetryPolicy {
+    RetryPolicy {
+        attempts: 3,
+        backoff: Duration::ZERO,
+        busy_timeout: Duration::from_millis(50),
+    }
+}
+
+const TIMEOUT: Duration = Duration::from_millis(50);
+const POLLS: u32 = 20_000;
+
+fn config(retry: RetryPolicy) -> ArrangeFTDIConfig {
+    ArrangeFTDIConfig {
+        timeout: TIMEOUT,
+        retry,
+        ..Default::default()
+    }
+}
+
+fn count(history: &[u8], command: FlashCommand) -> usize {
+    let opcode = command as u8;
+    history.iter().filter(|&&entry| entry == opcode).count()
+}
+
+#[test]
+fn burn_recovers_from_a_short_write() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, config(retry()));
+    let image = bitstream(3000);
+
+    emulator.inject(Fault::ShortWrite { accepted: 10 });
+    arrange.burn(&image).unwrap();
+
+    assert_eq!(&emulator.flash_memory()[..image.len()], &image[..]);
+    assert!(emulator.cdone());
+}
+
+#[test]
+fn burn_gives_up_on_a_short_write_without_retries() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, config(RetryPolicy::never()));
+
+    emulator.inject(Fault::ShortWrite { accepted: 10 });
+    let error = arrange.burn(&bitstream(3000)).unwrap_err();
+
+    assert!(matches!(error.root_cause(), ArrangeError::ShortWrite { .. }), "{error}");
+}
+
+#[test]
+fn burn_reports_its_own_failure_when_recovery_fails() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, config(retry()));
+
+    emulator.inject(Fault::ShortWrite { accepted: 10 });
+    emulator.inject(Fault::Disconnect {
+        opcode: FlashCommand::ERESET as u8,
+    });
+    let error = arrange.burn(&bitstream(3000)).unwrap_err();
+
+    assert!(matches!(error.root_cause(), ArrangeError::ShortWrite { .. }), "{error}");
+    assert!(!emulator.is_connected());
+}
+
+#[test]
+fn burn_rereads_after_a_bit_flip_during_verify() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, config(retry()));
+    let image = bitstream(3000);
+
+    // The first read is the check before programming, the second the verify.
+    emulator.inject(Fault::BitFlip {
+        address: 1234,
+        mask: 0x10,
+        skip: 1,
+    });
+    arrange.burn(&image).unwrap();
+
+    assert_eq!(&emulator.flash_memory()[..image.len()], &image[..]);
+    // The retry found the image already programmed and did not erase it again.
+    assert_eq!(count(&emulator.flash_history(), FlashCommand::BE64), 1);
+}
+
+#[test]
+fn burn_reports_a_bit_flip_without_retries() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, config(RetryPolicy::never()));
+    let image = bitstream(3000);
+
+    emulator.inject(Fault::BitFlip {
+        address: 1234,
+        mask: 0x10,
+        skip: 1,
+    });
+    let error = arrange.burn(&image).unwrap_err();
+
+    assert_eq!(
+        error,
+        ArrangeError::VerifyMismatch {
+            address: 1234,
+            expected: image[1234],
+            actual: image[1234] ^ 0x10,
+        }
+    );
+}
+
+#[test]
+fn burn_resets_a_flash_stuck_busy() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, config(retry()));
+    let image = bitstream(3000);
+
+    emulator.inject(Fault::StuckBusy);
+    arrange.burn(&image).unwrap();
+
+    assert_eq!(&emulator.flash_memory()[..image.len()], &image[..]);
+    assert!(count(&emulator.flash_history(), FlashCommand::RESET) >= 1);
+}
+
+#[test]
+fn wait_gives_up_on_a_flash_stuck_busy() {
+    let emulator = Emulator::new();
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, TIMEOUT);
+    let mut flash = Flash::new(&mut mpsse, PinMap::default()).with_retry(retry());
+
+    emulator.inject(Fault::StuckBusy);
+    let error = flash.wait().unwrap_err();
+
+    assert!(matches!(error.root_cause(), ArrangeError::Busy { .. }), "{error}");
+    assert!(error.is_transient());
+}
+
+#[test]
+fn wait_retries_a_failed_status_read() {
+    let emulator = Emulator::new();
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, TIMEOUT);
+    let mut flash = Flash::new(&mut mpsse, PinMap::default()).with_retry(retry());
+
+    flash.write_enable().unwrap();
+    flash.sector_erase(BlockErase::FourK, 0).unwrap();
+    emulator.inject(Fault::ShortWrite { accepted: 5 });
+    flash.wait().unwrap();
+
+    assert_eq!(flash.read_status().unwrap() & 0x01, 0);
+}
+
+/// Makes the next status read fail at every step.
+struct Hiccups {
+    emulator: Emulator,
+    injected: usize,
+}
+
+impl Step for Hiccups {
+    fn step(&mut self) {
+        self.emulator.inject(Fault::ShortWrite { accepted: 5 });
+        self.injected += 1;
+    }
+}
+
+#[test]
+fn wait_only_gives_up_on_failures_in_a_row() {
+    let emulator = Emulator::new();
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, TIMEOUT);
+    let retry = RetryPolicy {
+        attempts: 2,
+        busy_timeout: Duration::from_secs(10),
+        ..retry()
+    };
+    let mut flash = Flash::new(&mut mpsse, PinMap::default()).with_retry(retry);
+
+    emulator.with_flash(|flash| flash.set_busy_polls(POLLS));
+    flash.write_enable().unwrap();
+    flash.sector_erase(BlockErase::FourK, 0).unwrap();
+    let hiccups = Hiccups {
+        emulator: emulator.clone(),
+        injected: 0,
+    };
+    let hiccups = run_with(hiccups, || flash.wait().unwrap());
+
+    // Each failure alone is retried, however many there are over the whole wait.
+    assert!(hiccups.injected >= 3, "only {} failures", hiccups.injected);
+}
+
+#[test]
+fn corrupt_reads_reach_the_caller() {
+    let emulator = Emulator::new();
+    emulator.load_flash(0, &[0x42; 64]);
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, TIMEOUT);
+    let mut flash = Flash::new(&mut mpsse, PinMap::default());
+
+    emulator.inject(Fault::CorruptRead {
+        offset: 10,
+        mask: 0x81,
+    });
+    let data = flash.read(0, 64).unwrap();
+
+    assert_eq!(data[10], 0x42 ^ 0x81);
+    assert_eq!(flash.read(0, 64).unwrap(), vec![0x42; 64]);
+}
+
+#[test]
+fn burn_fails_when_the_device_disappears_mid_erase() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, config(retry()));
+    let image = bitstream(3000);
+
+    emulator.inject(Fault::Disconnect {
+        opcode: FlashCommand::BE64 as u8,
+    });
+    let error = arrange.burn(&image).unwrap_err();
+
+    assert!(matches!(error.root_cause(), ArrangeError::Ftdi { .. }), "{error}");
+    assert!(!error.is_transient());
+    assert!(!emulator.is_connected());
+    assert!(matches!(
+        arrange.init(),
+        Err(ArrangeError::DeviceNotFound { .. })
+    ));
+
+    emulator.reconnect();
+    arrange.init().unwrap();
+    arrange.burn(&image).unwrap();
+    assert_eq!(&emulator.flash_memory()[..image.len()], &image[..]);
+}
@@ -0,0 +1,125 @@
+#![cfg(feature = "embedded-hal")]
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use arrange_ftdi::ftdi::{
+    board::Interface,
+    config::ArrangeFTDIConfig,
+    emulator::Emulator,
+    hal::Hal,
+    mpsse::MPSSE,
+    pins::Pin,
+    spi::{BitOrder, SpiFormat},
+};
+use arrange_misc::error::ArrangeError;
+use common::{arrange, mpsse};
+use embedded_hal::{
+    delay::DelayNs,
+    digital::{InputPin, OutputPin, StatefulOutputPin},
+    spi::{Operation, SpiBus, SpiDevice},
+};
+
+const JEDEC_ID: [u8; 3] = [0x20, 0xBA, 0x16];
+
+/// A driver that knows nothing of the MPSSE.
+fn read_jedec_id<S: SpiDevice>(spi: &mut S) -> Result<[u8; 3], S::Error> {
+    let mut id = [0; 3];
+    spi.transaction(&mut [Operation::Write(&[0x9F]), Operation::Read(&mut id)])?;
+    Ok(id)
+}
+
+#[test]
+fn drivers_talk_to_spi_devices() {
+    let emulator = Emulator::new();
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    let hal = Hal::new(&mut mpsse, SpiFormat::default(), 1_000_000).unwrap();
+    let mut flash = hal.spi_device(Pin::low(4)).unwrap();
+
+    assert_eq!(read_jedec_id(&mut flash).unwrap(), JEDEC_ID);
+
+    let mut words = [0x9F, 0, 0, 0];
+    flash.transfer_in_place(&mut words).unwrap();
+    assert_eq!(words[1..], JEDEC_ID);
+
+    // A short read buffer, the rest of the answer is dropped.
+    let mut id = [0; 2];
+    let mut status = [0; 1];
+    flash
+        .transaction(&mut [
+            Operation::Transfer(&mut id, &[0x9F, 0, 0, 0]),
+            Operation::DelayNs(1_000),
+            Operation::TransferInPlace(&mut status),
+        ])
+        .unwrap();
+    assert_eq!(id, [0xFF, 0x20]);
+}
+
+#[test]
+fn the_bus_follows_a_chip_select_pin() {
+    let emulator = Emulator::new();
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    let format = SpiFormat {
+        bit_order: BitOrder::LsbFirst,
+        ..Default::default()
+    };
+    let hal = Hal::new(&mut mpsse, format, 1_000_000).unwrap();
+    let mut spi = hal.spi();
+    let mut cs = hal.output(Pin::low(4), true).unwrap();
+
+    cs.set_low().unwrap();
+    assert!(cs.is_set_low().unwrap());
+    spi.write(&[0x9Fu8.reverse_bits()]).unwrap();
+    let mut id = [0; 3];
+    spi.read(&mut id).unwrap();
+    spi.flush().unwrap();
+    cs.set_high().unwrap();
+
+    assert_eq!(id, JEDEC_ID.map(u8::reverse_bits));
+}
+
+#[test]
+fn pins_are_read_and_handed_out_once() {
+    let emulator = Emulator::new();
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    let hal = Hal::new(&mut mpsse, SpiFormat::default(), 1_000_000).unwrap();
+
+    // Nothing is configured in the emulated iCE40, and CRESET_B is pulled up.
+    let mut cdone = hal.input(Pin::low(6)).unwrap();
+    let mut creset = hal.input(Pin::low(7)).unwrap();
+    assert!(cdone.is_low().unwrap());
+    assert!(creset.is_high().unwrap());
+
+    for pin in [Pin::low(0), Pin::low(2), Pin::low(6), Pin::high(8)] {
+        let error = hal.output(pin, false).err().unwrap();
+        assert!(matches!(error, ArrangeError::InvalidConfiguration { .. }), "{error}");
+    }
+    drop(cdone);
+    hal.output(Pin::low(6), false).unwrap();
+}
+
+#[test]
+fn delays_take_at_least_as_long_as_asked() {
+    let emulator = Emulator::new();
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    let hal = Hal::new(&mut mpsse, SpiFormat::default(), 1_000_000).unwrap();
+
+    let start = Instant::now();
+    hal.delay().delay_ms(20);
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+#[test]
+fn arrange_hands_out_the_comm_interface() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+
+    let hal = arrange.hal(SpiFormat::default(), 1_000_000).unwrap();
+    let mut gateware = hal.spi_device(Pin::low(3)).unwrap();
+    // A STATUS command of the comm protocol: nothing to read, room for more than 255 bytes.
+    let mut status = [0x00, 0x00, 0x00];
+    gateware.transfer_in_place(&mut status).unwrap();
+    assert_eq!(status, [0x00, 0x00, 0xFF]);
+    assert_eq!(emulator.frequency(Interface::B), 1_000_000);
+}
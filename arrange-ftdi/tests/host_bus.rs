@@ -0,0 +1,113 @@
+mod common;
+
+use arrange_ftdi::ftdi::{
+    board::Interface,
+    capture::{
+        decode::{self, DecodedCommand},
+        Capture, CaptureLog,
+    },
+    emulator::Emulator,
+    flash::Flash,
+    mpsse::MPSSE,
+    pins::PinMap,
+    transport::Transport,
+};
+use arrange_misc::error::ArrangeError;
+use common::mpsse;
+
+/// The interface the emulated gateware's host bus is on, in MCU host bus emulation.
+fn host_bus<T: Transport>(transport: T) -> MPSSE<T> {
+    let mut mpsse = mpsse(transport, Interface::B, MPSSE::DEFAULT_TIMEOUT);
+    mpsse.enter_cpu_mode().unwrap();
+    mpsse
+}
+
+#[test]
+fn bus_cycles_reach_the_gateware() {
+    let emulator = Emulator::new();
+    emulator.with_gateware(|gateware| gateware.bus_mut()[0x1234] = 0x5A);
+    let mut mpsse = host_bus(emulator.clone());
+
+    assert_eq!(mpsse.cpu_read_extended(0x1234).unwrap(), 0x5A);
+    mpsse.cpu_write_extended(0xBEEF, 0xA5).unwrap();
+    assert_eq!(mpsse.cpu_read_extended(0xBEEF).unwrap(), 0xA5);
+
+    // Short addresses keep A15 to A8 of the last extended one.
+    mpsse.cpu_write_short(0x01, 0x11).unwrap();
+    assert_eq!(mpsse.cpu_read_short(0xEF).unwrap(), 0xA5);
+    emulator.with_gateware(|gateware| {
+        assert_eq!(gateware.bus()[0xBE01], 0x11);
+        assert_eq!(gateware.bus()[0x0001], 0x00);
+    });
+}
+
+#[test]
+fn blocks_are_read_and_written_in_bulk() {
+    let emulator = Emulator::new();
+    let mut mpsse = host_bus(emulator.clone());
+    let data: Vec<u8> = (0..10_000).map(|i| (i * 13 + i / 256) as u8).collect();
+
+    mpsse.cpu_write_block(0x4000, &data).unwrap();
+    assert_eq!(mpsse.cpu_read_block(0x4000, data.len()).unwrap(), data);
+    emulator.with_gateware(|gateware| {
+        assert_eq!(&gateware.bus()[0x4000..0x4000 + data.len()], &data[..]);
+    });
+
+    assert_eq!(mpsse.cpu_read_block(0xFFFF, 1).unwrap(), [0]);
+    let error = mpsse.cpu_write_block(0xFFFF, &[1, 2]).unwrap_err();
+    assert!(matches!(error, ArrangeError::InvalidConfiguration { .. }), "{error}");
+}
+
+#[test]
+fn leaving_cpu_mode_restores_the_mpsse() {
+    let emulator = Emulator::new();
+    let mut mpsse = mpsse(emulator.clone(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+
+    mpsse.enter_cpu_mode().unwrap();
+    // Nothing is on the bus of the flash interface.
+    assert_eq!(mpsse.cpu_read_extended(0x0000).unwrap(), 0xFF);
+    mpsse.leave_cpu_mode().unwrap();
+    assert_eq!(emulator.frequency(Interface::A), MPSSE::MAX_FREQUENCY);
+
+    let mut flash = Flash::new(&mut mpsse, PinMap::default());
+    let id = flash.read_id().unwrap();
+    assert!(id.starts_with("0x20 0xBA 0x16 "), "{id}");
+}
+
+#[test]
+fn cpu_mode_needs_an_open_interface() {
+    let mut mpsse = MPSSE::with_transport(Emulator::new());
+
+    let error = mpsse.enter_cpu_mode().unwrap_err();
+    assert!(matches!(error, ArrangeError::NotOpen { .. }), "{error}");
+    let error = mpsse.leave_cpu_mode().unwrap_err();
+    assert!(matches!(error, ArrangeError::NotOpen { .. }), "{error}");
+}
+
+#[test]
+fn bus_cycles_are_decoded_from_a_capture() {
+    let emulator = Emulator::new();
+    emulator.with_gateware(|gateware| gateware.bus_mut()[0x2042] = 0x99);
+    let log = CaptureLog::new();
+    let mut mpsse = host_bus(log.recorder(emulator));
+
+    mpsse.cpu_write_extended(0x2041, 0x77).unwrap();
+    mpsse.cpu_read_short(0x42).unwrap();
+    mpsse.leave_cpu_mode().unwrap();
+    drop(mpsse);
+
+    let capture: Capture = log.capture().to_string().parse().unwrap();
+    assert_eq!(capture, log.capture());
+    let commands = decode::mpsse_commands(&capture, Interface::B);
+    let bus: Vec<_> = commands
+        .iter()
+        .filter(|command| {
+            matches!(command, DecodedCommand::CpuRead { .. } | DecodedCommand::CpuWrite { .. })
+        })
+        .map(ToString::to_string)
+        .collect();
+    assert_eq!(bus, ["CPUWE 0x2041 <- 0x77", "CPURS 0x42 -> 0x99"]);
+    assert!(commands
+        .iter()
+        .all(|command| !matches!(command, DecodedCommand::Invalid(_))));
+}
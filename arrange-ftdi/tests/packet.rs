@@ -0,0 +1,248 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use arrange_ftdi::ftdi::{
+    config::ArrangeFTDIConfig,
+    emulator::Emulator,
+    packet::{Decoded, Frame, FrameDecoder, FrameKind, PacketConfig},
+};
+use arrange_misc::error::ArrangeError;
+use common::{arrange, run_with, Step};
+
+type Mangle = Box<dyn FnMut(&Frame, Vec<u8>) -> Vec<u8> + Send>;
+
+/// The gateware end of the protocol, following the module documentation, answering every
+/// message with its bytes reversed.
+struct Peer {
+    emulator: Emulator,
+    decoder: FrameDecoder,
+    last: Option<u8>,
+    send_seq: u8,
+    outstanding: Option<(Frame, Instant)>,
+    /// Every message passed on to the accelerator.
+    delivered: Vec<Vec<u8>>,
+    /// Applied to the bytes of each frame sent to the host.
+    outbound: Mangle,
+    /// Applied to the bytes of each frame the host sent, once decoded.
+    inbound: Box<dyn FnMut(Vec<u8>) -> Vec<u8> + Send>,
+}
+
+impl Peer {
+    fn new(emulator: &Emulator) -> Self {
+        Self {
+            emulator: emulator.clone(),
+            decoder: FrameDecoder::new(),
+            last: None,
+            send_seq: 0,
+            outstanding: None,
+            delivered: vec![],
+            outbound: Box::new(|_, bytes| bytes),
+            inbound: Box::new(|bytes| bytes),
+        }
+    }
+
+    fn send(&mut self, frame: &Frame) {
+        let bytes = (self.outbound)(frame, frame.encode());
+        self.emulator.with_gateware(|gateware| gateware.queue_for_host(&bytes));
+    }
+
+    fn resend(&mut self) {
+        if let Some((frame, _)) = self.outstanding.take() {
+            self.send(&frame);
+            self.outstanding = Some((frame, Instant::now()));
+        }
+    }
+}
+
+impl Step for Peer {
+    fn step(&mut self) {
+        let bytes = self.emulator.with_gateware(|gateware| gateware.take_from_host());
+        if !bytes.is_empty() {
+            let bytes = (self.inbound)(bytes);
+            self.decoder.push(&bytes);
+        }
+
+        while let Some(decoded) = self.decoder.decode() {
+            let frame = match decoded {
+                Decoded::Frame(frame) => frame,
+                Decoded::Corrupt => {
+                    self.send(&Frame::nak(self.last.map_or(0, |seq| seq.wrapping_add(1))));
+                    continue;
+                }
+            };
+            let outstanding = self.outstanding.as_ref().map(|(frame, _)| frame.seq);
+
+            match frame.kind {
+                FrameKind::Reset => {
+                    self.last = None;
+                    self.send_seq = 0;
+                    self.outstanding = None;
+                    self.send(&Frame::ack(frame.seq));
+                }
+                FrameKind::Data => {
+                    self.send(&Frame::ack(frame.seq));
+                    if self.last == Some(frame.seq) {
+                        continue;
+                    }
+                    self.last = Some(frame.seq);
+                    self.delivered.push(frame.payload.clone());
+
+                    let mut response = frame.payload;
+                    response.reverse();
+                    let response = Frame::data(self.send_seq, &response);
+                    self.send_seq = self.send_seq.wrapping_add(1);
+                    self.send(&response);
+                    self.outstanding = Some((response, Instant::now()));
+                }
+                FrameKind::Ack if outstanding == Some(frame.seq) => self.outstanding = None,
+                FrameKind::Nak if outstanding == Some(frame.seq) => self.resend(),
+                _ => {}
+            }
+        }
+
+        if let Some((_, sent)) = &self.outstanding {
+            if sent.elapsed() > Duration::from_millis(100) {
+                self.resend();
+            }
+        }
+    }
+}
+
+#[test]
+fn frames_survive_noise_and_splitting() {
+    let frames = [Frame::data(7, b"payload with \xA5 in it"), Frame::ack(7), Frame::nak(8)];
+    let mut stream = vec![0x00, 0xA5, 0xA5, 0x13];
+    for frame in &frames {
+        stream.extend(frame.encode());
+        stream.extend([0xFF, 0xA5]);
+    }
+
+    let mut decoder = FrameDecoder::new();
+    let mut decoded = vec![];
+    for byte in stream {
+        decoder.push(&[byte]);
+        while let Some(result) = decoder.decode() {
+            if let Decoded::Frame(frame) = result {
+                decoded.push(frame);
+            }
+        }
+    }
+    assert_eq!(decoded, frames);
+
+    let mut corrupt = Frame::data(1, b"flipped").encode();
+    corrupt[6] ^= 0x01;
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&corrupt);
+    assert_eq!(decoder.decode(), Some(Decoded::Corrupt));
+}
+
+#[test]
+fn requests_get_their_responses() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+
+    let peer = run_with(Peer::new(&emulator), || {
+        let mut packets = arrange.packets(PacketConfig::default()).unwrap();
+        // Enough to wrap the sequence numbers around.
+        for i in 0..300u32 {
+            let request = i.to_le_bytes();
+            let mut expected = request;
+            expected.reverse();
+            assert_eq!(packets.request(&request).unwrap(), expected);
+        }
+        packets.request(&[0x5A; Frame::MAX_PAYLOAD]).unwrap();
+    });
+
+    assert_eq!(peer.delivered.len(), 301);
+}
+
+#[test]
+fn corrupt_frames_are_sent_again() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+    let mut peer = Peer::new(&emulator);
+    let mut corrupted = false;
+    peer.outbound = Box::new(move |frame, mut bytes| {
+        if frame.kind == FrameKind::Data && !corrupted {
+            corrupted = true;
+            bytes[Frame::HEADER_LEN] ^= 0xFF;
+        }
+        bytes
+    });
+    let mut flipped = false;
+    peer.inbound = Box::new(move |mut bytes| {
+        if bytes.len() > Frame::OVERHEAD && !flipped {
+            flipped = true;
+            let last = bytes.len() - 1;
+            bytes[last] ^= 0x80;
+        }
+        bytes
+    });
+
+    let peer = run_with(peer, || {
+        let mut packets = arrange.packets(PacketConfig::default()).unwrap();
+        assert_eq!(packets.request(b"noisy").unwrap(), b"ysion");
+        assert_eq!(packets.request(b"quiet").unwrap(), b"teiuq");
+    });
+
+    assert_eq!(peer.delivered, [b"noisy".to_vec(), b"quiet".to_vec()]);
+}
+
+#[test]
+fn lost_acks_do_not_duplicate_messages() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+    let mut peer = Peer::new(&emulator);
+    let mut acks = 0;
+    peer.outbound = Box::new(move |frame, bytes| {
+        if frame.kind == FrameKind::Ack {
+            acks += 1;
+            // The ACK of the first message, after the one of the reset.
+            if acks == 2 {
+                return vec![];
+            }
+        }
+        bytes
+    });
+
+    let peer = run_with(peer, || {
+        let mut packets = arrange.packets(PacketConfig::default()).unwrap();
+        assert_eq!(packets.request(b"once").unwrap(), b"ecno");
+        assert_eq!(packets.request(b"twice").unwrap(), b"eciwt");
+    });
+
+    assert_eq!(peer.delivered, [b"once".to_vec(), b"twice".to_vec()]);
+}
+
+#[test]
+fn a_silent_gateware_times_out() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+    let config = PacketConfig {
+        ack_timeout: Duration::from_millis(10),
+        attempts: 3,
+        ..Default::default()
+    };
+
+    let error = arrange.packets(config).err().unwrap();
+    assert!(
+        matches!(error, ArrangeError::Timeout { operation: "packet reset", .. }),
+        "{error}"
+    );
+    // The reset, three times.
+    let sent = emulator.with_gateware(|gateware| gateware.take_from_host());
+    assert_eq!(sent, Frame::reset(0).encode().repeat(3));
+}
+
+#[test]
+fn oversized_messages_are_refused() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, ArrangeFTDIConfig::default());
+
+    run_with(Peer::new(&emulator), || {
+        let mut packets = arrange.packets(PacketConfig::default()).unwrap();
+        let error = packets.send(&[0; Frame::MAX_PAYLOAD + 1]).unwrap_err();
+        assert!(matches!(error, ArrangeError::InvalidConfiguration { .. }), "{error}");
+    });
+}
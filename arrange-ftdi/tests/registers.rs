@@ -0,0 +1,186 @@
+mod common;
+
+use std::{collections::BTreeMap, time::Duration};
+
+use arrange_ftdi::ftdi::{
+    config::ArrangeFTDIConfig,
+    emulator::Emulator,
+    registers::{RegisterBus, RegisterCommand, STATUS_OK, STATUS_UNMAPPED},
+};
+use arrange_misc::error::ArrangeError;
+use common::{arrange, run_with, Step};
+
+const ID: u32 = 0x00;
+const LEDS: u32 = 0x04;
+const SCRATCH: u32 = 0x0C;
+const RAM: u32 = 0x1000;
+const RAM_WORDS: u32 = 0x200;
+
+/// The register file of `arrange-demo`, plus some RAM, behind a model of `reg_bridge.v`.
+struct Bridge {
+    emulator: Emulator,
+    received: Vec<u8>,
+    registers: BTreeMap<u32, u32>,
+    /// Every command handled, as opcode, address and count.
+    commands: Vec<(u8, u32, u8)>,
+}
+
+impl Bridge {
+    fn new(emulator: &Emulator) -> Self {
+        let mut registers = BTreeMap::from([(ID, 0x474E5241), (LEDS, 0), (0x08, 0), (SCRATCH, 0)]);
+        registers.extend((0..RAM_WORDS).map(|word| (RAM + word * 4, 0)));
+        Self {
+            emulator: emulator.clone(),
+            received: vec![],
+            registers,
+            commands: vec![],
+        }
+    }
+}
+
+impl Step for Bridge {
+    fn step(&mut self) {
+        let bytes = self.emulator.with_gateware(|gateware| gateware.take_from_host());
+        self.received.extend(bytes);
+
+        while self.received.len() >= 6 {
+            let opcode = self.received[0];
+            let address = u32::from_le_bytes(self.received[1..5].try_into().unwrap());
+            let count = self.received[5];
+            let length = if opcode == RegisterCommand::Write as u8 {
+                7 + count as usize * 4
+            } else {
+                6
+            };
+            if self.received.len() < length {
+                return;
+            }
+            let command: Vec<u8> = self.received.drain(..length).collect();
+            self.commands.push((opcode, address, count));
+
+            let mut unmapped = false;
+            let mut answer = vec![];
+            let registers = (0..count as u32).map(|index| address + index * 4);
+            for (index, register) in registers.enumerate() {
+                let value = self.registers.get_mut(&register);
+                unmapped |= value.is_none();
+
+                if opcode == RegisterCommand::Read as u8 {
+                    answer.extend(value.map_or(0, |value| *value).to_le_bytes());
+                } else if let Some(value) = value.filter(|_| register != ID) {
+                    let start = 7 + index * 4;
+                    let mut bytes = value.to_le_bytes();
+                    for (byte, new) in command[start..start + 4].iter().enumerate() {
+                        if command[6] & 1 << byte != 0 {
+                            bytes[byte] = *new;
+                        }
+                    }
+                    *value = u32::from_le_bytes(bytes);
+                }
+            }
+            answer.push(STATUS_OK | if unmapped { STATUS_UNMAPPED } else { 0 });
+            self.emulator.with_gateware(|gateware| gateware.queue_for_host(&answer));
+        }
+    }
+}
+
+fn config() -> ArrangeFTDIConfig {
+    let mut config = ArrangeFTDIConfig::default();
+    config.comm.timeout = Duration::from_millis(100);
+    config
+}
+
+#[test]
+fn registers_read_back_what_was_written() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, config());
+
+    let bridge = run_with(Bridge::new(&emulator), || {
+        let mut registers = arrange.registers().unwrap();
+        assert_eq!(registers.read32(ID).unwrap().to_le_bytes(), *b"ARNG");
+
+        registers.write32(SCRATCH, 0x1234_5678).unwrap();
+        assert_eq!(registers.read32(SCRATCH).unwrap(), 0x1234_5678);
+
+        // Only the low and the high byte.
+        registers.write32_masked(SCRATCH, 0xAABB_CCDD, 0b1001).unwrap();
+        assert_eq!(registers.read32(SCRATCH).unwrap(), 0xAA34_56DD);
+    });
+
+    assert_eq!(bridge.registers[&SCRATCH], 0xAA34_56DD);
+}
+
+#[test]
+fn bursts_are_split_into_commands() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, config());
+    let values: Vec<u32> = (0..300u32).map(|i| i.wrapping_mul(0x0101_0101)).collect();
+
+    let bridge = run_with(Bridge::new(&emulator), || {
+        let mut registers = arrange.registers().unwrap();
+        registers.write_burst(RAM + 8, &values).unwrap();
+        assert_eq!(registers.read_burst(RAM + 8, 300).unwrap(), values);
+        assert_eq!(registers.read_burst(RAM, 2).unwrap(), [0, 0]);
+    });
+
+    let write = RegisterCommand::Write as u8;
+    let read = RegisterCommand::Read as u8;
+    assert_eq!(
+        bridge.commands,
+        [
+            (write, RAM + 8, 255),
+            (write, RAM + 8 + 255 * 4, 45),
+            (read, RAM + 8, 255),
+            (read, RAM + 8 + 255 * 4, 45),
+            (read, RAM, 2),
+        ]
+    );
+}
+
+#[test]
+fn unmapped_addresses_are_reported() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, config());
+
+    run_with(Bridge::new(&emulator), || {
+        let mut registers = arrange.registers().unwrap();
+        let error = registers.read_burst(SCRATCH, 2).unwrap_err();
+        assert!(matches!(error, ArrangeError::UnexpectedResponse { .. }), "{error}");
+        let error = registers.write32(0x40, 1).unwrap_err();
+        assert!(matches!(error, ArrangeError::UnexpectedResponse { .. }), "{error}");
+
+        // Still in step.
+        assert_eq!(registers.read32(LEDS).unwrap(), 0);
+    });
+}
+
+#[test]
+fn unaligned_or_wrapping_addresses_are_refused() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, config());
+    let mut registers = arrange.registers().unwrap();
+
+    for error in [
+        registers.read32(0x02).unwrap_err(),
+        registers.write_burst(0xFFFF_FFF8, &[1, 2, 3]).unwrap_err(),
+    ] {
+        assert!(matches!(error, ArrangeError::InvalidConfiguration { .. }), "{error}");
+    }
+    assert_eq!(registers.read_burst(0xFFFF_FFFC, 0).unwrap(), []);
+    assert!(emulator.with_gateware(|gateware| gateware.take_from_host()).is_empty());
+}
+
+#[test]
+fn a_bridge_out_of_step_is_noticed() {
+    let emulator = Emulator::new();
+    let mut arrange = arrange(&emulator, config());
+    emulator.with_gateware(|gateware| gateware.queue_for_host(&[1, 2, 3, 4, 0xFF]));
+
+    let mut registers = RegisterBus::new(arrange.channel().unwrap());
+    let error = registers.read32(ID).unwrap_err();
+    assert!(matches!(error, ArrangeError::UnexpectedResponse { .. }), "{error}");
+
+    // Nobody answers at all.
+    let error = registers.read32(ID).unwrap_err();
+    assert!(matches!(error, ArrangeError::Timeout { .. }), "{error}");
+}
@@ -0,0 +1,156 @@
+mod common;
+
+use arrange_ftdi::ftdi::{
+    board::Interface,
+    capture::{
+        decode::{self, DecodedCommand},
+        CaptureLog,
+    },
+    comm::CommMode,
+    config::ArrangeFTDIConfig,
+    emulator::Emulator,
+    mpsse::MPSSE,
+    pins::{Pin, PinByte},
+    spi::{BitOrder, SpiConfig, SpiMaster, SpiMode},
+    uart::UartConfig,
+};
+use arrange_misc::error::ArrangeError;
+use common::{arrange, mpsse};
+
+const JEDEC_ID: [u8; 3] = [0x20, 0xBA, 0x16];
+
+fn flash(cs: Vec<Pin>) -> SpiConfig {
+    SpiConfig {
+        cs,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn the_spi_master_talks_to_the_flash() {
+    let mut mpsse = mpsse(Emulator::new(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    let mut spi = SpiMaster::new(&mut mpsse, flash(vec![Pin::low(4)])).unwrap();
+
+    assert_eq!(spi.write_read(0, &[0x9F], 3).unwrap(), JEDEC_ID);
+    assert_eq!(spi.transfer(0, &[0x9F, 0, 0, 0]).unwrap()[1..], JEDEC_ID);
+    assert_eq!(spi.mpsse().frequency(), 6_000_000);
+}
+
+#[test]
+fn lsb_first_sends_the_bits_the_other_way_round() {
+    let mut mpsse = mpsse(Emulator::new(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    let config = SpiConfig {
+        bit_order: BitOrder::LsbFirst,
+        ..flash(vec![Pin::low(4)])
+    };
+    let mut spi = SpiMaster::new(&mut mpsse, config).unwrap();
+
+    let id = spi.write_read(0, &[0x9Fu8.reverse_bits()], 3).unwrap();
+    assert_eq!(id, JEDEC_ID.map(u8::reverse_bits));
+}
+
+#[test]
+fn modes_pick_the_clock_edges_and_idle_level() {
+    let log = CaptureLog::new();
+    let mut mpsse = mpsse(log.recorder(Emulator::new()), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    let modes = [SpiMode::Mode0, SpiMode::Mode1, SpiMode::Mode2, SpiMode::Mode3];
+    for mode in modes {
+        for bit_order in [BitOrder::MsbFirst, BitOrder::LsbFirst] {
+            let config = SpiConfig {
+                mode,
+                bit_order,
+                ..Default::default()
+            };
+            SpiMaster::new(&mut mpsse, config).unwrap().write(0, &[0x42]).unwrap();
+        }
+    }
+    drop(mpsse);
+
+    let commands = decode::mpsse_commands(&log.capture(), Interface::A);
+    let opcodes: Vec<u8> = commands
+        .iter()
+        .filter_map(|command| match command {
+            DecodedCommand::Data { opcode, .. } => Some(*opcode),
+            _ => None,
+        })
+        .collect();
+    // Data out on the falling edge in modes 0 and 3, sampled on it in modes 1 and 2.
+    assert_eq!(opcodes, [0x11, 0x19, 0x14, 0x1C, 0x14, 0x1C, 0x11, 0x19]);
+
+    let sck: Vec<bool> = commands
+        .iter()
+        .filter_map(|command| match command {
+            DecodedCommand::SetGpio {
+                byte: PinByte::Low,
+                value,
+                ..
+            } => Some(value & 1 != 0),
+            _ => None,
+        })
+        .collect();
+    // Idle, select and deselect, for each of the eight.
+    let expected: Vec<bool> = modes
+        .iter()
+        .flat_map(|mode| [mode.idle_high(); 6])
+        .collect();
+    assert_eq!(sck, expected);
+}
+
+#[test]
+fn only_the_chosen_device_is_selected() {
+    let log = CaptureLog::new();
+    let mut mpsse = mpsse(log.recorder(Emulator::new()), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    let mut spi = SpiMaster::new(&mut mpsse, flash(vec![Pin::low(4), Pin::high(1)])).unwrap();
+
+    // Nobody answers on the second device, MISO is pulled up.
+    assert_eq!(spi.write_read(1, &[0x9F], 3).unwrap(), [0xFF; 3]);
+    assert_eq!(spi.write_read(0, &[0x9F], 3).unwrap(), JEDEC_ID);
+    drop(mpsse);
+
+    let commands = decode::mpsse_commands(&log.capture(), Interface::A);
+    let gpio: Vec<(PinByte, u8, u8)> = commands
+        .iter()
+        .filter_map(|command| match command {
+            DecodedCommand::SetGpio {
+                byte,
+                value,
+                direction,
+            } => Some((*byte, *value, *direction)),
+            _ => None,
+        })
+        .collect();
+    let idle = [(PinByte::Low, 0x10, 0x13), (PinByte::High, 0x02, 0x02)];
+    let second = [(PinByte::Low, 0x10, 0x13), (PinByte::High, 0x00, 0x02)];
+    let first = [(PinByte::Low, 0x00, 0x13), (PinByte::High, 0x02, 0x02)];
+    assert_eq!(gpio, [idle, second, idle, first, idle].concat());
+}
+
+#[test]
+fn bad_buses_are_refused() {
+    let mut mpsse = mpsse(Emulator::new(), Interface::A, MPSSE::DEFAULT_TIMEOUT);
+    for config in [
+        flash(vec![Pin::low(2)]),
+        flash(vec![Pin::high(8)]),
+        flash(vec![Pin::low(4), Pin::low(5), Pin::low(4)]),
+        flash(vec![]),
+        SpiConfig {
+            frequency: 50_000_000,
+            ..Default::default()
+        },
+    ] {
+        let error = SpiMaster::new(&mut mpsse, config).err().unwrap();
+        assert!(matches!(error, ArrangeError::InvalidConfiguration { .. }), "{error}");
+    }
+
+    let mut spi = SpiMaster::new(&mut mpsse, flash(vec![Pin::low(4)])).unwrap();
+    let error = spi.write(1, &[0]).unwrap_err();
+    assert!(matches!(error, ArrangeError::InvalidConfiguration { .. }), "{error}");
+    drop(mpsse);
+
+    let emulator = Emulator::new();
+    let mut config = ArrangeFTDIConfig::default();
+    config.comm.mode = CommMode::Uart(UartConfig::default());
+    let mut arrange = arrange(&emulator, config);
+    let error = arrange.spi(SpiConfig::default()).err().unwrap();
+    assert!(matches!(error, ArrangeError::InvalidConfiguration { .. }), "{error}");
+}
@@ -1,15 +1,36 @@
-use super::parsers::{test_mode_parser, block_erase_parser, ftdi_interface_parser};
-use arrange::FTDI::{block_erase::BlockErase, test_mode::TestMode};
-use clap::Parser;
-use libftdi1_sys::ftdi_interface;
+use super::parsers::{
+    block_erase_parser, board_parser, device_string_parser, frequency_parser,
+    ftdi_interface_parser, pin_parser, test_mode_parser, usb_id_parser,
+};
+use arrange::FTDI::{
+    block_erase::BlockErase,
+    board::{BoardProfile, Interface},
+    device_string::DeviceString,
+    pins::Pin,
+    test_mode::TestMode,
+};
+use clap::{Parser, Subcommand};
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// List the connected FTDI devices that can be programmed.
+    List,
+}
 
 #[derive(Parser, Debug)]
 pub struct Arguments {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[arg(default_value = "")]
     pub file_name: String,
 
-    #[arg(short)]
-    pub device_string: Option<String>,
+    #[arg(
+        short,
+        help = "use the specified USB device [d:<bus>/<device>, i:<vendor>:<product>[:<index>], s:<vendor>:<product>:<serial>]",
+        value_parser = device_string_parser
+    )]
+    pub device_string: Option<DeviceString>,
     #[arg(
         short = 'i',
         default_value_t = BlockErase::SixtyFourK,
@@ -19,11 +40,29 @@ pub struct Arguments {
     pub block_erase_size: BlockErase, 
     #[arg(
         short = 'I',
-        default_value = "A",
-        help = "connect to the specified interface on the FTDI chip",
+        help = "connect to the specified interface on the FTDI chip [default: the board's flash interface]",
         value_parser = ftdi_interface_parser 
     )]
-    pub ftdi_chip_interface_select: ftdi_interface, 
+    pub ftdi_chip_interface_select: Option<Interface>,
+    #[arg(
+        long,
+        help = "board profile describing the FTDI wiring [icestick, hx8k, icebreaker, ft232h]; \
+                the default looks for FT2232H chips only",
+        value_parser = board_parser
+    )]
+    pub board: Option<BoardProfile>,
+    #[arg(
+        long = "id",
+        help = "USB <vendor>:<product> to look for instead of the board's, may be repeated",
+        value_parser = usb_id_parser
+    )]
+    pub usb_ids: Vec<(u16, u16)>,
+    #[arg(long, help = "pin wired to the flash CS, e.g. ADBUS4", value_parser = pin_parser)]
+    pub cs_pin: Option<Pin>,
+    #[arg(long, help = "pin wired to CRESET_B, e.g. ADBUS7", value_parser = pin_parser)]
+    pub creset_pin: Option<Pin>,
+    #[arg(long, help = "pin wired to CDONE, e.g. ADBUS6", value_parser = pin_parser)]
+    pub cdone_pin: Option<Pin>,
     #[arg(
         short = 'r',
         default_value_t = false,
@@ -66,9 +105,18 @@ pub struct Arguments {
     #[arg(short = 'v', default_value_t = false)]
     pub verbose: bool,
 
-    #[arg(short = 's', default_value_t = false)]
+    #[arg(short = 's', default_value_t = false, help = "slow SPI clock (50 kHz)")]
     pub slow_clock: bool,
 
+    #[arg(
+        short = 'f',
+        long,
+        conflicts_with = "slow_clock",
+        help = "SPI clock frequency in Hz, accepts k and M suffixes [default: 30M]",
+        value_parser = frequency_parser
+    )]
+    pub frequency: Option<u32>,
+
     #[arg(short = 'p', default_value_t = false)]
     pub disable_protect: bool,
 
@@ -77,4 +125,11 @@ pub struct Arguments {
 
     #[arg(short = 'k', default_value_t = false)]
     pub disable_powerdown: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "capture all USB traffic with the FTDI chip to FILE, for debugging"
+    )]
+    pub capture: Option<String>,
 }
@@ -1,7 +1,9 @@
 use super::error::GenericArgumentError;
 use arrange::FTDI::block_erase::BlockErase;
+use arrange::FTDI::board::{BoardProfile, Interface};
+use arrange::FTDI::device_string::DeviceString;
+use arrange::FTDI::pins::Pin;
 use arrange::FTDI::test_mode::TestMode;
-use libftdi1_sys::ftdi_interface;
 
 pub fn block_erase_parser(arg: &str) -> Result<BlockErase, GenericArgumentError> {
     match arg {
@@ -25,14 +27,57 @@ pub fn test_mode_parser(arg: &str) -> Result<TestMode, GenericArgumentError> {
     }
 }
 
-pub fn ftdi_interface_parser(arg: &str) -> Result<ftdi_interface, GenericArgumentError> {
+pub fn ftdi_interface_parser(arg: &str) -> Result<Interface, GenericArgumentError> {
     match arg {
-        "A" => Ok(ftdi_interface::INTERFACE_A),
-        "B" => Ok(ftdi_interface::INTERFACE_B),
-        "C" => Ok(ftdi_interface::INTERFACE_C),
-        "D" => Ok(ftdi_interface::INTERFACE_D),
+        "A" => Ok(Interface::A),
+        "B" => Ok(Interface::B),
+        "C" => Ok(Interface::C),
+        "D" => Ok(Interface::D),
         _ => Err(GenericArgumentError::new(
             "Only valid values are A, B, C or D",
         )),
     }
 }
+
+pub fn device_string_parser(arg: &str) -> Result<DeviceString, GenericArgumentError> {
+    arg.parse()
+        .map_err(|error| GenericArgumentError::new(&format!("{error}")))
+}
+
+pub fn board_parser(arg: &str) -> Result<BoardProfile, GenericArgumentError> {
+    BoardProfile::from_name(arg).ok_or_else(|| {
+        GenericArgumentError::new("Only valid values are icestick, hx8k, icebreaker or ft232h")
+    })
+}
+
+pub fn usb_id_parser(arg: &str) -> Result<(u16, u16), GenericArgumentError> {
+    let error = || GenericArgumentError::new("Expected <vendor>:<product> in hexadecimal");
+    let (vendor, product) = arg.split_once(':').ok_or_else(error)?;
+
+    Ok((
+        u16::from_str_radix(vendor.trim_start_matches("0x"), 16).map_err(|_| error())?,
+        u16::from_str_radix(product.trim_start_matches("0x"), 16).map_err(|_| error())?,
+    ))
+}
+
+pub fn pin_parser(arg: &str) -> Result<Pin, GenericArgumentError> {
+    arg.parse()
+        .map_err(|error| GenericArgumentError::new(&format!("{error}")))
+}
+
+pub fn frequency_parser(arg: &str) -> Result<u32, GenericArgumentError> {
+    let (number, multiplier) = match arg.strip_suffix(['M', 'm']) {
+        Some(number) => (number, 1_000_000.0),
+        None => match arg.strip_suffix(['k', 'K']) {
+            Some(number) => (number, 1_000.0),
+            None => (arg, 1.0),
+        },
+    };
+
+    match number.parse::<f64>() {
+        Ok(value) if value > 0.0 => Ok((value * multiplier) as u32),
+        _ => Err(GenericArgumentError::new(
+            "Expected a frequency in Hz, e.g. 6M, 400k or 1000000",
+        )),
+    }
+}
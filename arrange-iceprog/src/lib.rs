@@ -0,0 +1,205 @@
+/*
+ * The iceprog flows, usable with any transport so that they can be tested against the emulator.
+ */
+use std::{
+    fs::File,
+    io::{self, Read, Seek},
+    thread::sleep,
+    time::Duration,
+};
+
+use arrange::{
+    prelude::*,
+    FTDI::{
+        board::BoardProfile, config::ArrangeFTDIConfig, mpsse::MPSSE, test_mode::TestMode,
+        transport::Transport,
+    },
+};
+use log::{debug, info};
+
+use crate::cli::arguments::Arguments;
+pub mod cli;
+
+macro_rules! read_cdone {
+    ($flash: expr) => {{
+        if $flash.cdone()? {
+            info!("cdone: high");
+        } else {
+            info!("cdone: low");
+        }
+    }};
+}
+
+/// The board described by the command line: the `--board` profile with any overrides applied.
+pub fn board(args: &Arguments) -> BoardProfile {
+    let mut board = args.board.clone().unwrap_or_default();
+    if let Some(interface) = args.ftdi_chip_interface_select {
+        board.flash_interface = interface;
+        // The flash takes the board's comm interface, there is nothing else to talk over.
+        if board.comm_interface == Some(interface) {
+            board.comm_interface = None;
+        }
+    }
+    if !args.usb_ids.is_empty() {
+        board.name = "custom".to_string();
+        board.ids = args.usb_ids.clone();
+    }
+    if let Some(pin) = args.cs_pin {
+        board.pins.cs = pin;
+    }
+    if let Some(pin) = args.creset_pin {
+        board.pins.creset = pin;
+    }
+    if let Some(pin) = args.cdone_pin {
+        board.pins.cdone = pin;
+    }
+
+    board
+}
+
+/// How to open the device described by the command line.
+pub fn config(args: &Arguments) -> ArrangeFTDIConfig {
+    ArrangeFTDIConfig {
+        board: board(args),
+        device_string: args.device_string.clone(),
+        frequency: args.frequency.unwrap_or(if args.slow_clock {
+            MPSSE::SLOW_FREQUENCY
+        } else {
+            MPSSE::MAX_FREQUENCY
+        }),
+        ..Default::default()
+    }
+}
+
+/// An error reading the image to program.
+fn image_error(error: io::Error) -> ArrangeError {
+    ArrangeError::InvalidConfiguration {
+        message: format!("cannot read the image: {error}"),
+    }
+}
+
+/// Opens `arrange` and runs the flash flow selected by `args`. `file` is the image to program,
+/// it is not needed in test mode.
+pub fn program<T: Transport + Default>(
+    args: &Arguments,
+    arrange: &mut arrange::Arrange<T>,
+    file: Option<File>,
+) -> Result<(), ArrangeError> {
+    let unsupported = if args.test_mode == TestMode::Quad {
+        Some("quad SPI test mode")
+    } else if args.test_mode == TestMode::NoTest && args.prog_sram {
+        Some("SRAM programming")
+    } else if args.test_mode == TestMode::NoTest && args.read_mode {
+        Some("reading the flash")
+    } else if args.test_mode == TestMode::NoTest && args.check_mode {
+        Some("checking the flash")
+    } else {
+        None
+    };
+    if let Some(feature) = unsupported {
+        return Err(ArrangeError::InvalidConfiguration {
+            message: format!("{feature} is not supported yet"),
+        });
+    }
+
+    info!("Initializing MPSSE...");
+    arrange.init()?;
+    info!("MPSSE initialized.");
+
+    let mut flash = arrange.get_flash(true)?;
+    read_cdone!(flash);
+    flash.release_reset()?;
+    sleep(Duration::from_millis(100));
+    info!("Reset...");
+
+    if args.test_mode != TestMode::NoTest {
+        // If in test mode...
+        flash.chip_deselect()?;
+        sleep(Duration::from_millis(250));
+
+        read_cdone!(flash);
+        flash.reset()?;
+        flash.power_up()?;
+        let id = flash.read_id()?;
+        info!("flash ID: {id}");
+        flash.power_down()?;
+        flash.release_reset()?;
+        sleep(Duration::from_millis(250));
+        read_cdone!(flash);
+    } else {
+        // Programming FLASH
+        let mut f = file.ok_or(ArrangeError::InvalidConfiguration {
+            message: "no image to program".to_string(),
+        })?;
+        let file_size = f.metadata().map_err(image_error)?.len() as usize;
+        if args.disable_protect {
+            flash.write_enable()?;
+            flash.disable_protection()?;
+        }
+
+        if !args.dont_erase {
+            if args.bulk_erase {
+                flash.write_enable()?;
+                flash.bulk_erase()?;
+                flash.wait()?;
+            } else {
+                // Erase enough for the file.
+                debug!("File Size: {file_size}");
+                let block_size = (args.block_erase_size as usize) << 10;
+                debug!("Block Size: {block_size}");
+                let block_mask = block_size - 1;
+                let begin_addr = args.address_offset & !block_mask;
+                let end_addr = (args.address_offset + file_size + block_mask) & !block_mask;
+
+                for addr in (begin_addr..end_addr).step_by(block_size) {
+                    flash.write_enable()?;
+                    flash.sector_erase(args.block_erase_size, addr)?;
+
+                    debug!("Status after Block Erase: {}", flash.read_status()?);
+                    flash.wait()?;
+                }
+            }
+        }
+
+        if args.erase_blocks.is_none() {
+            info!("Programming...");
+
+            let mut addr = 0;
+            loop {
+                let mut buffer: [u8; 256] = [0; 256];
+                // Read chunks out of the file.
+                let read_count = f.read(&mut buffer).map_err(image_error)?;
+                if read_count == 0 {
+                    break;
+                }
+
+                info!(
+                    "addr {:#06X} {}",
+                    args.address_offset + addr,
+                    100 * addr / file_size
+                );
+
+                // Write those chunks into the FLASH.
+                flash.write_enable()?;
+                flash.prog(args.address_offset + addr, &buffer[..read_count])?;
+                flash.wait()?;
+
+                addr += read_count;
+            }
+
+            info!("done.");
+            f.seek(io::SeekFrom::Start(0)).map_err(image_error)?;
+        }
+
+        if !args.disable_powerdown {
+            flash.power_down()?;
+        }
+
+        flash.release_reset()?;
+        sleep(Duration::from_millis(250));
+        read_cdone!(flash);
+    }
+
+    info!("Bye.");
+    Ok(())
+}
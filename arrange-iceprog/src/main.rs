@@ -2,42 +2,63 @@
  * Allows you to program an iCE40 FPGA through a FTDI 2322.
  * (sorta) a drop in replacement for iceprog.
  */
-use std::{
-    fs::File,
-    io::{Read, Seek},
-    process::exit,
-    thread::sleep,
-    time::Duration,
-};
+use std::{error::Error, fs::File, process::exit};
 
 use arrange::{
     prelude::*,
-    FTDI::{flash::Flash, test_mode::TestMode},
+    FTDI::{
+        capture::CaptureLog, discovery::list_devices_for, test_mode::TestMode,
+        transport::DefaultTransport,
+    },
+};
+use arrange_iceprog::{
+    board,
+    cli::arguments::{Arguments, Command},
+    config, program,
 };
 use clap::{CommandFactory, Parser};
-use log::{debug, error, info};
-
-use crate::cli::arguments::Arguments;
-mod cli;
-
-macro_rules! read_cdone {
-    ($mpsse: expr) => {{
-        if $mpsse.read_low_byte().unwrap() & 0x40 != 0 {
-            info!("cdone: high");
-        } else {
-            info!("cdone: low");
-        }
-    }};
-}
+use log::{debug, error};
 
-pub fn main() -> Result<(), ArrangeError> {
+pub fn main() {
     let args = Arguments::parse();
     if args.verbose {
         env_logger::builder()
             .filter_level(log::LevelFilter::Debug)
             .init();
     } else {
-        env_logger::init();
+        // Progress is logged at info level.
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    }
+
+    if let Err(error) = run(args) {
+        error!("{error}");
+        let mut source = error.source();
+        while let Some(cause) = source {
+            error!("  caused by: {cause}");
+            source = cause.source();
+        }
+
+        if let ArrangeError::DeviceNotFound { .. } = error.root_cause() {
+            error!("Run `arrange-iceprog list` to see the connected devices.");
+            error!("FT232H boards are only looked for with `--board ft232h`.");
+        }
+
+        exit(error.exit_code());
+    }
+}
+
+fn run(args: Arguments) -> Result<(), ArrangeError> {
+    if let Some(Command::List) = args.command {
+        let devices = list_devices_for(&board(&args))?;
+        if devices.is_empty() {
+            eprintln!("No devices found.");
+        }
+
+        for device in devices {
+            println!("{device}");
+        }
+
+        return Ok(());
     }
 
     debug!("Arguments: {:?}", args);
@@ -72,120 +93,22 @@ pub fn main() -> Result<(), ArrangeError> {
     };
 
     // Create Arrange.
-    let mut arrange = arrange::Arrange::new();
-    eprintln!("Initializing MPSSE...");
-    arrange.init()?;
-    let mpsse = arrange.get_mpsse_mut(true);
-    eprintln!("MPSSE initialized.");
-    read_cdone!(mpsse);
-
-    let mut flash = Flash::new(mpsse);
-    flash.release_reset()?;
-    sleep(Duration::from_millis(100));
-    eprintln!("Reset...");
-
-    if args.test_mode != TestMode::NoTest {
-        // If in test mode...
-        flash.chip_deselect()?;
-        sleep(Duration::from_millis(250));
-
-        read_cdone!(mpsse);
-        flash.reset()?;
-        flash.power_up()?;
-        if args.test_mode == TestMode::Quad {
-            //flash.enable_quad();
-            todo!("Implement QUAD SPI");
-        } else {
-            flash.read_id().unwrap();
+    match &args.capture {
+        Some(path) => {
+            let log = CaptureLog::create(path).unwrap_or_else(|error| {
+                error!("Cannot create capture '{path}': {error}");
+                exit(1);
+            });
+            let mut arrange = arrange::Arrange::with_transports(
+                config(&args),
+                log.recorder(DefaultTransport::default()),
+                log.recorder(DefaultTransport::default()),
+            );
+            program(&args, &mut arrange, file)
         }
-        flash.power_down()?;
-        flash.release_reset()?;
-        sleep(Duration::from_millis(250));
-        read_cdone!(mpsse);
-    } else if args.prog_sram {
-        // Programming SRAM
-        todo!("Implement SRAM programming");
-    } else {
-        // Programming FLASH
-        assert!(file.is_some());
-        let mut f = file.unwrap();
-        let file_size = f.metadata().unwrap().len() as usize;
-        if !args.read_mode && !args.check_mode {
-            if args.disable_protect {
-                flash.write_enable()?;
-                flash.disable_protection()?;
-            }
-
-            if !args.dont_erase {
-                if args.bulk_erase {
-                    flash.write_enable()?;
-                    flash.bulk_erase()?;
-                    flash.wait()?;
-                } else {
-                    // Erase enough for the file.
-                    eprintln!("File Size: {file_size}");
-                    let block_size = (args.block_erase_size as usize) << 10;
-                    eprintln!("Block Size: {block_size}");
-                    let block_mask = block_size - 1;
-                    let begin_addr = args.address_offset & !block_mask;
-                    let end_addr = (args.address_offset + file_size + block_mask) & !block_mask;
-
-                    for addr in (begin_addr..end_addr).step_by(block_size) {
-                        flash.write_enable()?;
-                        flash.sector_erase(args.block_erase_size, addr)?;
-
-                        debug!("Status after Block Erase: {}", flash.read_status()?);
-                        flash.wait()?;
-                    }
-                }
-            }
-
-            if args.erase_blocks.is_none() {
-                eprintln!("Programming...");
-
-                let mut addr = 0;
-                'chunks: loop {
-                    let mut buffer: [u8; 256] = [0; 256];
-                    // Read chunks out of the file.
-                    let read_count = match f.read(&mut buffer) {
-                        Ok(0) => break 'chunks,
-                        Ok(value) => value,
-                        Err(_) => {
-                            error!("Unable to continue reading from file...");
-                            mpsse.close();
-                            exit(2);
-                        }
-                    };
-
-                    info!(
-                        "addr {:#06X} {}",
-                        args.address_offset + addr,
-                        100 * addr / file_size
-                    );
-
-                    // Write those chunks into the FLASH.
-                    flash.write_enable()?;
-                    flash.prog(args.address_offset + addr, &buffer[..read_count])?;
-                    flash.wait()?;
-
-                    addr += read_count;
-                }
-
-                eprintln!("done.");
-                f.seek(std::io::SeekFrom::Start(0)).unwrap();
-            }
+        None => {
+            let mut arrange = arrange::Arrange::with_config(config(&args));
+            program(&args, &mut arrange, file)
         }
-
-        if !args.disable_powerdown {
-            flash.power_down()?;
-        }
-
-        flash.release_reset()?;
-        sleep(Duration::from_millis(250));
-        read_cdone!(mpsse);
     }
-
-    eprintln!("Bye.");
-    mpsse.close();
-    Ok(())
 }
@@ -0,0 +1,168 @@
+use std::{fs::File, path::PathBuf};
+
+use arrange::{
+    prelude::ArrangeError,
+    FTDI::{
+        board::Interface,
+        emulator::{Emulator, EmulatorConfig},
+        flash::FlashCommand,
+    },
+};
+use arrange_iceprog::{cli::arguments::Arguments, config, program};
+use clap::Parser;
+
+/// Writes `contents` to a file only this test uses.
+fn image(name: &str, contents: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("arrange-iceprog-{}-{name}", std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+fn bitstream(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0x7E, 0xAA, 0x99, 0x7E];
+    bytes.extend((0..len - 4).map(|i| (i % 253) as u8));
+    bytes
+}
+
+/// Runs `arrange-iceprog <args>` against `emulator`, handing it `file` like `main` would.
+fn iceprog(emulator: &Emulator, args: &[&str], file: Option<&PathBuf>) {
+    let args = Arguments::try_parse_from(["arrange-iceprog"].iter().chain(args)).unwrap();
+    let mut arrange =
+        arrange::Arrange::with_transports(config(&args), emulator.clone(), emulator.clone());
+    let file = file.map(|path| File::open(path).unwrap());
+
+    program(&args, &mut arrange, file).unwrap();
+}
+
+#[test]
+fn programs_the_flash_and_boots_the_fpga() {
+    let emulator = Emulator::new();
+    let contents = bitstream(70_000);
+    let path = image("program.bin", &contents);
+
+    iceprog(&emulator, &[path.to_str().unwrap()], Some(&path));
+
+    assert_eq!(&emulator.flash_memory()[..contents.len()], &contents[..]);
+    assert!(emulator.cdone());
+    assert!(emulator.with_flash(|flash| flash.is_powered_down()));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn programs_at_an_offset_with_small_erases() {
+    let emulator = Emulator::new();
+    emulator.load_flash(0, &[0x00; 0x3000]);
+    let contents = [0x55; 300];
+    let path = image("offset.bin", &contents);
+
+    iceprog(
+        &emulator,
+        &["-o", "4096", "-i", "4", "-k", path.to_str().unwrap()],
+        Some(&path),
+    );
+
+    let memory = emulator.flash_memory();
+    assert_eq!(&memory[..0x1000], &[0x00; 0x1000][..]);
+    assert_eq!(&memory[0x1000..0x1000 + 300], &contents[..]);
+    assert!(memory[0x1000 + 300..0x2000].iter().all(|&byte| byte == 0xFF));
+    assert_eq!(&memory[0x2000..0x3000], &[0x00; 0x1000][..]);
+    assert!(!emulator.with_flash(|flash| flash.is_powered_down()));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn bulk_erases_the_whole_flash() {
+    let emulator = Emulator::new();
+    emulator.load_flash(0x3F_0000, &[0x00; 16]);
+    let path = image("bulk.bin", &bitstream(16));
+
+    iceprog(&emulator, &["-b", path.to_str().unwrap()], Some(&path));
+
+    assert!(emulator.flash_history().contains(&(FlashCommand::CE as u8)));
+    assert_eq!(&emulator.flash_memory()[0x3F_0000..0x3F_0010], &[0xFF; 16]);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_mode_reads_the_flash_id_without_touching_it() {
+    let emulator = Emulator::new();
+    let contents = bitstream(64);
+    emulator.load_flash(0, &contents);
+
+    iceprog(&emulator, &["-t", "1"], None);
+
+    let history = emulator.flash_history();
+    assert!(history.contains(&(FlashCommand::JEDECID as u8)));
+    assert!(!history.contains(&(FlashCommand::PP as u8)));
+    assert_eq!(&emulator.flash_memory()[..64], &contents[..]);
+    assert!(emulator.cdone());
+}
+
+#[test]
+fn unsupported_modes_are_refused_before_touching_the_board() {
+    let path = image("unsupported.bin", &bitstream(64));
+    let path = path.to_str().unwrap();
+    for args in [
+        vec!["-t", "2"],
+        vec!["-S", path],
+        vec!["-r", path],
+        vec!["-c", path],
+    ] {
+        let emulator = Emulator::new();
+        let args = Arguments::try_parse_from(["arrange-iceprog"].iter().chain(&args)).unwrap();
+        let mut arrange =
+            arrange::Arrange::with_transports(config(&args), emulator.clone(), emulator.clone());
+
+        let error = program(&args, &mut arrange, None).unwrap_err();
+        assert!(matches!(error, ArrangeError::InvalidConfiguration { .. }), "{error}");
+        assert!(emulator.flash_history().is_empty());
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn programming_without_an_image_is_an_error() {
+    let emulator = Emulator::new();
+    let args = Arguments::try_parse_from(["arrange-iceprog", "missing.bin"]).unwrap();
+    let mut arrange =
+        arrange::Arrange::with_transports(config(&args), emulator.clone(), emulator.clone());
+
+    let error = program(&args, &mut arrange, None).unwrap_err();
+    assert!(matches!(error, ArrangeError::InvalidConfiguration { .. }), "{error}");
+    assert!(!emulator.flash_history().contains(&(FlashCommand::PP as u8)));
+}
+
+#[test]
+fn uses_the_selected_interface() {
+    let emulator = Emulator::with_config(EmulatorConfig {
+        flash_interface: Interface::B,
+        ..Default::default()
+    });
+    let contents = bitstream(512);
+    let path = image("interface.bin", &contents);
+
+    // The comm interface of the default board is B, the flash takes it over.
+    iceprog(&emulator, &["-I", "B", path.to_str().unwrap()], Some(&path));
+
+    assert_eq!(&emulator.flash_memory()[..contents.len()], &contents[..]);
+    assert!(emulator.cdone());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn programs_a_single_interface_ft232h() {
+    let emulator = Emulator::with_config(EmulatorConfig {
+        id: (0x0403, 0x6014),
+        interfaces: 1,
+        ..Default::default()
+    });
+    let contents = bitstream(4096);
+    let path = image("ft232h.bin", &contents);
+
+    iceprog(&emulator, &["--board", "ft232h", path.to_str().unwrap()], Some(&path));
+
+    assert_eq!(&emulator.flash_memory()[..contents.len()], &contents[..]);
+    assert!(emulator.cdone());
+    assert!(!emulator.is_open(Interface::B));
+    std::fs::remove_file(path).unwrap();
+}
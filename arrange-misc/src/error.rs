@@ -1,6 +1,210 @@
-#[derive(Debug, Clone, Copy)]
+use core::fmt;
+use std::{error::Error, io, time::Duration};
+
+/// Errors produced while talking to an Arrange device.
+///
+/// Low level transport failures carry the libftdi return code and error string, while
+/// [`ArrangeError::Flash`] wraps them with the flash operation and address that was being
+/// performed so the whole chain can be walked with [`Error::source`].
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ArrangeError {
-    WriteError,
-    ReadError,
-    DeviceError,
+    /// No matching USB device could be opened.
+    DeviceNotFound { description: String },
+    /// A libftdi call returned an error code.
+    Ftdi {
+        operation: &'static str,
+        code: i32,
+        message: String,
+    },
+    /// The device accepted fewer bytes than were sent.
+    ShortWrite {
+        operation: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    /// The device returned fewer bytes than were requested.
+    ShortRead {
+        operation: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    /// The device did not return the expected number of bytes before the deadline.
+    Timeout {
+        operation: &'static str,
+        expected: usize,
+        actual: usize,
+        timeout: Duration,
+    },
+    /// Data read back from the flash differs from what was programmed.
+    VerifyMismatch {
+        address: usize,
+        expected: u8,
+        actual: u8,
+    },
+    /// The device answered, but with something we cannot make sense of.
+    UnexpectedResponse {
+        operation: &'static str,
+        message: String,
+    },
+    /// The requested configuration cannot work, e.g. two signals assigned to the same pin.
+    InvalidConfiguration { message: String },
+    /// An interface was used before it was opened, or after it was closed.
+    NotOpen { description: String },
+    /// The flash still reported a program or erase in progress when we gave up waiting.
+    Busy {
+        operation: &'static str,
+        timeout: Duration,
+    },
+    /// A flash operation failed, `source` holds the underlying cause.
+    Flash {
+        operation: &'static str,
+        address: Option<usize>,
+        source: Box<ArrangeError>,
+    },
+}
+
+impl ArrangeError {
+    /// Wraps this error with the flash operation (and address) that was being performed.
+    pub fn in_flash(self, operation: &'static str, address: Option<usize>) -> Self {
+        ArrangeError::Flash {
+            operation,
+            address,
+            source: Box::new(self),
+        }
+    }
+
+    /// Returns the innermost error of a [`ArrangeError::Flash`] chain.
+    pub fn root_cause(&self) -> &ArrangeError {
+        match self {
+            ArrangeError::Flash { source, .. } => source.root_cause(),
+            _ => self,
+        }
+    }
+
+    /// Whether trying again may succeed: the device is still there, but an exchange with it went
+    /// wrong or the flash did not do what it was told.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self.root_cause(),
+            ArrangeError::ShortWrite { .. }
+                | ArrangeError::ShortRead { .. }
+                | ArrangeError::Timeout { .. }
+                | ArrangeError::VerifyMismatch { .. }
+                | ArrangeError::UnexpectedResponse { .. }
+                | ArrangeError::Busy { .. }
+        )
+    }
+
+    /// A process exit code describing the kind of failure, used by the command line tools.
+    pub fn exit_code(&self) -> i32 {
+        match self.root_cause() {
+            ArrangeError::DeviceNotFound { .. } => 3,
+            ArrangeError::Ftdi { .. } => 4,
+            ArrangeError::ShortWrite { .. } | ArrangeError::ShortRead { .. } => 5,
+            ArrangeError::VerifyMismatch { .. } => 6,
+            ArrangeError::UnexpectedResponse { .. } => 7,
+            ArrangeError::InvalidConfiguration { .. } => 8,
+            ArrangeError::Timeout { .. } => 9,
+            ArrangeError::NotOpen { .. } => 10,
+            ArrangeError::Busy { .. } => 11,
+            ArrangeError::Flash { .. } => unreachable!("root_cause never returns a Flash error"),
+        }
+    }
+}
+
+impl fmt::Display for ArrangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArrangeError::DeviceNotFound { description } => {
+                write!(f, "no device found ({description})")
+            }
+            ArrangeError::Ftdi {
+                operation,
+                code,
+                message,
+            } => write!(f, "{operation} failed with code {code}: {message}"),
+            ArrangeError::ShortWrite {
+                operation,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{operation} wrote {actual} of {expected} bytes to the device"
+            ),
+            ArrangeError::ShortRead {
+                operation,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{operation} read {actual} of {expected} bytes from the device"
+            ),
+            ArrangeError::Timeout {
+                operation,
+                expected,
+                actual,
+                timeout,
+            } => write!(
+                f,
+                "{operation} timed out after {timeout:?} with {actual} of {expected} bytes read"
+            ),
+            ArrangeError::VerifyMismatch {
+                address,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "verify failed at {address:#08X}: expected {expected:#04X}, read {actual:#04X}"
+            ),
+            ArrangeError::UnexpectedResponse { operation, message } => {
+                write!(f, "unexpected response to {operation}: {message}")
+            }
+            ArrangeError::InvalidConfiguration { message } => {
+                write!(f, "invalid configuration: {message}")
+            }
+            ArrangeError::NotOpen { description } => write!(f, "{description} is not open"),
+            ArrangeError::Busy { operation, timeout } => {
+                write!(f, "{operation}: flash still busy after {timeout:?}")
+            }
+            ArrangeError::Flash {
+                operation,
+                address: Some(address),
+                ..
+            } => write!(f, "flash {operation} at {address:#08X} failed"),
+            ArrangeError::Flash {
+                operation,
+                address: None,
+                ..
+            } => write!(f, "flash {operation} failed"),
+        }
+    }
+}
+
+impl Error for ArrangeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ArrangeError::Flash { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// For callers behind [`std::io`] traits. The kind says what went wrong and the original error
+/// stays reachable through [`io::Error::get_ref`].
+impl From<ArrangeError> for io::Error {
+    fn from(error: ArrangeError) -> Self {
+        let kind = match error.root_cause() {
+            ArrangeError::DeviceNotFound { .. } => io::ErrorKind::NotFound,
+            ArrangeError::Timeout { .. } | ArrangeError::Busy { .. } => io::ErrorKind::TimedOut,
+            ArrangeError::NotOpen { .. } => io::ErrorKind::NotConnected,
+            ArrangeError::InvalidConfiguration { .. } => io::ErrorKind::InvalidInput,
+            ArrangeError::ShortWrite { .. } => io::ErrorKind::WriteZero,
+            ArrangeError::ShortRead { .. } => io::ErrorKind::UnexpectedEof,
+            ArrangeError::VerifyMismatch { .. } | ArrangeError::UnexpectedResponse { .. } => {
+                io::ErrorKind::InvalidData
+            }
+            ArrangeError::Ftdi { .. } | ArrangeError::Flash { .. } => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, error)
+    }
 }
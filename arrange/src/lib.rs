@@ -1,7 +1,7 @@
 pub mod prelude;
 
-#[cfg(feature = "ftdi")]
+#[cfg(any(feature = "ftdi", feature = "nusb"))]
 pub use arrange_ftdi::ArrangeFTDI as Arrange;
-#[cfg(feature = "ftdi")]
+#[cfg(any(feature = "ftdi", feature = "nusb"))]
 pub use arrange_ftdi::ftdi as FTDI;
 